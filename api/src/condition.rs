@@ -0,0 +1,82 @@
+/*
+ * condition - kubernetes-like status conditions shared by the konfig
+ * objects, e.g: a KonfigSet which conflicts with another on some node.
+ */
+use chrono::SecondsFormat;
+use chrono::Utc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/* Two KonfigSets assigned to the same node manage the same resource */
+pub const CONFLICT: &str = "Conflict";
+
+/* The KonfigNode or KonfigSet is paused, its drift is only reported */
+pub const PAUSED: &str = "Paused";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+
+    #[serde(rename = "type")]
+    pub type_: String,
+
+    /* Either "True" or "False" */
+    pub status: String,
+
+    /* A CamelCase word explaining the status */
+    pub reason: Option<String>,
+
+    pub message: Option<String>,
+
+    /* RFC3339 timestamp of when the status last changed */
+    pub last_transition_time: Option<String>,
+}
+
+/*
+ * The current time in RFC3339, as used by the timestamps in status.
+ */
+pub fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+impl Condition {
+
+    pub fn new(type_: &str, status: bool, reason: &str, message: &str) -> Self {
+	Self{
+	    type_: type_.to_string(),
+	    status: String::from(if status { "True" } else { "False" }),
+	    reason: Some(reason.to_string()),
+	    message: Some(message.to_string()),
+	    last_transition_time: Some(now()),
+	}
+    }
+
+    pub fn is_true(&self) -> bool {
+	self.status == "True"
+    }
+}
+
+/*
+ * Adds or replaces the condition of the same type, keeping the transition
+ * time when the status didn't change.  Returns whether anything changed.
+ */
+pub fn set(conditions: &mut Vec<Condition>, mut condition: Condition) -> bool {
+    match conditions.iter_mut().find(|c| c.type_ == condition.type_) {
+	Some(current) => {
+	    if current.status == condition.status {
+		condition.last_transition_time = current.last_transition_time.clone();
+	    }
+	    let changed = *current != condition;
+	    *current = condition;
+	    changed
+	},
+	None => {
+	    conditions.push(condition);
+	    true
+	}
+    }
+}
+
+pub fn find<'a>(conditions: &'a Option<Vec<Condition>>, type_: &str) -> Option<&'a Condition> {
+    conditions.iter().flatten().find(|c| c.type_ == type_)
+}
@@ -0,0 +1,181 @@
+/*
+ * conflict - detects KonfigSets assigned to the same node that manage the
+ * same resource, which would otherwise flip-flop on every reconcile.
+ *
+ * Resources claimed by more than one KonfigSet go to the one with the
+ * highest spec.priority, the others are overridden.  When the highest
+ * priority is shared by KonfigSets that want different things, there is no
+ * way to choose and all of them are refused.  When they all want the same
+ * thing, the first one by namespace and name manages it, whatever the order
+ * the KonfigSets were listed in.
+ */
+use crate::konfignode::ConfigsetRef;
+use crate::konfigset::KonfigSet;
+use crate::konfigset::ResourceRef;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub resource: ResourceRef,
+    pub konfigsets: Vec<ConfigsetRef>,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	let konfigsets: Vec<String> = self.konfigsets.iter().map(|kref| kref.to_string()).collect();
+	write!(f, "{} is managed by {} with the same priority", self.resource, konfigsets.join(" and "))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Override {
+    /* the KonfigSet whose resource is not applied */
+    pub konfigset: ConfigsetRef,
+    pub resource: ResourceRef,
+
+    /* the KonfigSet whose resource is applied instead */
+    pub winner: ConfigsetRef,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Resolution {
+    pub conflicts: Vec<Conflict>,
+    pub overrides: Vec<Override>,
+}
+
+impl Resolution {
+
+    /* KonfigSets involved in a conflict, which must not be applied at all */
+    pub fn refused(&self) -> BTreeSet<ConfigsetRef> {
+	self.conflicts.iter().flat_map(|conflict| conflict.konfigsets.clone()).collect()
+    }
+
+    pub fn conflicts_of(&self, kref: &ConfigsetRef) -> Vec<&Conflict> {
+	self.conflicts.iter().filter(|conflict| conflict.konfigsets.contains(kref)).collect()
+    }
+
+    /* the KonfigSet managing `resource` instead of `kref`, if any */
+    pub fn overridden_by(&self, kref: &ConfigsetRef, resource: &ResourceRef) -> Option<&ConfigsetRef> {
+	self.overrides.iter()
+	    .find(|o| o.konfigset == *kref && o.resource == *resource)
+	    .map(|o| &o.winner)
+    }
+}
+
+/*
+ * Builds the merged desired state of the KonfigSets assigned to a node.
+ */
+pub fn resolve(konfigsets: &[(ConfigsetRef, &KonfigSet)]) -> Resolution {
+    let mut claims: BTreeMap<ResourceRef, Vec<(ConfigsetRef, i32, serde_json::Value)>> = BTreeMap::new();
+
+    for (kref, konfigset) in konfigsets {
+	let priority = konfigset.spec.priority.unwrap_or(0);
+
+	if let Some(configs) = &konfigset.spec.configurations {
+	    for entry in configs.entries() {
+		claims.entry(entry.id).or_default().push((kref.clone(), priority, entry.value));
+	    }
+	}
+    }
+
+    let mut resolution = Resolution::default();
+    for (resource, claimants) in claims.into_iter().filter(|(_, claimants)| claimants.len() > 1) {
+	let top = claimants.iter().map(|(_, priority, _)| *priority).max().unwrap_or(0);
+	let mut winners: Vec<&(ConfigsetRef, i32, serde_json::Value)> = claimants.iter()
+	    .filter(|(_, priority, _)| *priority == top)
+	    .collect();
+	winners.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+
+	/* the same desired state from several KonfigSets is not a conflict */
+	if winners.iter().any(|(_, _, value)| *value != winners[0].2) {
+	    let mut involved: Vec<ConfigsetRef> = vec![];
+	    for (kref, _, _) in winners {
+		if !involved.contains(kref) {
+		    involved.push(kref.clone());
+		}
+	    }
+	    resolution.conflicts.push(Conflict{ resource, konfigsets: involved });
+	    continue;
+	}
+
+	let winner = winners[0].0.clone();
+	for (kref, _, _) in claimants.iter().filter(|(kref, _, _)| *kref != winner) {
+	    resolution.overrides.push(Override{
+		konfigset: kref.clone(),
+		resource: resource.clone(),
+		winner: winner.clone(),
+	    });
+	}
+    }
+
+    resolution
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kref(name: &str) -> ConfigsetRef {
+	ConfigsetRef{ namespace: Some(String::from("default")), name: Some(name.to_string()) }
+    }
+
+    fn konfigset(name: &str, priority: Option<i32>, motd: &str) -> KonfigSet {
+	serde_json::from_value(serde_json::json!({
+	    "apiVersion": "runfc.br/v1alpha",
+	    "kind": "KonfigSet",
+	    "metadata": { "name": name, "namespace": "default" },
+	    "spec": {
+		"priority": priority,
+		"configurations": {
+		    "files": [{ "source": "static://", "destination": "/etc/motd", "content": motd }],
+		    "sysctls": [{ "name": format!("net.core.{}", name), "value": "1" }],
+		},
+	    },
+	})).unwrap()
+    }
+
+    #[test]
+    fn higher_priority_wins() {
+	let (low, high) = (konfigset("low", None, "low\n"), konfigset("high", Some(10), "high\n"));
+	let motd = ResourceRef::new("file", "/etc/motd");
+
+	for assigned in [vec![(kref("low"), &low), (kref("high"), &high)], vec![(kref("high"), &high), (kref("low"), &low)]] {
+	    let resolution = resolve(&assigned);
+	    assert!(resolution.conflicts.is_empty());
+	    assert_eq!(resolution.overrides, vec![Override{ konfigset: kref("low"), resource: motd.clone(), winner: kref("high") }]);
+	    assert_eq!(resolution.overridden_by(&kref("low"), &motd), Some(&kref("high")));
+	    assert_eq!(resolution.overridden_by(&kref("high"), &motd), None);
+	    assert_eq!(resolution.overridden_by(&kref("low"), &ResourceRef::new("sysctl", "net.core.low")), None);
+	}
+    }
+
+    #[test]
+    fn same_priority() {
+	let motd = ResourceRef::new("file", "/etc/motd");
+
+	/* wanting the same thing, whatever the order */
+	let (a, b) = (konfigset("a", Some(1), "same\n"), konfigset("b", Some(1), "same\n"));
+	for assigned in [vec![(kref("a"), &a), (kref("b"), &b)], vec![(kref("b"), &b), (kref("a"), &a)]] {
+	    let resolution = resolve(&assigned);
+	    assert!(resolution.refused().is_empty());
+	    assert_eq!(resolution.overridden_by(&kref("b"), &motd), Some(&kref("a")));
+	    assert_eq!(resolution.overridden_by(&kref("a"), &motd), None);
+	}
+
+	/* wanting different things, none of them is applied */
+	let (a, b, low) = (konfigset("a", Some(1), "a\n"), konfigset("b", Some(1), "b\n"), konfigset("low", None, "low\n"));
+	for assigned in [vec![(kref("b"), &b), (kref("low"), &low), (kref("a"), &a)], vec![(kref("a"), &a), (kref("b"), &b), (kref("low"), &low)]] {
+	    let resolution = resolve(&assigned);
+	    assert_eq!(resolution.conflicts, vec![Conflict{ resource: motd.clone(), konfigsets: vec![kref("a"), kref("b")] }]);
+	    assert_eq!(resolution.refused(), BTreeSet::from([kref("a"), kref("b")]));
+	    assert_eq!(resolution.conflicts_of(&kref("b")).len(), 1);
+	    assert!(resolution.conflicts_of(&kref("low")).is_empty());
+
+	    /* the message of the Conflict condition */
+	    assert_eq!(resolution.conflicts[0].to_string(), "file:/etc/motd is managed by default/a and default/b with the same priority");
+	}
+    }
+}
@@ -0,0 +1,169 @@
+/*
+ * graph - dependency graph used to order resources within a KonfigSet and
+ * KonfigSets within a KonfigNode.
+ */
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/*
+ * The nodes that are part of a dependency cycle, in the order they require
+ * each other (the first one is repeated at the end).
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct CycleError<T>(pub Vec<T>);
+
+impl<T: fmt::Display> fmt::Display for CycleError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	let path: Vec<String> = self.0.iter().map(|node| node.to_string()).collect();
+	write!(f, "dependency cycle: {}", path.join(" -> "))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Graph<T> {
+    /* in insertion order, which is kept whenever dependencies allow it */
+    nodes: Vec<T>,
+
+    /* node -> the nodes it requires (which must come first) */
+    requires: BTreeMap<T, BTreeSet<T>>,
+}
+
+impl<T: Ord + Clone> Default for Graph<T> {
+    fn default() -> Self {
+	Self{ nodes: vec![], requires: BTreeMap::new() }
+    }
+}
+
+impl<T: Ord + Clone> Graph<T> {
+
+    pub fn new() -> Self {
+	Self::default()
+    }
+
+    pub fn add_node(&mut self, node: T) {
+	if !self.requires.contains_key(&node) {
+	    self.requires.insert(node.clone(), BTreeSet::new());
+	    self.nodes.push(node);
+	}
+    }
+
+    pub fn contains(&self, node: &T) -> bool {
+	self.requires.contains_key(node)
+    }
+
+    /* `node` can only be handled once `requirement` has been, requiring itself is a cycle */
+    pub fn add_requirement(&mut self, node: &T, requirement: &T) {
+	self.add_node(node.clone());
+	self.add_node(requirement.clone());
+	self.requires.entry(node.clone()).or_default().insert(requirement.clone());
+    }
+
+    pub fn requirements(&self, node: &T) -> Vec<T> {
+	match self.requires.get(node) {
+	    Some(requirements) => requirements.iter().cloned().collect(),
+	    None => vec![],
+	}
+    }
+
+    /*
+     * Returns every node after the ones it requires, nodes without
+     * dependencies between them keep their insertion order.
+     */
+    pub fn order(&self) -> Result<Vec<T>, CycleError<T>> {
+	let mut ordered: Vec<T> = vec![];
+	let mut done: BTreeSet<T> = BTreeSet::new();
+
+	while ordered.len() < self.nodes.len() {
+	    let ready = self.nodes.iter()
+		.filter(|node| !done.contains(*node))
+		.find(|node| self.requires[*node].iter().all(|req| done.contains(req)));
+
+	    match ready {
+		Some(node) => {
+		    done.insert(node.clone());
+		    ordered.push(node.clone());
+		},
+		None => return Err(self.cycle(&done)),
+	    }
+	}
+	Ok(ordered)
+    }
+
+    /*
+     * Every node left out of `done` is waiting on another one left out, so
+     * following the requirements from any of them ends up in a cycle.
+     */
+    fn cycle(&self, done: &BTreeSet<T>) -> CycleError<T> {
+	let mut path: Vec<T> = vec![];
+	let mut current = match self.nodes.iter().find(|node| !done.contains(*node)) {
+	    Some(node) => node.clone(),
+	    None => return CycleError(vec![]),
+	};
+
+	while !path.contains(&current) {
+	    path.push(current.clone());
+	    current = match self.requires[&current].iter().find(|req| !done.contains(*req)) {
+		Some(next) => next.clone(),
+		None => break,
+	    };
+	}
+
+	let start = path.iter().position(|node| *node == current).unwrap_or(0);
+	let mut cycle: Vec<T> = path[start..].to_vec();
+	cycle.push(current);
+	CycleError(cycle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(nodes: &[&'static str], requires: &[(&'static str, &'static str)]) -> Graph<&'static str> {
+	let mut graph = Graph::new();
+	for node in nodes {
+	    graph.add_node(*node);
+	}
+	for (node, requirement) in requires {
+	    graph.add_requirement(node, requirement);
+	}
+	graph
+    }
+
+    #[test]
+    fn insertion_order_without_dependencies() {
+	let graph = build(&["c", "a", "b"], &[]);
+	assert_eq!(graph.order(), Ok(vec!["c", "a", "b"]));
+	assert!(graph.contains(&"a"));
+	assert!(!graph.contains(&"d"));
+    }
+
+    #[test]
+    fn requirements_come_first() {
+	/* a chain, then d must come before a (d is a requirement of a) */
+	let graph = build(&["a", "b", "c", "d"], &[("a", "b"), ("b", "c"), ("a", "d")]);
+	assert_eq!(graph.order(), Ok(vec!["c", "b", "d", "a"]));
+	assert_eq!(graph.requirements(&"a"), vec!["b", "d"]);
+	assert_eq!(graph.requirements(&"c"), Vec::<&str>::new());
+
+	/* requiring a node which wasn't added adds it */
+	let graph = build(&["a"], &[("a", "z")]);
+	assert_eq!(graph.order(), Ok(vec!["z", "a"]));
+    }
+
+    #[test]
+    fn cycles() {
+	let graph = build(&["a", "b"], &[("a", "a")]);
+	assert_eq!(graph.order(), Err(CycleError(vec!["a", "a"])));
+
+	let graph = build(&["ok", "a", "b", "c"], &[("a", "b"), ("b", "c"), ("c", "a")]);
+	let cycle = graph.order().unwrap_err();
+	assert_eq!(cycle, CycleError(vec!["a", "b", "c", "a"]));
+	assert_eq!(cycle.to_string(), "dependency cycle: a -> b -> c -> a");
+
+	/* a node waiting on the cycle isn't part of it */
+	let graph = build(&["x", "a", "b"], &[("x", "a"), ("a", "b"), ("b", "a")]);
+	assert_eq!(graph.order(), Err(CycleError(vec!["a", "b", "a"])));
+    }
+}
@@ -1,53 +1,160 @@
+use crate::condition::Condition;
 use kube::api::ObjectMeta;
 use kube_derive::CustomResource;
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
-#[derive(Debug, Copy, Clone)]
+/*
+ * The state of a KonfigNode, stored in lower case (e.g: "ready") in its
+ * status.  Moves between states go through transition():
+ *
+ *   Starting -> Syncing -> Ready, Failed or Paused, and back to Syncing
+ *   any -> Starting, Leaving, Stopped or Unknown
+ *
+ * Every reconciliation goes through Syncing, the outcome of one is never
+ * turned into another one directly (e.g: Failed -> Ready).
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum KonfigNodeState {
     /*
      * The first step when the node joins the k8s
      * control plane it enter in the syncing state
      */
-    STARTING,
+    Starting,
 
     /*
-     * when we are doing some work, we need to tell the control
-     * plane
+     * a reconciliation is in progress, every one goes through this state
+     * although konfigd only writes its outcome
      */
-    SYNCING,
+    Syncing,
 
     /*
      * After successfully applying the configsets, the node entered
      * in synced state
      */
-    READY,
+    Ready,
 
     /*
      * If any configset fails to apply
      */
-    FAILED,
+    Failed,
 
     /*
      * when we are leaving
      */
-    LEAVING,
+    Leaving,
+
+    /*
+     * konfigd stopped (e.g: restart or upgrade) and is expected back, the
+     * node keeps its KonfigSets
+     */
+    Stopped,
+
+    /*
+     * the node is paused (cordoned): konfigd only reports the drift
+     */
+    Paused,
+
+    /*
+     * konfigm hasn't heard from the node (its Lease wasn't renewed) for too
+     * long, it may be down
+     */
+    Unknown,
+}
+
+impl KonfigNodeState {
+
+    /* whether a node in this state may move to `next` */
+    pub fn can_become(self, next: KonfigNodeState) -> bool {
+	use KonfigNodeState::*;
+
+	match (self, next) {
+	    /*
+	     * konfigd (re)starts, stops or leaves whenever, konfigm may lose it
+	     * whenever; a leaving node only stops, or joins again (e.g: its
+	     * deletion was cancelled) with a new bootstrap
+	     */
+	    (_, Leaving) => true,
+	    (Leaving, Starting) | (Leaving, Stopped) => true,
+	    (Leaving, _) => false,
+	    (_, Starting) | (_, Stopped) | (_, Unknown) => true,
+
+	    /* a reconciliation starts, konfigd may also be back without having restarted */
+	    (Starting, Syncing) | (Syncing, Syncing) => true,
+	    (Ready, Syncing) | (Failed, Syncing) | (Paused, Syncing) => true,
+	    (Stopped, Syncing) | (Unknown, Syncing) => true,
+
+	    /* and ends */
+	    (Syncing, Ready) | (Syncing, Failed) | (Syncing, Paused) => true,
+
+	    _ => false,
+	}
+    }
+
+    pub fn transition(self, next: KonfigNodeState) -> Result<KonfigNodeState, String> {
+	match self.can_become(next) {
+	    true => Ok(next),
+	    false => Err(format!("a {} KonfigNode can't become {}", self, next)),
+	}
+    }
+}
+
+impl fmt::Display for KonfigNodeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	let state = match self {
+	    KonfigNodeState::Starting => "starting",
+	    KonfigNodeState::Syncing => "syncing",
+	    KonfigNodeState::Ready => "ready",
+	    KonfigNodeState::Failed => "failed",
+	    KonfigNodeState::Leaving => "leaving",
+	    KonfigNodeState::Stopped => "stopped",
+	    KonfigNodeState::Paused => "paused",
+	    KonfigNodeState::Unknown => "unknown",
+	};
+	write!(f, "{}", state)
+    }
 }
 
-impl ToString for KonfigNodeState {
-    fn to_string(&self) -> String {
-	match self {
-	    KonfigNodeState::STARTING => String::from("starting"),
-	    KonfigNodeState::SYNCING => String::from("syncing"),
-	    KonfigNodeState::READY => String::from("ready"),
-	    KonfigNodeState::FAILED => String::from("failed"),
-	    KonfigNodeState::LEAVING => String::from("leaving"),
+/*
+ * Case insensitive, the older spellings are accepted too (e.g: "joining"
+ * for starting).
+ */
+impl FromStr for KonfigNodeState {
+    type Err = String;
+
+    fn from_str(state: &str) -> Result<Self, Self::Err> {
+	match state.to_lowercase().as_str() {
+	    "starting" | "joining" => Ok(KonfigNodeState::Starting),
+	    "syncing" => Ok(KonfigNodeState::Syncing),
+	    "ready" | "synced" => Ok(KonfigNodeState::Ready),
+	    "failed" => Ok(KonfigNodeState::Failed),
+	    "leaving" => Ok(KonfigNodeState::Leaving),
+	    "stopped" => Ok(KonfigNodeState::Stopped),
+	    "paused" => Ok(KonfigNodeState::Paused),
+	    "unknown" => Ok(KonfigNodeState::Unknown),
+	    other => Err(format!("unknown KonfigNode state {:?}", other)),
 	}
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+/*
+ * A state this version doesn't know about is read as Unknown, so a single
+ * odd KonfigNode doesn't break the watchers of both binaries.
+ */
+impl<'de> Deserialize<'de> for KonfigNodeState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+	let state = String::deserialize(deserializer)?;
+	Ok(KonfigNodeState::from_str(&state).unwrap_or(KonfigNodeState::Unknown))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema)]
 pub struct ConfigsetRef {
     pub namespace: Option<String>,
     pub name: Option<String>,
@@ -75,6 +182,23 @@ impl ConfigsetRef {
 	    namespace: Some(namespace.to_string()),
 	}
     }
+
+    /*
+     * Returns a copy with the namespace filled with `namespace` when it was
+     * not defined, as references within a KonfigSet are relative to it.
+     */
+    pub fn in_namespace(&self, namespace: &str) -> Self {
+	Self{
+	    name: self.name.clone(),
+	    namespace: Some(self.namespace.clone().unwrap_or(namespace.to_string())),
+	}
+    }
+}
+
+impl fmt::Display for ConfigsetRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "{}/{}", self.namespace.as_deref().unwrap_or(""), self.name.as_deref().unwrap_or(""))
+    }
 }
 
 #[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
@@ -86,6 +210,10 @@ pub struct KonfigNodeSpec {
     // a list of ConfigSet URIs with the configuration set that needs to be
     // applied on this hosts
     pub configsets: Option<Vec<ConfigsetRef>>,
+
+    // freezes the node (e.g: during an incident), konfigd keeps checking
+    // and reporting the drift but doesn't apply anything
+    pub paused: Option<bool>,
 }
 
 impl KonfigNode {
@@ -103,6 +231,39 @@ impl KonfigNode {
 	}
 	configs
     }
+
+    pub fn is_paused(&self) -> bool {
+	self.spec.paused.unwrap_or(false)
+    }
+}
+
+/*
+ * Holds the deletion of a KonfigNode until its konfigd cleaned up the local
+ * state.
+ */
+pub const FINALIZER: &str = "konfig.runfc.br/cleanup";
+
+/*
+ * The coordination.k8s.io Lease renewed by konfigd as a heartbeat.
+ */
+pub fn lease_name(name: &str) -> String {
+    format!("konfignode-{}", name)
+}
+
+/*
+ * Seconds since the epoch, as used by last_updated.
+ */
+pub fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+/* the labels every KonfigNode is created with */
+pub fn default_labels(name: &str) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+
+    labels.insert(String::from("konfignodes.runfc.br/name"), name.to_string());
+    labels.insert(String::from("konfignodes.runfc.br/managed"), String::from("true"));
+    labels
 }
 
 pub fn new(name: &str, labels: BTreeMap<String, String>) -> KonfigNode {
@@ -116,17 +277,19 @@ pub fn new(name: &str, labels: BTreeMap<String, String>) -> KonfigNode {
 	metadata: metadata,
 	spec: KonfigNodeSpec{
 	    configsets: Some(Vec::new()),
+	    paused: None,
 	},
 	status: Some(KonfigNodeStatus::default()),
     }
 }
 
 
-#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct KonfigNodeStatus {
 
-    // the state of the konfignode: joining, ready, leaving
-    pub state: Option<String>,
+    // the state of the konfignode: starting, ready, leaving, ...
+    pub state: Option<KonfigNodeState>,
 
     // whether the node are in sync with its proposed configuration?
     pub synced: Option<bool>,
@@ -135,8 +298,26 @@ pub struct KonfigNodeStatus {
     // be applied
     pub failed_reason: Option<String>,
 
-    // When the object was last updated
+    // When the object was last updated (seconds since the epoch)
     pub last_updated: Option<u64>,
+
+    // RFC3339, when konfigd last reconciled the node, and last did so
+    // without any failure
+    pub last_attempt: Option<String>,
+    pub last_success: Option<String>,
+
+    // RFC3339, when konfigd last changed anything on the node
+    pub last_change: Option<String>,
+
+    // RFC3339, when konfigd retries the resources which failed
+    pub next_retry: Option<String>,
+
+    // the commits the git sources resolved to, by repository and ref
+    // (e.g: https://git.example.com/app.git?ref=v1.2)
+    pub revisions: Option<BTreeMap<String, String>>,
+
+    // e.g: Conflict, when KonfigSets assigned to the node manage the same resource
+    pub conditions: Option<Vec<Condition>>,
 }
 
 impl KonfigNodeStatus {
@@ -146,19 +327,115 @@ impl KonfigNodeStatus {
      */
     pub fn default() -> KonfigNodeStatus {
 	KonfigNodeStatus{
-	    state: Some(KonfigNodeState::STARTING.to_string()),
+	    state: Some(KonfigNodeState::Starting),
 	    synced: Some(false),
 	    failed_reason: None,
-	    last_updated: None,
+	    last_updated: Some(timestamp()),
+	    last_attempt: None,
+	    last_success: None,
+	    last_change: None,
+	    next_retry: None,
+	    revisions: None,
+	    conditions: None,
 	}
     }
 
+    /*
+     * Moves to `state` when it's allowed from the current one, which is
+     * kept otherwise.
+     */
+    pub fn transition(&mut self, state: KonfigNodeState) -> Result<(), String> {
+	let current = self.state.unwrap_or(KonfigNodeState::Starting);
+
+	self.state = Some(current.transition(state)?);
+	Ok(())
+    }
+
     pub fn from(state: KonfigNodeState, synced: bool, failed_reason: &str) -> KonfigNodeStatus {
 	KonfigNodeStatus{
-	    state: Some(state.to_string()),
+	    state: Some(state),
 	    synced: Some(synced),
 	    failed_reason: Some(failed_reason.to_string()),
-	    last_updated: Some(1738792666),
+	    last_updated: Some(timestamp()),
+	    last_attempt: None,
+	    last_success: None,
+	    last_change: None,
+	    next_retry: None,
+	    revisions: None,
+	    conditions: None,
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use KonfigNodeState::*;
+
+    const STATES: [KonfigNodeState; 8] = [Starting, Syncing, Ready, Failed, Leaving, Stopped, Paused, Unknown];
+
+    #[test]
+    fn transitions() {
+	let allowed = [
+	    (Starting, Syncing),
+	    (Syncing, Syncing), (Syncing, Ready), (Syncing, Failed), (Syncing, Paused),
+	    (Ready, Syncing), (Failed, Syncing), (Paused, Syncing),
+	    (Stopped, Syncing), (Unknown, Syncing),
+	];
+
+	for current in STATES {
+	    for next in STATES {
+		let expected = match (current, next) {
+		    (_, Leaving) => true,
+		    (Leaving, Starting) | (Leaving, Stopped) => true,
+		    (Leaving, _) => false,
+		    (_, Starting) | (_, Stopped) | (_, Unknown) => true,
+		    edge => allowed.contains(&edge),
+		};
+		assert_eq!(current.can_become(next), expected, "{} -> {}", current, next);
+		assert_eq!(current.transition(next).is_ok(), expected, "{} -> {}", current, next);
+	    }
 	}
     }
+
+    #[test]
+    fn outcomes_go_through_syncing() {
+	assert!(!Ready.can_become(Ready));
+	assert!(!Failed.can_become(Ready));
+	assert!(!Ready.can_become(Failed));
+	assert!(!Starting.can_become(Ready));
+	assert!(!Leaving.can_become(Syncing));
+	assert!(!Leaving.can_become(Ready));
+	assert!(!Leaving.can_become(Unknown));
+	assert_eq!(Failed.transition(Ready), Err(String::from("a failed KonfigNode can't become ready")));
+
+	let mut status = KonfigNodeStatus::default();
+	assert!(status.transition(Ready).is_err());
+	assert_eq!(status.state, Some(Starting));
+	assert!(status.transition(Syncing).and_then(|_| status.transition(Ready)).is_ok());
+	assert_eq!(status.state, Some(Ready));
+
+	/* left, then joined again */
+	assert!(status.transition(Leaving).and_then(|_| status.transition(Starting)).is_ok());
+	assert!(status.transition(Syncing).is_ok());
+    }
+
+    #[test]
+    fn lenient_states() {
+	let state = |value: &str| serde_json::from_value::<KonfigNodeState>(serde_json::json!(value)).unwrap();
+
+	for known in STATES {
+	    assert_eq!(state(&known.to_string()), known);
+	    assert_eq!(serde_json::to_value(known).unwrap(), serde_json::json!(known.to_string()));
+	}
+	assert_eq!(state("READY"), Ready);
+	assert_eq!(state("joining"), Starting);
+	assert_eq!(state("synced"), Ready);
+	assert_eq!(state("draining"), Unknown);
+	assert_eq!(state(""), Unknown);
+	assert!(serde_json::from_value::<KonfigNodeState>(serde_json::json!(1)).is_err());
+
+	let status: KonfigNodeStatus = serde_json::from_value(serde_json::json!({ "state": "rebooting", "synced": true })).unwrap();
+	assert_eq!(status.state, Some(Unknown));
+    }
 }
@@ -0,0 +1,39 @@
+/*
+ * konfignodegroup - named pools of KonfigNodes (web, db, build agents, ...)
+ * which KonfigSets can target by name instead of repeating selectors.
+ */
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube_derive::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(group = "runfc.br", version = "v1alpha", kind = "KonfigNodeGroup")]
+#[kube(status = "KonfigNodeGroupStatus")]
+#[serde(rename_all = "camelCase")]
+pub struct KonfigNodeGroupSpec {
+
+    /* KonfigNodes whose labels match are members of the group */
+    pub node_selector: Option<LabelSelector>,
+
+    /* static members, whatever their labels */
+    pub nodes: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KonfigNodeGroupStatus {
+
+    // the names of the KonfigNodes currently in the group, maintained by konfigm
+    pub members: Option<Vec<String>>,
+}
+
+impl KonfigNodeGroup {
+
+    pub fn members(&self) -> Vec<String> {
+	match &self.status {
+	    Some(status) => status.members.clone().unwrap_or_default(),
+	    None => vec![],
+	}
+    }
+}
@@ -0,0 +1,61 @@
+/*
+ * konfignoderegistration - how a konfigd joins the cluster without a
+ * privileged kubeconfig.
+ *
+ * konfigd creates a KonfigNodeRegistration (named after its KonfigNode) with
+ * a short-lived join token, only allowed to create and read these.  Once the
+ * registration is approved, konfigm creates a ServiceAccount for the node,
+ * only bound to its own objects, and publishes its token sealed for the
+ * node's public key (see sealed.rs), renewing it before it expires.
+ */
+use crate::condition;
+use crate::condition::Condition;
+
+use kube_derive::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+pub const APPROVED: &str = "Approved";
+
+/* labels the objects created for a node, to find them back */
+pub const NODE_LABEL: &str = "konfig.runfc.br/node";
+
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(group = "runfc.br", version = "v1alpha", kind = "KonfigNodeRegistration")]
+#[kube(status = "KonfigNodeRegistrationStatus")]
+#[serde(rename_all = "camelCase")]
+pub struct KonfigNodeRegistrationSpec {
+
+    /* the age recipient (age1...) the token is sealed for */
+    pub public_key: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KonfigNodeRegistrationStatus {
+
+    /* the ServiceAccount token of the node, sealed for spec.publicKey */
+    pub token: Option<String>,
+
+    /* RFC3339 expiration of the token */
+    pub expiration: Option<String>,
+
+    pub conditions: Option<Vec<Condition>>,
+}
+
+impl KonfigNodeRegistration {
+
+    pub fn is_approved(&self) -> bool {
+	let conditions = self.status.as_ref().and_then(|status| status.conditions.clone());
+
+	match condition::find(&conditions, APPROVED) {
+	    Some(approved) => approved.is_true(),
+	    None => false,
+	}
+    }
+}
+
+/* the ServiceAccount, (Cluster)Roles and bindings of a node are all named after it */
+pub fn account_name(node: &str) -> String {
+    format!("konfignode-{}", node)
+}
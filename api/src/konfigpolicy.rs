@@ -0,0 +1,402 @@
+/*
+ * konfigpolicy - restricts, per namespace, what the KonfigSets of that
+ * namespace may manage and on which KonfigNodes, so several teams can share
+ * the same fleet.
+ *
+ * A namespace no policy applies to is unrestricted.  When several policies
+ * apply to a namespace, anything allowed by one of them is allowed.
+ *
+ * Unlike the other restrictions, reading ConfigMaps and Secrets of other
+ * namespaces, managing privileged accounts and the accounts konfigd didn't
+ * create must be explicitly allowed.
+ */
+use crate::konfigset::Configuration;
+use crate::konfigset::FileSource;
+use crate::konfigset::KonfigGroup;
+use crate::konfigset::KonfigUser;
+use crate::konfigset::kind;
+use crate::labels::label_selector_matches;
+use crate::validation::sysctl_path;
+
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube_derive::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/* KonfigSets refused by a policy get this condition */
+pub const POLICY_VIOLATION: &str = "PolicyViolation";
+
+/* the groups granting privileges when privilegedGroups is not defined */
+pub const DEFAULT_PRIVILEGED_GROUPS: [&str; 7] = ["sudo", "wheel", "adm", "shadow", "docker", "disk", "root"];
+
+/* the ids below are the system ones, same as the useradd(8) defaults */
+pub const FIRST_REGULAR_ID: u32 = 1000;
+
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(group = "runfc.br", version = "v1alpha", kind = "KonfigPolicy")]
+#[serde(rename_all = "camelCase")]
+pub struct KonfigPolicySpec {
+
+    /* the namespaces of the KonfigSets this policy applies to, "*" for all */
+    pub namespaces: Vec<String>,
+
+    /* kinds of resources allowed (file, sysctl, user, ...), all when not defined */
+    pub allowed_kinds: Option<Vec<String>>,
+
+    /* files can only be written under these directories, anywhere when not defined */
+    pub allowed_paths: Option<Vec<String>>,
+
+    /* sysctls allowed, either by name or by prefix (e.g: net.ipv4), all when not defined */
+    pub allowed_sysctls: Option<Vec<String>>,
+
+    /*
+     * namespaces the ConfigMaps and Secrets of the sources may be read
+     * from, "*" for all, only the namespace of the KonfigSet when not defined
+     */
+    pub allowed_source_namespaces: Option<Vec<String>>,
+
+    /* users must define their home under these directories, anywhere when not defined */
+    pub allowed_homes: Option<Vec<String>>,
+
+    /*
+     * whether users and groups may be privileged: named root, with a system
+     * uid or gid (below 1000), or in one of the privileged groups, refused
+     * when not defined
+     */
+    pub allow_privileged_accounts: Option<bool>,
+
+    /* groups granting privileges to their members, DEFAULT_PRIVILEGED_GROUPS when not defined */
+    pub privileged_groups: Option<Vec<String>>,
+
+    /*
+     * whether the users and groups konfigd didn't create (e.g: the ubuntu
+     * user of the image) may be managed, their authorized_keys and their
+     * removal included, refused when not defined
+     */
+    pub allow_existing_accounts: Option<bool>,
+
+    /* KonfigNodes the KonfigSets may be applied on, all when not defined */
+    pub node_selector: Option<LabelSelector>,
+}
+
+impl KonfigPolicy {
+
+    pub fn applies_to(&self, namespace: &str) -> bool {
+	self.spec.namespaces.iter().any(|ns| ns == "*" || ns == namespace)
+    }
+
+    fn allows_kind(&self, kind: &str) -> bool {
+	match &self.spec.allowed_kinds {
+	    Some(kinds) => kinds.iter().any(|allowed| allowed == kind),
+	    None => true,
+	}
+    }
+
+    /* prefixes match whole components: /etc/app allows /etc/app/a.conf but not /etc/application */
+    fn allows_path(&self, destination: &str) -> bool {
+	match &self.spec.allowed_paths {
+	    Some(paths) => paths.iter().any(|allowed| Path::new(destination).starts_with(allowed)),
+	    None => true,
+	}
+    }
+
+    fn allows_sysctl(&self, name: &str) -> bool {
+	let path = match sysctl_path(name) {
+	    Ok(path) => path,
+	    Err(_) => return false,
+	};
+
+	match &self.spec.allowed_sysctls {
+	    Some(sysctls) => sysctls.iter()
+		.filter_map(|allowed| sysctl_path(allowed).ok())
+		.any(|allowed| path.starts_with(allowed)),
+	    None => true,
+	}
+    }
+
+    fn allows_source_namespace(&self, source: &str, namespace: &str) -> bool {
+	match &self.spec.allowed_source_namespaces {
+	    Some(namespaces) => source == namespace || namespaces.iter().any(|ns| ns == "*" || ns == source),
+	    None => source == namespace,
+	}
+    }
+
+    fn allows_home(&self, home: Option<&str>) -> bool {
+	match (&self.spec.allowed_homes, home) {
+	    (Some(homes), Some(home)) => homes.iter().any(|allowed| Path::new(home).starts_with(allowed)),
+	    (Some(_), None) => false,
+	    (None, _) => true,
+	}
+    }
+
+    fn allows_privileged_accounts(&self) -> bool {
+	self.spec.allow_privileged_accounts.unwrap_or(false)
+    }
+
+    fn is_privileged_group(&self, name: &str) -> bool {
+	match &self.spec.privileged_groups {
+	    Some(groups) => groups.iter().any(|group| group == name),
+	    None => DEFAULT_PRIVILEGED_GROUPS.contains(&name),
+	}
+    }
+
+    fn allows_group(&self, group: &KonfigGroup) -> bool {
+	let privileged = group.name == "root"
+	    || group.gid.is_some_and(|gid| gid < FIRST_REGULAR_ID)
+	    || self.is_privileged_group(&group.name);
+	!privileged || self.allows_privileged_accounts()
+    }
+
+    fn allows_user(&self, user: &KonfigUser) -> bool {
+	let privileged = user.name == "root"
+	    || user.uid.is_some_and(|uid| uid < FIRST_REGULAR_ID)
+	    || user.gid.is_some_and(|gid| gid < FIRST_REGULAR_ID)
+	    || user.groups.iter().flatten().any(|group| group == "root" || self.is_privileged_group(group));
+	!privileged || self.allows_privileged_accounts()
+    }
+
+    fn allows_existing_accounts(&self) -> bool {
+	self.spec.allow_existing_accounts.unwrap_or(false)
+    }
+
+    fn allows_node(&self, labels: &BTreeMap<String, String>) -> Result<bool, String> {
+	match &self.spec.node_selector {
+	    Some(selector) => label_selector_matches(selector, labels),
+	    None => Ok(true),
+	}
+    }
+}
+
+pub fn applicable<'a>(policies: &'a [KonfigPolicy], namespace: &str) -> Vec<&'a KonfigPolicy> {
+    policies.iter().filter(|policy| policy.applies_to(namespace)).collect()
+}
+
+/*
+ * The resources of the configuration none of the policies allow, e.g:
+ *   file:/etc/shadow is not allowed by any KonfigPolicy of namespace team-a
+ */
+pub fn violations(policies: &[&KonfigPolicy], namespace: &str, configs: &Configuration) -> Vec<String> {
+    if policies.is_empty() {
+	return vec![];
+    }
+    let allowed = |allows: &dyn Fn(&KonfigPolicy) -> bool| policies.iter().any(|policy| allows(policy));
+    let refused = |what: String| format!("{} is not allowed by any KonfigPolicy of namespace {}", what, namespace);
+
+    let mut violations: Vec<String> = configs.entries().into_iter()
+	.filter(|entry| !allowed(&|policy| {
+	    policy.allows_kind(&entry.id.kind) && match entry.id.kind.as_str() {
+		kind::FILE => policy.allows_path(&entry.id.name),
+		kind::SYSCTL => policy.allows_sysctl(&entry.id.name),
+		_ => true,
+	    }
+	}))
+	.map(|entry| refused(entry.id.to_string()))
+	.collect();
+
+    for file in configs.files.iter().flatten() {
+	if let Ok(FileSource::ConfigMap{ namespace: Some(source), .. }) = file.content_source() {
+	    if !allowed(&|policy| policy.allows_source_namespace(&source, namespace)) {
+		violations.push(refused(format!("{}:{} reading namespace {}", kind::FILE, file.destination, source)));
+	    }
+	}
+    }
+
+    for group in configs.groups.iter().flatten() {
+	if !allowed(&|policy| policy.allows_group(group)) {
+	    violations.push(refused(format!("{}:{} as a privileged group", kind::GROUP, group.name)));
+	}
+    }
+
+    for user in configs.users.iter().flatten() {
+	if !allowed(&|policy| policy.allows_user(user)) {
+	    violations.push(refused(format!("{}:{} as a privileged account", kind::USER, user.name)));
+	}
+
+	/* existing users keep their home when it isn't defined, wherever it is */
+	if user.ensure.as_deref() != Some("absent") && !allowed(&|policy| policy.allows_home(user.home.as_deref())) {
+	    match &user.home {
+		Some(home) => violations.push(refused(format!("{}:{} with home {}", kind::USER, user.name, home))),
+		None => violations.push(refused(format!("{}:{} without an explicit home", kind::USER, user.name))),
+	    }
+	}
+
+	if let Some(Ok(Some(keys))) = user.authorized_keys.as_ref().map(|keys| keys.content_source()) {
+	    let source = keys.namespace().unwrap_or(namespace);
+	    if !allowed(&|policy| policy.allows_source_namespace(source, namespace)) {
+		violations.push(refused(format!("{}:{} reading namespace {}", kind::AUTHORIZED_KEYS, user.name, source)));
+	    }
+	}
+    }
+
+    violations
+}
+
+/*
+ * Whether the KonfigSets of the namespace may manage the accounts of a node
+ * konfigd didn't create, only the node knows which ones these are.
+ */
+pub fn allows_existing_accounts(policies: &[&KonfigPolicy]) -> bool {
+    policies.is_empty() || policies.iter().any(|policy| policy.allows_existing_accounts())
+}
+
+/* whether the KonfigSets of the namespace may be applied on a node with these labels */
+pub fn allows_node(policies: &[&KonfigPolicy], labels: &BTreeMap<String, String>) -> Result<bool, String> {
+    if policies.is_empty() {
+	return Ok(true);
+    }
+
+    for policy in policies {
+	if policy.allows_node(labels)? {
+	    return Ok(true);
+	}
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(spec: serde_json::Value) -> KonfigPolicy {
+	KonfigPolicy::new("team-a", serde_json::from_value(spec).unwrap())
+    }
+
+    fn configs(configs: serde_json::Value) -> Configuration {
+	serde_json::from_value(configs).unwrap()
+    }
+
+    #[test]
+    fn unrestricted_without_policies() {
+	let configs = configs(serde_json::json!({
+	    "users": [{ "name": "root", "authorizedKeys": { "source": "k8s://secret/keys", "key": "k", "namespace": "kube-system" } }],
+	}));
+	assert!(violations(&[], "team-a", &configs).is_empty());
+    }
+
+    #[test]
+    fn kinds_paths_and_sysctls() {
+	let policy = policy(serde_json::json!({
+	    "namespaces": ["team-a"],
+	    "allowedKinds": ["file", "sysctl"],
+	    "allowedPaths": ["/etc/app"],
+	    "allowedSysctls": ["net.core"],
+	}));
+	let configs = configs(serde_json::json!({
+	    "files": [
+		{ "source": "static://", "destination": "/etc/app/a.conf" },
+		{ "source": "static://", "destination": "/etc/application" },
+	    ],
+	    "sysctls": [{ "name": "net.core.somaxconn", "value": "1" }, { "name": "kernel.panic", "value": "1" }],
+	    "groups": [{ "name": "app" }],
+	}));
+
+	assert_eq!(violations(&[&policy], "team-a", &configs), vec![
+	    "group:app is not allowed by any KonfigPolicy of namespace team-a",
+	    "sysctl:kernel.panic is not allowed by any KonfigPolicy of namespace team-a",
+	    "file:/etc/application is not allowed by any KonfigPolicy of namespace team-a",
+	]);
+    }
+
+    #[test]
+    fn source_namespaces() {
+	let configs = configs(serde_json::json!({
+	    "files": [
+		{ "source": "k8s://configmap/motd#motd", "destination": "/etc/motd" },
+		{ "source": "k8s://configmap/team-a/issue#issue", "destination": "/etc/issue" },
+		{ "source": "k8s://configmap/kube-system/ca#ca", "destination": "/etc/ca.pem" },
+	    ],
+	    "users": [{ "name": "alice", "home": "/home/alice", "authorizedKeys": { "source": "k8s://secret/keys", "key": "k", "namespace": "shared" } }],
+	}));
+
+	let restricted = policy(serde_json::json!({ "namespaces": ["team-a"] }));
+	assert_eq!(violations(&[&restricted], "team-a", &configs), vec![
+	    "file:/etc/ca.pem reading namespace kube-system is not allowed by any KonfigPolicy of namespace team-a",
+	    "authorizedkeys:alice reading namespace shared is not allowed by any KonfigPolicy of namespace team-a",
+	]);
+
+	let shared = policy(serde_json::json!({ "namespaces": ["team-a"], "allowedSourceNamespaces": ["shared"] }));
+	assert_eq!(violations(&[&restricted, &shared], "team-a", &configs).len(), 1);
+
+	let any = policy(serde_json::json!({ "namespaces": ["team-a"], "allowedSourceNamespaces": ["*"] }));
+	assert!(violations(&[&any], "team-a", &configs).is_empty());
+    }
+
+    #[test]
+    fn accounts() {
+	let configs = configs(serde_json::json!({
+	    "groups": [{ "name": "root" }, { "name": "ops", "gid": 0 }, { "name": "app" }],
+	    "users": [
+		{ "name": "root", "home": "/root" },
+		{ "name": "toor", "uid": 0, "home": "/home/toor" },
+		{ "name": "wheel", "groups": ["root"], "home": "/home/wheel" },
+		{ "name": "app", "home": "/srv/app" },
+		{ "name": "legacy" },
+		{ "name": "gone", "ensure": "absent" },
+	    ],
+	}));
+
+	let homes = policy(serde_json::json!({ "namespaces": ["team-a"], "allowedHomes": ["/home"] }));
+	assert_eq!(violations(&[&homes], "team-a", &configs), vec![
+	    "group:root as a privileged group is not allowed by any KonfigPolicy of namespace team-a",
+	    "group:ops as a privileged group is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:root as a privileged account is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:root with home /root is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:toor as a privileged account is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:wheel as a privileged account is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:app with home /srv/app is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:legacy without an explicit home is not allowed by any KonfigPolicy of namespace team-a",
+	]);
+
+	let privileged = policy(serde_json::json!({ "namespaces": ["team-a"], "allowPrivilegedAccounts": true }));
+	assert!(violations(&[&privileged], "team-a", &configs).is_empty());
+    }
+
+    #[test]
+    fn privileged_accounts() {
+	let configs = configs(serde_json::json!({
+	    "groups": [{ "name": "sudo" }, { "name": "staff", "gid": 50 }, { "name": "ops" }, { "name": "app", "gid": 1000 }],
+	    "users": [
+		{ "name": "daemon", "uid": 1, "home": "/home/daemon" },
+		{ "name": "nobody", "gid": 999, "home": "/home/nobody" },
+		{ "name": "alice", "groups": ["docker"], "home": "/home/alice" },
+		{ "name": "bob", "groups": ["ops"], "home": "/home/bob" },
+		{ "name": "carol", "uid": 1001, "gid": 1000, "home": "/home/carol" },
+		{ "name": "root", "ensure": "absent" },
+	    ],
+	}));
+
+	let defaults = policy(serde_json::json!({ "namespaces": ["team-a"] }));
+	assert_eq!(violations(&[&defaults], "team-a", &configs), vec![
+	    "group:sudo as a privileged group is not allowed by any KonfigPolicy of namespace team-a",
+	    "group:staff as a privileged group is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:daemon as a privileged account is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:nobody as a privileged account is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:alice as a privileged account is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:root as a privileged account is not allowed by any KonfigPolicy of namespace team-a",
+	]);
+
+	/* the list replaces the defaults */
+	let ops = policy(serde_json::json!({ "namespaces": ["team-a"], "privilegedGroups": ["ops"] }));
+	assert_eq!(violations(&[&ops], "team-a", &configs), vec![
+	    "group:staff as a privileged group is not allowed by any KonfigPolicy of namespace team-a",
+	    "group:ops as a privileged group is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:daemon as a privileged account is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:nobody as a privileged account is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:bob as a privileged account is not allowed by any KonfigPolicy of namespace team-a",
+	    "user:root as a privileged account is not allowed by any KonfigPolicy of namespace team-a",
+	]);
+    }
+
+    #[test]
+    fn existing_accounts() {
+	assert!(allows_existing_accounts(&[]));
+
+	let restricted = policy(serde_json::json!({ "namespaces": ["team-a"] }));
+	assert!(!allows_existing_accounts(&[&restricted]));
+
+	let adopting = policy(serde_json::json!({ "namespaces": ["team-a"], "allowExistingAccounts": true }));
+	assert!(allows_existing_accounts(&[&restricted, &adopting]));
+    }
+}
@@ -1,6 +1,56 @@
+use crate::condition::Condition;
+use crate::graph::Graph;
+use crate::konfignode::ConfigsetRef;
+use crate::schedule::MaintenanceWindow;
+use crate::validation;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
 use kube_derive::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/*
+ * The kinds of resources within a Configuration, used to reference them.
+ */
+pub mod kind {
+    pub const GROUP: &str = "group";
+    pub const USER: &str = "user";
+    pub const AUTHORIZED_KEYS: &str = "authorizedkeys";
+    pub const SYSCTL: &str = "sysctl";
+    pub const FILE: &str = "file";
+}
+
+/*
+ * References a resource within the same KonfigSet by its kind and name, the
+ * name being what identifies it on the node: the file destination, the
+ * sysctl name, the user or group name (and the user name for its
+ * authorizedkeys).
+ */
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema)]
+pub struct ResourceRef {
+    pub kind: String,
+    pub name: String,
+}
+
+impl ResourceRef {
+
+    pub fn new(kind: &str, name: &str) -> Self {
+	Self{
+	    kind: kind.to_string(),
+	    name: name.to_string(),
+	}
+    }
+}
+
+impl fmt::Display for ResourceRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "{}:{}", self.kind, self.name)
+    }
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct KonfigSysctl {
@@ -10,6 +60,12 @@ pub struct KonfigSysctl {
 
     /* The desired value for the configuration */
     pub value: String,
+
+    /* Resources that must be applied before this one */
+    pub requires: Option<Vec<ResourceRef>>,
+
+    /* Resources that must be applied after this one */
+    pub before: Option<Vec<ResourceRef>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -17,6 +73,8 @@ pub struct KonfigFile {
 
     pub ensure: Option<String>,
 
+    /* see FileSource */
+    #[schemars(regex(pattern = r"^(static://|https?://[^/?#\s]+[^\s]*|git\+(https?|ssh|file)://[^\s]+|k8s://configmap/([a-z0-9]([-a-z0-9]*[a-z0-9])?/)?[a-z0-9]([-.a-z0-9]*[a-z0-9])?(#[-._a-zA-Z0-9]+)?)$"))]
     pub source: String,
 
     pub destination: String,
@@ -27,39 +85,829 @@ pub struct KonfigFile {
 
     pub content: Option<String>,
 
+    /* binary content of static:// sources, base64 encoded (whitespace is ignored) */
+    #[serde(rename = "contentBase64")]
+    pub content_base64: Option<String>,
+
+    pub namespace: Option<String>,
+
+    /* sha256:<hex> of the content of http(s):// sources, required for http:// */
+    #[schemars(regex(pattern = r"^sha256:[0-9a-fA-F]{64}$"))]
+    pub checksum: Option<String>,
+
+    pub requires: Option<Vec<ResourceRef>>,
+
+    pub before: Option<Vec<ResourceRef>>,
+}
+
+/*
+ * Where the content of a KonfigFile comes from, parsed from its source:
+ *
+ *   static://                                    its content field
+ *   k8s://configmap/<name>#<key>                 a key of a ConfigMap, in the
+ *   k8s://configmap/<namespace>/<name>#<key>     namespace of the KonfigSet
+ *                                                by default
+ *   https://<host>/<path>                        downloaded by konfigd, and
+ *   http://<host>/<path>                         checked against the sha256
+ *                                                when given
+ *   git+<repository>//<path>?ref=<ref>           a file, or a whole directory
+ *                                                tree, of a git repository
+ *                                                (https, http, ssh or file)
+ *                                                at a branch, tag or commit
+ *
+ * The key and namespace may be given by the fields of the file instead, as
+ * well as the checksum, see KonfigFile::content_source().
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileSource {
+    Static,
+    ConfigMap{ namespace: Option<String>, name: String, key: String },
+    Http{ url: String, sha256: Option<String> },
+    Git{ repository: String, path: String, reference: Option<String> },
+}
+
+/*
+ * The namespace, name and key of a <kind>/[<namespace>/]<name>[#<key>]
+ * source, the key and namespace not given in the source are taken from
+ * `key` and `namespace`.
+ */
+fn parse_object(source: &str, kind: &str, key: Option<&str>, namespace: Option<&str>) -> Result<(Option<String>, String, String), (&'static str, String)> {
+    let path = source.strip_prefix(kind).and_then(|path| path.strip_prefix('/')).unwrap_or_default();
+    let (path, fragment) = match path.split_once('#') {
+	Some((path, fragment)) => (path, Some(fragment)),
+	None => (path, None),
+    };
+    let (in_source, name) = match path.split('/').collect::<Vec<&str>>()[..] {
+	[name] => (None, name),
+	[namespace, name] => (Some(namespace), name),
+	_ => return Err(("source", format!("too many components in {:?}, expected {}/[<namespace>/]<name>", source, kind))),
+    };
+
+    validation::object_name(name).map_err(|err| ("source", err))?;
+    if let Some(namespace) = in_source {
+	validation::namespace(namespace).map_err(|err| ("source", err))?;
+    }
+
+    let key = match (fragment, key) {
+	(Some(fragment), Some(key)) if !key.is_empty() && fragment != key => {
+	    return Err(("key", format!("{:?} differs from the key {:?} of the source", key, fragment)));
+	},
+	(Some(fragment), _) => fragment,
+	(None, Some(key)) if !key.is_empty() => key,
+	(None, _) => return Err(("key", format!("is required for {} sources", kind))),
+    };
+    validation::config_key(key).map_err(|err| ("key", err))?;
+
+    let namespace = match (in_source, namespace) {
+	(Some(in_source), Some(namespace)) if !namespace.is_empty() && in_source != namespace => {
+	    return Err(("namespace", format!("{:?} differs from the namespace {:?} of the source", namespace, in_source)));
+	},
+	(Some(in_source), _) => Some(in_source),
+	(None, Some(namespace)) if !namespace.is_empty() => Some(namespace),
+	(None, _) => None,
+    };
+    if let Some(namespace) = namespace {
+	validation::namespace(namespace).map_err(|err| ("namespace", err))?;
+    }
+
+    Ok((namespace.map(String::from), name.to_string(), key.to_string()))
+}
+
+/* sha256:<hex>, the hex digest is returned in lower case */
+pub fn checksum(checksum: &str) -> Result<String, String> {
+    match checksum.strip_prefix("sha256:") {
+	Some(digest) if digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()) => Ok(digest.to_ascii_lowercase()),
+	_ => Err(format!("invalid checksum {:?}, expected sha256:<64 hexadecimal digits>", checksum)),
+    }
+}
+
+impl FileSource {
+
+    /*
+     * Returns the field at fault along with the problem, the key and
+     * namespace not given in the source are taken from `key` and
+     * `namespace`.
+     */
+    fn parse(source: &str, key: Option<&str>, namespace: Option<&str>, checksum: Option<&str>) -> Result<FileSource, (&'static str, String)> {
+	let unsupported = || ("source", format!("unsupported source {:?}, valid values are: static://, k8s://configmap/[<namespace>/]<name>, https://<host>/<path>, http://<host>/<path>, git+<repository>//<path>?ref=<ref>", source));
+
+	let is_http = source.starts_with("http://") || source.starts_with("https://");
+	if checksum.is_some() && !is_http {
+	    return Err(("checksum", String::from("only applies to http:// and https:// sources")));
+	}
+
+	if source == "static://" {
+	    return Ok(FileSource::Static);
+	}
+	if source.starts_with("static://") {
+	    return Err(("source", String::from("static:// takes nothing after it, the content goes in the content field")));
+	}
+	if is_http {
+	    return FileSource::parse_http(source, checksum);
+	}
+	if let Some(url) = source.strip_prefix("git+") {
+	    return FileSource::parse_git(url).map_err(|err| ("source", err));
+	}
+	if source == "k8s://configmap" || source == "k8s://configmap/" {
+	    return Err(("source", String::from("k8s://configmap/ requires the name of the configmap")));
+	}
+
+	source.strip_prefix("k8s://configmap/").ok_or_else(unsupported)?;
+	let (namespace, name, key) = parse_object(source, "k8s://configmap", key, namespace)?;
+	Ok(FileSource::ConfigMap{ namespace, name, key })
+    }
+
+    /* plain http:// has to be checked against a checksum */
+    fn parse_http(url: &str, checksum: Option<&str>) -> Result<FileSource, (&'static str, String)> {
+	let (scheme, rest) = url.split_once("://").unwrap_or_default();
+	let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+
+	if host.is_empty() || url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+	    return Err(("source", format!("invalid url {:?}", url)));
+	}
+
+	let sha256 = match checksum {
+	    Some(value) => Some(self::checksum(value).map_err(|err| ("checksum", err))?),
+	    None if scheme == "http" => return Err(("checksum", String::from("is required for http:// sources, or use https://"))),
+	    None => None,
+	};
+
+	Ok(FileSource::Http{
+	    url: url.to_string(),
+	    sha256,
+	})
+    }
+
+    /*
+     * <repository>[//<path>][?ref=<ref>], the path within the repository
+     * is its root when not given and the ref its HEAD.
+     */
+    fn parse_git(url: &str) -> Result<FileSource, String> {
+	let invalid = |problem: &str| format!("invalid source \"git+{}\": {}", url, problem);
+
+	let (url, reference) = match url.split_once('?') {
+	    Some((url, query)) => match query.strip_prefix("ref=") {
+		Some(reference) => (url, Some(reference)),
+		None => return Err(invalid("ref=<branch, tag or commit> is the only parameter")),
+	    },
+	    None => (url, None),
+	};
+	let (scheme, rest) = match url.split_once("://") {
+	    Some((scheme, rest)) if ["https", "http", "ssh", "file"].contains(&scheme) => (scheme, rest),
+	    _ => return Err(invalid("the repository must be an https, http, ssh or file url")),
+	};
+	/* file:///srv/repo.git//path, the path of a local repository starts with a / */
+	let (repository, path) = match rest.get(1..).and_then(|after| after.split_once("//")) {
+	    Some((repository, path)) => (format!("{}://{}{}", scheme, &rest[..1], repository), path.trim_end_matches('/')),
+	    None => (url.to_string(), ""),
+	};
+
+	if repository.len() <= scheme.len() + 3 || url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+	    return Err(invalid("the repository is missing"));
+	}
+	if path.starts_with('/') || path.split('/').any(|part| part == ".." || part == ".") {
+	    return Err(invalid("the path must be relative to the root of the repository"));
+	}
+	if let Some(reference) = reference {
+	    let valid = !reference.is_empty()
+		&& !reference.starts_with('-')
+		&& !reference.contains("..")
+		&& reference.chars().all(|c| c.is_ascii_graphic() && !matches!(c, '~' | '^' | ':' | '\\' | '?' | '*' | '['));
+	    if !valid {
+		return Err(invalid("the ref must name a branch, tag or commit"));
+	    }
+	}
+
+	Ok(FileSource::Git{
+	    repository,
+	    path: path.to_string(),
+	    reference: reference.map(String::from),
+	})
+    }
+
+    /*
+     * Whether the content can only change along with the KonfigSet: it is
+     * inline, checked against its checksum or a git commit.
+     */
+    pub fn is_pinned(&self) -> bool {
+	match self {
+	    FileSource::Static => true,
+	    FileSource::ConfigMap{ .. } => false,
+	    FileSource::Http{ sha256, .. } => sha256.is_some(),
+	    FileSource::Git{ reference, .. } => reference.as_deref()
+		.is_some_and(|reference| reference.len() == 40 && reference.chars().all(|c| c.is_ascii_hexdigit())),
+	}
+    }
+}
+
+impl FromStr for FileSource {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+	FileSource::parse(source, None, None, None).map_err(|(field, err)| match field {
+	    "source" => err,
+	    field => format!("{} {}", field, err),
+	})
+    }
+}
+
+impl fmt::Display for FileSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	match self {
+	    FileSource::Static => write!(f, "static://"),
+	    FileSource::ConfigMap{ namespace: Some(namespace), name, key } => write!(f, "k8s://configmap/{}/{}#{}", namespace, name, key),
+	    FileSource::ConfigMap{ namespace: None, name, key } => write!(f, "k8s://configmap/{}#{}", name, key),
+	    FileSource::Http{ url, .. } => write!(f, "{}", url),
+	    FileSource::Git{ repository, path, reference } => {
+		write!(f, "git+{}", repository)?;
+		if !path.is_empty() {
+		    write!(f, "//{}", path)?;
+		}
+		match reference {
+		    Some(reference) => write!(f, "?ref={}", reference),
+		    None => Ok(()),
+		}
+	    },
+	}
+    }
+}
+
+impl KonfigFile {
+
+    /* the source of the content, along with the key, namespace and checksum fields */
+    pub fn content_source(&self) -> Result<FileSource, (&'static str, String)> {
+	FileSource::parse(&self.source, self.key.as_deref(), self.namespace.as_deref(), self.checksum.as_deref())
+    }
+
+    /* the content of a static:// source, from either content field (empty by default) */
+    pub fn static_content(&self) -> Result<Vec<u8>, (&'static str, String)> {
+	match (&self.content, &self.content_base64) {
+	    (Some(_), Some(_)) => Err(("contentBase64", String::from("can't be given along with content"))),
+	    (Some(content), None) => Ok(content.as_bytes().to_vec()),
+	    (None, Some(encoded)) => {
+		let encoded: String = encoded.split_whitespace().collect();
+		BASE64.decode(encoded).map_err(|err| ("contentBase64", format!("invalid base64: {}", err)))
+	    },
+	    (None, None) => Ok(vec![]),
+	}
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KonfigAuthorizedKeys {
+
+    /* Public keys written as-is, one per line, into the user's authorized_keys */
+    pub keys: Option<Vec<String>>,
+
+    /* Where to read additional keys from, see KeysSource */
+    #[schemars(regex(pattern = r"^k8s://(configmap|secret)/([a-z0-9]([-a-z0-9]*[a-z0-9])?/)?[a-z0-9]([-.a-z0-9]*[a-z0-9])?(#[-._a-zA-Z0-9]+)?$"))]
+    pub source: Option<String>,
+
+    pub key: Option<String>,
+
     pub namespace: Option<String>,
 }
 
+/*
+ * Where the additional keys of a KonfigAuthorizedKeys come from, parsed
+ * from its source:
+ *
+ *   k8s://configmap/[<namespace>/]<name>#<key>   a key of a ConfigMap
+ *   k8s://secret/[<namespace>/]<name>#<key>      a key of a Secret
+ *
+ * in the namespace of the KonfigSet by default, the key and namespace may
+ * be given by the fields instead, see KonfigAuthorizedKeys::content_source().
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeysSource {
+    ConfigMap{ namespace: Option<String>, name: String, key: String },
+    Secret{ namespace: Option<String>, name: String, key: String },
+}
+
+impl KeysSource {
+
+    fn parse(source: &str, key: Option<&str>, namespace: Option<&str>) -> Result<KeysSource, (&'static str, String)> {
+	for kind in ["k8s://configmap", "k8s://secret"] {
+	    if source == kind || source == format!("{}/", kind) {
+		return Err(("source", format!("{}/ requires the name of the object", kind)));
+	    }
+	}
+
+	if source.starts_with("k8s://configmap/") {
+	    let (namespace, name, key) = parse_object(source, "k8s://configmap", key, namespace)?;
+	    return Ok(KeysSource::ConfigMap{ namespace, name, key });
+	}
+	if source.starts_with("k8s://secret/") {
+	    let (namespace, name, key) = parse_object(source, "k8s://secret", key, namespace)?;
+	    return Ok(KeysSource::Secret{ namespace, name, key });
+	}
+	Err(("source", format!("unsupported source {:?}, valid values are: k8s://configmap/[<namespace>/]<name>, k8s://secret/[<namespace>/]<name>", source)))
+    }
+
+    /* when not the one of the KonfigSet */
+    pub fn namespace(&self) -> Option<&str> {
+	match self {
+	    KeysSource::ConfigMap{ namespace, .. } | KeysSource::Secret{ namespace, .. } => namespace.as_deref(),
+	}
+    }
+}
+
+impl FromStr for KeysSource {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+	KeysSource::parse(source, None, None).map_err(|(field, err)| match field {
+	    "source" => err,
+	    field => format!("{} {}", field, err),
+	})
+    }
+}
+
+impl fmt::Display for KeysSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	let (kind, namespace, name, key) = match self {
+	    KeysSource::ConfigMap{ namespace, name, key } => ("configmap", namespace, name, key),
+	    KeysSource::Secret{ namespace, name, key } => ("secret", namespace, name, key),
+	};
+	match namespace {
+	    Some(namespace) => write!(f, "k8s://{}/{}/{}#{}", kind, namespace, name, key),
+	    None => write!(f, "k8s://{}/{}#{}", kind, name, key),
+	}
+    }
+}
+
+impl KonfigAuthorizedKeys {
+
+    /* the source of the additional keys if any, along with the key and namespace fields */
+    pub fn content_source(&self) -> Result<Option<KeysSource>, (&'static str, String)> {
+	match &self.source {
+	    Some(source) => KeysSource::parse(source, self.key.as_deref(), self.namespace.as_deref()).map(Some),
+	    None => Ok(None),
+	}
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KonfigUser {
+
+    /* The login name of the account */
+    pub name: String,
+
+    /* Either present (default) or absent */
+    pub ensure: Option<String>,
+
+    pub uid: Option<u32>,
+
+    /* Primary group, when not defined a group with the user's name is used */
+    pub gid: Option<u32>,
+
+    pub comment: Option<String>,
+
+    /* Home directory (default: /home/<name>) */
+    pub home: Option<String>,
+
+    /* Login shell (default: /bin/sh) */
+    pub shell: Option<String>,
+
+    /* Supplementary groups, the user is removed from any group not listed */
+    pub groups: Option<Vec<String>>,
+
+    /* Whether the password is locked (ssh keys still work) */
+    pub locked: Option<bool>,
+
+    pub authorized_keys: Option<KonfigAuthorizedKeys>,
+
+    pub requires: Option<Vec<ResourceRef>>,
+
+    pub before: Option<Vec<ResourceRef>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct KonfigGroup {
+
+    pub name: String,
+
+    /* Either present (default) or absent */
+    pub ensure: Option<String>,
+
+    pub gid: Option<u32>,
+
+    pub requires: Option<Vec<ResourceRef>>,
+
+    pub before: Option<Vec<ResourceRef>>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Configuration {
 
     pub sysctls: Option<Vec<KonfigSysctl>>,
 
     pub files: Option<Vec<KonfigFile>>,
+
+    pub groups: Option<Vec<KonfigGroup>>,
+
+    pub users: Option<Vec<KonfigUser>>,
+}
+
+/*
+ * A resource of a Configuration along with the ones it must come after.
+ */
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub id: ResourceRef,
+    pub requires: Vec<ResourceRef>,
+    pub before: Vec<ResourceRef>,
+
+    /* the desired state of the resource, without its dependencies */
+    pub value: serde_json::Value,
+}
+
+fn refs(list: &Option<Vec<ResourceRef>>) -> Vec<ResourceRef> {
+    list.clone().unwrap_or_default()
+}
+
+fn desired<T: Serialize>(item: &T, skip: &[&str]) -> serde_json::Value {
+    let mut value = serde_json::to_value(item).unwrap_or_default();
+    if let Some(fields) = value.as_object_mut() {
+	for field in ["requires", "before"].iter().chain(skip) {
+	    fields.remove(*field);
+	}
+    }
+    value
+}
+
+impl Configuration {
+
+    /*
+     * Lists all the resources in the order konfigd handles them when there
+     * is no dependency between them: groups, users, their authorized keys,
+     * sysctls then files.
+     *
+     * Users implicitly require the groups they reference (by name, or by gid
+     * for their primary group) and authorized keys require their user, when
+     * these are part of the same configuration.
+     */
+    pub fn entries(&self) -> Vec<Entry> {
+	let mut entries = vec![];
+	let groups = self.groups.clone().unwrap_or_default();
+	let users = self.users.clone().unwrap_or_default();
+
+	for group in &groups {
+	    entries.push(Entry{
+		id: ResourceRef::new(kind::GROUP, &group.name),
+		requires: refs(&group.requires),
+		before: refs(&group.before),
+		value: desired(group, &[]),
+	    });
+	}
+
+	for user in &users {
+	    let mut requires = refs(&user.requires);
+	    for group in user.groups.iter().flatten() {
+		if groups.iter().any(|g| &g.name == group) {
+		    requires.push(ResourceRef::new(kind::GROUP, group));
+		}
+	    }
+	    for group in groups.iter().filter(|g| user.gid.is_some() && g.gid == user.gid) {
+		requires.push(ResourceRef::new(kind::GROUP, &group.name));
+	    }
+	    entries.push(Entry{
+		id: ResourceRef::new(kind::USER, &user.name),
+		requires,
+		before: refs(&user.before),
+		value: desired(user, &["authorizedKeys"]),
+	    });
+	}
+
+	for user in users.iter().filter(|user| user.authorized_keys.is_some()) {
+	    entries.push(Entry{
+		id: ResourceRef::new(kind::AUTHORIZED_KEYS, &user.name),
+		requires: vec![ResourceRef::new(kind::USER, &user.name)],
+		before: vec![],
+		value: desired(&user.authorized_keys, &[]),
+	    });
+	}
+
+	for sysctl in self.sysctls.iter().flatten() {
+	    entries.push(Entry{
+		id: ResourceRef::new(kind::SYSCTL, &sysctl.name),
+		requires: refs(&sysctl.requires),
+		before: refs(&sysctl.before),
+		value: desired(sysctl, &[]),
+	    });
+	}
+
+	for file in self.files.iter().flatten() {
+	    entries.push(Entry{
+		id: ResourceRef::new(kind::FILE, &file.destination),
+		requires: refs(&file.requires),
+		before: refs(&file.before),
+		value: desired(file, &[]),
+	    });
+	}
+
+	entries
+    }
+
+    /*
+     * A copy without the given resources, e.g: the ones konfigd waits to
+     * retry, so they aren't even parsed.
+     */
+    pub fn without(&self, ids: &[ResourceRef]) -> Configuration {
+	let skip = |kind: &str, name: &str| ids.contains(&ResourceRef::new(kind, name));
+
+	Configuration{
+	    sysctls: self.sysctls.clone()
+		.map(|sysctls| sysctls.into_iter().filter(|sysctl| !skip(kind::SYSCTL, &sysctl.name)).collect()),
+	    files: self.files.clone()
+		.map(|files| files.into_iter().filter(|file| !skip(kind::FILE, &file.destination)).collect()),
+	    groups: self.groups.clone()
+		.map(|groups| groups.into_iter().filter(|group| !skip(kind::GROUP, &group.name)).collect()),
+	    users: self.users.clone()
+		.map(|users| users.into_iter()
+		     .filter(|user| !skip(kind::USER, &user.name))
+		     .map(|mut user| {
+			 if skip(kind::AUTHORIZED_KEYS, &user.name) {
+			     user.authorized_keys = None;
+			 }
+			 user
+		     })
+		     .collect()),
+	}
+    }
+
+    /*
+     * Builds the dependency graph of the configuration, referencing a
+     * resource which is not part of it is a validation error.
+     */
+    pub fn dependency_graph(&self) -> Result<Graph<ResourceRef>, String> {
+	let entries = self.entries();
+	let mut graph = Graph::new();
+
+	for entry in &entries {
+	    graph.add_node(entry.id.clone());
+	}
+
+	for entry in &entries {
+	    for requirement in &entry.requires {
+		if !graph.contains(requirement) {
+		    return Err(format!("{} requires {} which is not defined", entry.id, requirement));
+		}
+		graph.add_requirement(&entry.id, requirement);
+	    }
+	    for dependent in &entry.before {
+		if !graph.contains(dependent) {
+		    return Err(format!("{} must come before {} which is not defined", entry.id, dependent));
+		}
+		graph.add_requirement(dependent, &entry.id);
+	    }
+	}
+
+	if let Err(cycle) = graph.order() {
+	    return Err(cycle.to_string());
+	}
+	Ok(graph)
+    }
 }
 
 #[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
 #[kube(group = "runfc.br", version = "v1alpha", kind = "KonfigSet", namespaced)]
+#[kube(status = "KonfigSetStatus")]
 #[serde(rename_all = "camelCase")]
 pub struct KonfigSetSpec {
 
     /*
      * Provides a list of konfig node selectors to apply
      * this configuration for.
+     *
+     * Deprecated: every selector must match (e.g: "a=b", "a!=b", "a",
+     * "!a"), use nodeSelector instead.
      */
     pub selectors: Option<Vec<String>>,
 
+    /*
+     * Selects the KonfigNodes by their labels, same as a Kubernetes
+     * LabelSelector: every matchLabels and matchExpressions must match.
+     */
+    pub node_selector: Option<LabelSelector>,
+
+    /* KonfigNodes selected by their name, whatever their labels */
+    pub nodes: Option<Vec<String>>,
+
+    /* the KonfigNodeGroups whose members are selected */
+    pub node_groups: Option<Vec<String>>,
+
+    /* KonfigNodes never selected, even when named or matched above */
+    pub exclude_nodes: Option<Vec<String>>,
+
     /*
      * Defines a configuration entries for the selected konfig node(s)
      */
     pub configurations: Option<Configuration>,
+
+    /*
+     * KonfigSets that must be successfully applied before this one, the
+     * namespace defaults to the one of this KonfigSet.
+     */
+    pub requires: Option<Vec<ConfigsetRef>>,
+
+    /* KonfigSets that must only be applied after this one */
+    pub before: Option<Vec<ConfigsetRef>>,
+
+    /*
+     * When several KonfigSets on the same node manage the same resource,
+     * the one with the highest priority wins (default: 0).
+     */
+    pub priority: Option<i32>,
+
+    /*
+     * Maintenance windows: outside of them the drift is only reported, see
+     * api::schedule (default: always applied).
+     */
+    pub schedule: Option<Vec<MaintenanceWindow>>,
+
+    /*
+     * Halts the rollout on every node: the drift is only reported, whatever
+     * the maintenance windows.
+     */
+    pub paused: Option<bool>,
 }
 
+impl KonfigSet {
+
+    pub fn is_paused(&self) -> bool {
+	self.spec.paused.unwrap_or(false)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct KonfigSetStatus {
 
     // Defines how many konfignodes references this konfigset
-    pub references: u32,
+    pub references: Option<u32>,
 
     // When the object was last updated
-    pub last_updated: u64,
+    pub last_updated: Option<u64>,
+
+    // e.g: Conflict, when it manages the same resources as another KonfigSet
+    pub conditions: Option<Vec<Condition>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(source: &str, key: Option<&str>, namespace: Option<&str>, checksum: Option<&str>) -> KonfigFile {
+	serde_json::from_value(serde_json::json!({
+	    "source": source,
+	    "destination": "/etc/motd",
+	    "key": key,
+	    "namespace": namespace,
+	    "checksum": checksum,
+	})).unwrap()
+    }
+
+    fn keys(source: &str, key: Option<&str>, namespace: Option<&str>) -> KonfigAuthorizedKeys {
+	serde_json::from_value(serde_json::json!({ "source": source, "key": key, "namespace": namespace })).unwrap()
+    }
+
+    #[test]
+    fn file_sources_round_trip() {
+	let sources = [
+	    "static://",
+	    "k8s://configmap/motd#motd",
+	    "k8s://configmap/team-a/motd#motd",
+	    "https://example.com/motd?v=1",
+	    "git+https://example.com/repo.git",
+	    "git+https://example.com/repo.git//etc/app?ref=main",
+	    "git+file:///srv/repo.git//etc",
+	];
+	for source in sources {
+	    let parsed: FileSource = source.parse().unwrap();
+	    assert_eq!(parsed.to_string(), source);
+	    assert_eq!(parsed.to_string().parse::<FileSource>(), Ok(parsed));
+	}
+
+	assert_eq!(file("k8s://configmap/motd", Some("motd"), Some("team-a"), None).content_source(), Ok(FileSource::ConfigMap{
+	    namespace: Some(String::from("team-a")),
+	    name: String::from("motd"),
+	    key: String::from("motd"),
+	}));
+	assert_eq!(file("https://example.com/motd", None, None, Some(&format!("sha256:{}", "A".repeat(64)))).content_source(), Ok(FileSource::Http{
+	    url: String::from("https://example.com/motd"),
+	    sha256: Some("a".repeat(64)),
+	}));
+    }
+
+    #[test]
+    fn malformed_file_sources() {
+	let sources = [
+	    "",
+	    "motd",
+	    "static://motd",
+	    "k8s://configmap",
+	    "k8s://configmap/",
+	    "k8s://configmap/a/b/c#key",
+	    "k8s://configmap/Motd#key",
+	    "k8s://secret/motd#key",
+	    "https://",
+	    "https:// example.com/motd",
+	    "git+ftp://example.com/repo.git",
+	    "git+https://example.com/repo.git//../etc",
+	    "git+https://example.com/repo.git?branch=main",
+	];
+	for source in sources {
+	    assert!(source.parse::<FileSource>().is_err(), "{:?}", source);
+	}
+
+	/* plain http has to be checked, checksums only apply to it */
+	assert_eq!(file("http://example.com/motd", None, None, None).content_source().unwrap_err().0, "checksum");
+	assert_eq!(file("static://", None, None, Some(&format!("sha256:{}", "a".repeat(64)))).content_source().unwrap_err().0, "checksum");
+	assert_eq!(file("https://example.com/motd", None, None, Some("md5:abc")).content_source().unwrap_err().0, "checksum");
+    }
+
+    #[test]
+    fn configmap_keys_and_namespaces() {
+	assert_eq!(file("k8s://configmap/motd", None, None, None).content_source().unwrap_err().0, "key");
+	assert_eq!(file("k8s://configmap/motd", Some(""), None, None).content_source().unwrap_err().0, "key");
+	assert_eq!(file("k8s://configmap/motd#a", Some("b"), None, None).content_source().unwrap_err().0, "key");
+	assert_eq!(file("k8s://configmap/motd#a b", None, None, None).content_source().unwrap_err().0, "key");
+	assert_eq!(file("k8s://configmap/a/motd#a", None, Some("b"), None).content_source().unwrap_err().0, "namespace");
+	assert!(file("k8s://configmap/a/motd#a", Some("a"), Some("a"), None).content_source().is_ok());
+    }
+
+    #[test]
+    fn keys_sources() {
+	for source in ["k8s://configmap/keys#authorized_keys", "k8s://secret/keys#authorized_keys", "k8s://secret/team-a/keys#authorized_keys"] {
+	    let parsed: KeysSource = source.parse().unwrap();
+	    assert_eq!(parsed.to_string(), source);
+	    assert_eq!(parsed.to_string().parse::<KeysSource>(), Ok(parsed));
+	}
+
+	assert_eq!(keys("k8s://secret/keys", Some("authorized_keys"), Some("team-a")).content_source(), Ok(Some(KeysSource::Secret{
+	    namespace: Some(String::from("team-a")),
+	    name: String::from("keys"),
+	    key: String::from("authorized_keys"),
+	})));
+	assert_eq!(keys("k8s://configmap/keys", Some("k"), None).content_source().unwrap().unwrap().namespace(), None);
+
+	let no_source: KonfigAuthorizedKeys = serde_json::from_value(serde_json::json!({ "keys": ["ssh-ed25519 AAAA"] })).unwrap();
+	assert_eq!(no_source.content_source(), Ok(None));
+
+	for source in ["", "static://", "k8s://secret", "k8s://secret/", "k8s://configmaps/keys#k", "https://example.com/keys", "k8s://secret/a/b/c#k"] {
+	    assert_eq!(keys(source, Some("k"), None).content_source().unwrap_err().0, "source", "{:?}", source);
+	}
+	assert_eq!(keys("k8s://secret/keys", None, None).content_source().unwrap_err().0, "key");
+	assert_eq!(keys("k8s://secret/keys#a", Some("b"), None).content_source().unwrap_err().0, "key");
+	assert_eq!(keys("k8s://secret/a/keys#k", None, Some("b")).content_source().unwrap_err().0, "namespace");
+    }
+
+    #[test]
+    fn dependency_graph() {
+	let graph = |configs: serde_json::Value| serde_json::from_value::<Configuration>(configs).unwrap().dependency_graph();
+	let id = |kind: &str, name: &str| ResourceRef::new(kind, name);
+
+	/* the sysctl requires the file, which comes before the user, itself after its group */
+	let ordered = graph(serde_json::json!({
+	    "sysctls": [{ "name": "net.core.somaxconn", "value": "1", "requires": [{ "kind": "file", "name": "/etc/app.conf" }] }],
+	    "files": [{ "source": "static://", "destination": "/etc/app.conf", "before": [{ "kind": "user", "name": "app" }] }],
+	    "groups": [{ "name": "app" }],
+	    "users": [{ "name": "app", "groups": ["app"] }],
+	})).unwrap().order().unwrap();
+	let position = |id: &ResourceRef| ordered.iter().position(|other| other == id).unwrap();
+	assert!(position(&id(kind::FILE, "/etc/app.conf")) < position(&id(kind::SYSCTL, "net.core.somaxconn")));
+	assert!(position(&id(kind::FILE, "/etc/app.conf")) < position(&id(kind::USER, "app")));
+	assert!(position(&id(kind::GROUP, "app")) < position(&id(kind::USER, "app")));
+
+	let itself = graph(serde_json::json!({
+	    "files": [{ "source": "static://", "destination": "/etc/a", "requires": [{ "kind": "file", "name": "/etc/a" }] }],
+	}));
+	assert_eq!(itself.unwrap_err(), "dependency cycle: file:/etc/a -> file:/etc/a");
+
+	let cycle = graph(serde_json::json!({
+	    "files": [
+		{ "source": "static://", "destination": "/etc/a", "requires": [{ "kind": "file", "name": "/etc/b" }] },
+		{ "source": "static://", "destination": "/etc/b" },
+	    ],
+	    "sysctls": [{
+		"name": "kernel.panic", "value": "1",
+		"requires": [{ "kind": "file", "name": "/etc/a" }],
+		"before": [{ "kind": "file", "name": "/etc/b" }],
+	    }],
+	}));
+	assert_eq!(cycle.unwrap_err(), "dependency cycle: sysctl:kernel.panic -> file:/etc/a -> file:/etc/b -> sysctl:kernel.panic");
+
+	let missing = graph(serde_json::json!({
+	    "files": [{ "source": "static://", "destination": "/etc/a", "requires": [{ "kind": "user", "name": "nobody" }] }],
+	}));
+	assert_eq!(missing.unwrap_err(), "file:/etc/a requires user:nobody which is not defined");
+
+	let missing = graph(serde_json::json!({
+	    "files": [{ "source": "static://", "destination": "/etc/a", "before": [{ "kind": "file", "name": "/etc/b" }] }],
+	}));
+	assert_eq!(missing.unwrap_err(), "file:/etc/a must come before file:/etc/b which is not defined");
+    }
 }
@@ -0,0 +1,121 @@
+/*
+ * labels - evaluation of Kubernetes LabelSelectors against the labels of a
+ * KonfigNode, shared by konfigm (assignments) and konfigd (policies).
+ */
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelectorRequirement;
+use std::collections::BTreeMap;
+
+/*
+ * Same semantics as Kubernetes: every matchLabels and matchExpressions
+ * must match, so an empty selector matches every node.
+ */
+pub fn label_selector_matches(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> Result<bool, String> {
+    for (key, value) in selector.match_labels.iter().flatten() {
+	if labels.get(key) != Some(value) {
+	    return Ok(false);
+	}
+    }
+
+    for requirement in selector.match_expressions.iter().flatten() {
+	if !requirement_matches(requirement, labels)? {
+	    return Ok(false);
+	}
+    }
+    Ok(true)
+}
+
+fn requirement_matches(requirement: &LabelSelectorRequirement, labels: &BTreeMap<String, String>) -> Result<bool, String> {
+    let values = requirement.values.clone().unwrap_or_default();
+    let current = labels.get(&requirement.key);
+
+    match requirement.operator.as_str() {
+	"In" | "NotIn" if values.is_empty() => {
+	    Err(format!("{} {} requires at least one value", requirement.key, requirement.operator))
+	},
+	"In" => Ok(current.is_some_and(|value| values.contains(value))),
+	"NotIn" => Ok(!current.is_some_and(|value| values.contains(value))),
+	"Exists" | "DoesNotExist" if !values.is_empty() => {
+	    Err(format!("{} {} does not take any value", requirement.key, requirement.operator))
+	},
+	"Exists" => Ok(current.is_some()),
+	"DoesNotExist" => Ok(current.is_none()),
+	operator => Err(format!("{}: unknown operator {}", requirement.key, operator)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels() -> BTreeMap<String, String> {
+	BTreeMap::from([
+	    (String::from("zone"), String::from("a")),
+	    (String::from("gpu"), String::from("true")),
+	])
+    }
+
+    /* a selector with a single expression, without values when none are given */
+    fn requirement(key: &str, operator: &str, values: &[&str]) -> LabelSelector {
+	let values = match values {
+	    [] => None,
+	    values => Some(values.iter().map(|value| value.to_string()).collect()),
+	};
+
+	LabelSelector{
+	    match_labels: None,
+	    match_expressions: Some(vec![LabelSelectorRequirement{
+		key: key.to_string(),
+		operator: operator.to_string(),
+		values,
+	    }]),
+	}
+    }
+
+    #[test]
+    fn match_labels() {
+	let selector = LabelSelector{
+	    match_labels: Some(BTreeMap::from([(String::from("zone"), String::from("a"))])),
+	    match_expressions: None,
+	};
+	assert_eq!(label_selector_matches(&selector, &labels()), Ok(true));
+	assert_eq!(label_selector_matches(&selector, &BTreeMap::new()), Ok(false));
+	assert_eq!(label_selector_matches(&LabelSelector::default(), &BTreeMap::new()), Ok(true));
+    }
+
+    #[test]
+    fn operator_in() {
+	assert_eq!(label_selector_matches(&requirement("zone", "In", &["a", "b"]), &labels()), Ok(true));
+	assert_eq!(label_selector_matches(&requirement("zone", "In", &["b"]), &labels()), Ok(false));
+	assert_eq!(label_selector_matches(&requirement("rack", "In", &["a"]), &labels()), Ok(false));
+    }
+
+    #[test]
+    fn operator_not_in() {
+	assert_eq!(label_selector_matches(&requirement("zone", "NotIn", &["b"]), &labels()), Ok(true));
+	assert_eq!(label_selector_matches(&requirement("zone", "NotIn", &["a", "b"]), &labels()), Ok(false));
+	assert_eq!(label_selector_matches(&requirement("rack", "NotIn", &["a"]), &labels()), Ok(true));
+    }
+
+    #[test]
+    fn operator_exists() {
+	assert_eq!(label_selector_matches(&requirement("gpu", "Exists", &[]), &labels()), Ok(true));
+	assert_eq!(label_selector_matches(&requirement("rack", "Exists", &[]), &labels()), Ok(false));
+    }
+
+    #[test]
+    fn operator_does_not_exist() {
+	assert_eq!(label_selector_matches(&requirement("rack", "DoesNotExist", &[]), &labels()), Ok(true));
+	assert_eq!(label_selector_matches(&requirement("gpu", "DoesNotExist", &[]), &labels()), Ok(false));
+    }
+
+    #[test]
+    fn invalid_requirements() {
+	assert!(label_selector_matches(&requirement("zone", "In", &[]), &labels()).is_err());
+	assert!(label_selector_matches(&requirement("zone", "NotIn", &[]), &labels()).is_err());
+	assert!(label_selector_matches(&requirement("gpu", "Exists", &["true"]), &labels()).is_err());
+	assert!(label_selector_matches(&requirement("gpu", "DoesNotExist", &["true"]), &labels()).is_err());
+	assert!(label_selector_matches(&requirement("zone", "Gt", &["1"]), &labels()).is_err());
+	assert!(label_selector_matches(&requirement("zone", "in", &["a"]), &labels()).is_err());
+    }
+}
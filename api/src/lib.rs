@@ -3,13 +3,48 @@
  * in different part of the runfc ecosystem
  */
 
+pub mod condition;
+pub use condition::Condition;
+
+pub mod conflict;
+
+pub mod graph;
+pub use graph::Graph;
+
 pub mod konfignode;
 pub use konfignode::KonfigNode;
 pub use konfignode::KonfigNodeState;
 pub use konfignode::KonfigNodeStatus;
 pub use konfignode::ConfigsetRef;
 
+pub mod konfignodegroup;
+pub use konfignodegroup::KonfigNodeGroup;
+
+pub mod konfignoderegistration;
+pub use konfignoderegistration::KonfigNodeRegistration;
+
+pub mod konfigpolicy;
+pub use konfigpolicy::KonfigPolicy;
+
 pub mod konfigset;
 pub use konfigset::KonfigSet;
+pub use konfigset::KonfigSetStatus;
 pub use konfigset::KonfigFile;
+pub use konfigset::FileSource;
+pub use konfigset::KeysSource;
 pub use konfigset::KonfigSysctl;
+pub use konfigset::KonfigUser;
+pub use konfigset::KonfigGroup;
+pub use konfigset::KonfigAuthorizedKeys;
+pub use konfigset::ResourceRef;
+
+pub mod labels;
+
+pub mod schedule;
+pub use schedule::MaintenanceWindow;
+
+pub mod sealed;
+
+pub mod signature;
+
+pub mod validation;
@@ -0,0 +1,287 @@
+/*
+ * schedule - maintenance windows, when konfigd is allowed to apply the
+ * drift of the KonfigSets; outside of them it only reports it.
+ *
+ * A window starts on every occurrence of a cron expression, in its
+ * timezone, and lasts for its duration, e.g:
+ *
+ *   schedule:
+ *   - cron: "0 2 * * Sun"
+ *     timezone: America/Sao_Paulo
+ *     duration: 2h
+ *
+ * Windows are set per KonfigSet (spec.schedule) and per KonfigNode (the
+ * WINDOWS_ANNOTATION, a JSON list of windows), both must be open for a
+ * KonfigSet to be applied.  The OVERRIDE_ANNOTATION, on either of them,
+ * applies right away whatever the windows.
+ */
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use chrono_tz::Tz;
+use cron::Schedule;
+use kube::api::ObjectMeta;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+pub const WINDOWS_ANNOTATION: &str = "konfig.runfc.br/maintenance-windows";
+
+pub const OVERRIDE_ANNOTATION: &str = "konfig.runfc.br/apply-now";
+
+/* KonfigNode condition: some drift is held back, outside of the windows or paused */
+pub const MAINTENANCE_PENDING: &str = "MaintenancePending";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct MaintenanceWindow {
+
+    /*
+     * When the window opens: minute hour day-of-month month day-of-week
+     * (0 or 7 being Sunday, as in crontab), a leading seconds and a
+     * trailing year fields are accepted too
+     */
+    pub cron: String,
+
+    /* e.g: 2h, 30m, 1h30m */
+    pub duration: String,
+
+    /* IANA name, e.g: Europe/Lisbon (default: UTC) */
+    pub timezone: Option<String>,
+}
+
+/*
+ * crontab(5) numbers the days of the week from Sunday = 0 (or 7), the cron
+ * crate from Sunday = 1: numeric terms are expanded to the days they mean.
+ * Names (e.g: Mon-Fri) mean the same for both and are kept as they are.
+ */
+fn days_of_week(field: &str) -> Result<String, String> {
+    let mut days: Vec<String> = vec![];
+
+    for term in field.split(',') {
+	if term == "*" || term == "?" || term.chars().any(|c| c.is_ascii_alphabetic()) {
+	    days.push(term.to_string());
+	    continue;
+	}
+
+	let invalid = || format!("invalid day of week {:?}, e.g: 1-5, Mon-Fri", term);
+	let number = |value: &str| value.parse::<u32>().map_err(|_| invalid());
+	let (range, step) = match term.split_once('/') {
+	    Some((range, step)) => (range, number(step)? as usize),
+	    None => (term, 1),
+	};
+	let (first, last) = match range.split_once('-') {
+	    Some((first, last)) => (number(first)?, number(last)?),
+	    None if range == "*" => (0, 6),
+	    /* like the cron crate, a single day with a step goes up to the end of the week */
+	    None if term.contains('/') => (number(range)?, 6),
+	    None => (number(range)?, number(range)?),
+	};
+	if step == 0 || first > last || last > 7 {
+	    return Err(invalid());
+	}
+
+	for day in (first..=last).step_by(step).map(|day| (day % 7 + 1).to_string()) {
+	    if !days.contains(&day) {
+		days.push(day);
+	    }
+	}
+    }
+    Ok(days.join(","))
+}
+
+/*
+ * The cron crate wants the seconds, unlike the usual crontab(5) format, and
+ * numbers the days of the week differently.
+ */
+fn schedule(cron: &str) -> Result<Schedule, String> {
+    let mut fields: Vec<String> = cron.split_whitespace().map(String::from).collect();
+    if fields.len() == 5 {
+	fields.insert(0, String::from("0"));
+    }
+    if fields.len() == 6 || fields.len() == 7 {
+	fields[5] = days_of_week(&fields[5]).map_err(|err| format!("invalid cron expression {:?}: {}", cron, err))?;
+    }
+
+    Schedule::from_str(&fields.join(" ")).map_err(|err| format!("invalid cron expression {:?}: {}", cron, err))
+}
+
+/* a sequence of <number><unit>, the units being h, m and s */
+pub fn duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration {:?}, e.g: 2h, 30m, 1h30m", duration);
+    let mut seconds: i64 = 0;
+    let mut number = String::new();
+
+    for c in duration.chars() {
+	match c {
+	    '0'..='9' => number.push(c),
+	    'h' | 'm' | 's' if !number.is_empty() => {
+		let value: i64 = number.parse().map_err(|_| invalid())?;
+		let unit = match c {
+		    'h' => 3600,
+		    'm' => 60,
+		    _ => 1,
+		};
+		seconds = value.checked_mul(unit).and_then(|value| seconds.checked_add(value)).ok_or_else(invalid)?;
+		number.clear();
+	    },
+	    _ => return Err(invalid()),
+	}
+    }
+    if !number.is_empty() || seconds == 0 {
+	return Err(invalid());
+    }
+    Ok(Duration::seconds(seconds))
+}
+
+fn timezone(timezone: &Option<String>) -> Result<Tz, String> {
+    match timezone {
+	Some(name) => Tz::from_str(name).map_err(|_| format!("unknown timezone {:?}", name)),
+	None => Ok(Tz::UTC),
+    }
+}
+
+impl MaintenanceWindow {
+
+    pub fn validate(&self) -> Result<(), (&'static str, String)> {
+	schedule(&self.cron).map_err(|err| ("cron", err))?;
+	duration(&self.duration).map_err(|err| ("duration", err))?;
+	timezone(&self.timezone).map_err(|err| ("timezone", err))?;
+	Ok(())
+    }
+
+    /*
+     * Whether `now` falls within an occurrence of the window: the first one
+     * starting after now - duration must have already started.
+     */
+    pub fn is_open(&self, now: DateTime<Utc>) -> Result<bool, String> {
+	let schedule = schedule(&self.cron)?;
+	let duration = duration(&self.duration)?;
+	let tz = timezone(&self.timezone)?;
+
+	let since = (now - duration).with_timezone(&tz);
+	match schedule.after(&since).next() {
+	    Some(start) => Ok(start.with_timezone(&Utc) <= now),
+	    None => Ok(false),
+	}
+    }
+}
+
+/*
+ * No window at all means always open, an invalid one never is so a typo
+ * doesn't let changes through.
+ */
+pub fn is_open(windows: &[MaintenanceWindow], now: DateTime<Utc>) -> Result<bool, String> {
+    if windows.is_empty() {
+	return Ok(true);
+    }
+
+    for window in windows {
+	if window.is_open(now)? {
+	    return Ok(true);
+	}
+    }
+    Ok(false)
+}
+
+/* the windows of a KonfigNode, from its annotation */
+pub fn node_windows(metadata: &ObjectMeta) -> Result<Vec<MaintenanceWindow>, String> {
+    match metadata.annotations.as_ref().and_then(|annotations| annotations.get(WINDOWS_ANNOTATION)) {
+	Some(windows) => serde_json::from_str(windows).map_err(|err| format!("invalid {} annotation: {}", WINDOWS_ANNOTATION, err)),
+	None => Ok(vec![]),
+    }
+}
+
+/* set to "true" to apply right away, removed to go back to the windows */
+pub fn is_overridden(metadata: &ObjectMeta) -> bool {
+    match metadata.annotations.as_ref().and_then(|annotations| annotations.get(OVERRIDE_ANNOTATION)) {
+	Some(value) => value == "true",
+	None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(cron: &str, duration: &str, timezone: Option<&str>) -> MaintenanceWindow {
+	MaintenanceWindow{
+	    cron: cron.to_string(),
+	    duration: duration.to_string(),
+	    timezone: timezone.map(String::from),
+	}
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+	DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn durations() {
+	assert_eq!(duration("2h"), Ok(Duration::hours(2)));
+	assert_eq!(duration("1h30m"), Ok(Duration::minutes(90)));
+	assert_eq!(duration("90s"), Ok(Duration::seconds(90)));
+	for invalid in ["", "0m", "2", "h", "2d", "1h 30m", "99999999999999999999h"] {
+	    assert!(duration(invalid).is_err(), "{:?}", invalid);
+	}
+    }
+
+    #[test]
+    fn crontab_days_of_week() {
+	assert_eq!(days_of_week("1-5"), Ok(String::from("2,3,4,5,6")));
+	assert_eq!(days_of_week("0"), Ok(String::from("1")));
+	assert_eq!(days_of_week("7"), Ok(String::from("1")));
+	assert_eq!(days_of_week("5-7"), Ok(String::from("6,7,1")));
+	assert_eq!(days_of_week("*/2"), Ok(String::from("1,3,5,7")));
+	assert_eq!(days_of_week("Mon-Fri,0"), Ok(String::from("Mon-Fri,1")));
+	assert_eq!(days_of_week("*"), Ok(String::from("*")));
+	for invalid in ["8", "5-1", "1/0", "-1", "1-"] {
+	    assert!(days_of_week(invalid).is_err(), "{:?}", invalid);
+	}
+    }
+
+    #[test]
+    fn weekdays() {
+	/* 2024-06-02 is a Sunday */
+	let weekdays = window("0 2 * * 1-5", "2h", None);
+	assert_eq!(weekdays.is_open(at("2024-06-02T02:30:00Z")), Ok(false));
+	assert_eq!(weekdays.is_open(at("2024-06-03T02:30:00Z")), Ok(true));
+	assert_eq!(weekdays.is_open(at("2024-06-07T03:59:00Z")), Ok(true));
+	assert_eq!(weekdays.is_open(at("2024-06-07T04:00:01Z")), Ok(false));
+	assert_eq!(weekdays.is_open(at("2024-06-08T02:30:00Z")), Ok(false));
+
+	let sundays = window("* * * * 0", "1m", None);
+	assert_eq!(sundays.is_open(at("2024-06-02T12:00:00Z")), Ok(true));
+	assert_eq!(sundays.is_open(at("2024-06-03T12:00:00Z")), Ok(false));
+	assert_eq!(window("0 2 * * Sun", "2h", None).is_open(at("2024-06-02T03:00:00Z")), Ok(true));
+    }
+
+    #[test]
+    fn timezones() {
+	/* 02:00 in Sao Paulo (UTC-3) on Sundays, i.e. 05:00 UTC */
+	let sao_paulo = window("0 2 * * 0", "2h", Some("America/Sao_Paulo"));
+	assert_eq!(sao_paulo.is_open(at("2024-06-02T02:30:00Z")), Ok(false));
+	assert_eq!(sao_paulo.is_open(at("2024-06-02T05:30:00Z")), Ok(true));
+	assert_eq!(sao_paulo.is_open(at("2024-06-02T07:00:01Z")), Ok(false));
+
+	/* 23:00 on Saturdays in Tokyo (UTC+9) is still Saturday in UTC, 14:00 */
+	let tokyo = window("0 23 * * 6", "30m", Some("Asia/Tokyo"));
+	assert_eq!(tokyo.is_open(at("2024-06-01T14:10:00Z")), Ok(true));
+	assert_eq!(tokyo.is_open(at("2024-06-01T23:10:00Z")), Ok(false));
+
+	/* windows spanning midnight in their timezone */
+	let lisbon = window("30 23 * * Fri", "1h", Some("Europe/Lisbon"));
+	assert_eq!(lisbon.is_open(at("2024-06-07T23:00:00Z")), Ok(true));
+	assert_eq!(lisbon.is_open(at("2024-06-07T22:29:00Z")), Ok(false));
+
+	assert!(window("0 2 * * *", "2h", Some("Mars/Olympus")).is_open(at("2024-06-02T02:30:00Z")).is_err());
+    }
+
+    #[test]
+    fn any_window() {
+	let now = at("2024-06-03T02:30:00Z");
+	assert_eq!(is_open(&[], now), Ok(true));
+	assert_eq!(is_open(&[window("0 2 * * 0", "1h", None), window("0 2 * * 1", "1h", None)], now), Ok(true));
+	assert_eq!(is_open(&[window("0 2 * * 0", "1h", None)], now), Ok(false));
+	assert!(is_open(&[window("0 2 * *", "1h", None)], now).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+/*
+ * sealed - encrypted content inside KonfigSets, so they can be committed to
+ * git along with their secrets.
+ *
+ * Content is sealed as an ASCII armored age message for a set of X25519
+ * recipients (age1...), typically one per node or per group of nodes, and
+ * only decrypted by konfigd at apply time with its identity
+ * (AGE-SECRET-KEY-1...).  The same messages can be produced and read with
+ * the age(1) command line tool.
+ */
+use age::armor::ArmoredReader;
+use age::armor::ArmoredWriter;
+use age::armor::Format;
+use age::x25519::Identity;
+use age::x25519::Recipient;
+use std::fs;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+
+pub fn is_sealed(content: &str) -> bool {
+    content.trim_start().starts_with(ARMOR_BEGIN)
+}
+
+pub fn seal(plaintext: &[u8], recipients: &[String]) -> Result<String, String> {
+    let recipients = recipients.iter()
+	.map(|recipient| recipient.trim().parse::<Recipient>().map_err(|err| format!("invalid recipient {}: {}", recipient, err)))
+	.collect::<Result<Vec<Recipient>, String>>()?;
+
+    let encryptor = age::Encryptor::with_recipients(recipients.iter().map(|recipient| recipient as &dyn age::Recipient))
+	.map_err(|err| err.to_string())?;
+
+    let mut sealed = vec![];
+    let armored = ArmoredWriter::wrap_output(&mut sealed, Format::AsciiArmor).map_err(|err| err.to_string())?;
+    let mut writer = encryptor.wrap_output(armored).map_err(|err| err.to_string())?;
+    writer.write_all(plaintext).map_err(|err| err.to_string())?;
+    writer.finish().and_then(|armored| armored.finish()).map_err(|err| err.to_string())?;
+
+    String::from_utf8(sealed).map_err(|err| err.to_string())
+}
+
+/*
+ * The error never contains any of the plaintext, so it can be logged.
+ */
+pub fn unseal(content: &str, identities: &[Identity]) -> Result<Vec<u8>, String> {
+    if identities.is_empty() {
+	return Err(String::from("the content is sealed but no identity was given to decrypt it"));
+    }
+
+    let decryptor = age::Decryptor::new_buffered(ArmoredReader::new(content.trim().as_bytes()))
+	.map_err(|err| format!("malformed sealed content: {}", err))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|identity| identity as &dyn age::Identity))
+	.map_err(|err| format!("unable to decrypt the sealed content: {}", err))?;
+
+    let mut plaintext = vec![];
+    reader.read_to_end(&mut plaintext).map_err(|err| format!("unable to decrypt the sealed content: {}", err))?;
+    Ok(plaintext)
+}
+
+/*
+ * age identity files: one AGE-SECRET-KEY-1... per line, comments (#) and
+ * empty lines are ignored.
+ */
+pub fn read_identities(path: &Path) -> Result<Vec<Identity>, String> {
+    let content = fs::read_to_string(path).map_err(|err| format!("Unable to read the identities {}: {}", path.display(), err))?;
+
+    content.lines()
+	.map(|line| line.trim())
+	.filter(|line| !line.is_empty() && !line.starts_with('#'))
+	.map(|line| line.parse::<Identity>().map_err(|_| format!("{} contains an invalid identity", path.display())))
+	.collect()
+}
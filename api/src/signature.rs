@@ -0,0 +1,243 @@
+/*
+ * signature - detached ed25519 signatures of KonfigSets, so konfigd only
+ * applies what was signed by a trusted key and not whatever a (possibly
+ * compromised) namespace editor wrote in the API server.
+ *
+ * The signature covers the namespace, the name and the spec serialized as
+ * canonical JSON (sorted keys, no null values, no whitespace), and is
+ * stored base64 encoded in the ANNOTATION annotation.
+ *
+ * Content coming from outside the spec could change without the signature
+ * noticing, so a signed KonfigSet may only use pinned sources (see
+ * FileSource::is_pinned): no ConfigMaps nor Secrets, http(s) along with a
+ * checksum and git at a commit.
+ */
+use crate::konfigset::KonfigSet;
+use crate::konfigset::kind;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ed25519_dalek::Signature;
+use ed25519_dalek::Signer;
+use ed25519_dalek::SigningKey;
+use ed25519_dalek::Verifier;
+use ed25519_dalek::VerifyingKey;
+use ed25519_dalek::pkcs8::DecodePrivateKey;
+use ed25519_dalek::pkcs8::DecodePublicKey;
+use std::path::Path;
+
+pub const ANNOTATION: &str = "konfig.runfc.br/signature";
+
+/* KonfigNodes applying unsigned or tampered KonfigSets get this condition */
+pub const SIGNATURE_INVALID: &str = "SignatureInvalid";
+
+fn canonical_json(value: &serde_json::Value, out: &mut String) {
+    match value {
+	serde_json::Value::Object(fields) => {
+	    let mut keys: Vec<&String> = fields.iter()
+		.filter(|(_, field)| !field.is_null())
+		.map(|(key, _)| key)
+		.collect();
+	    keys.sort();
+
+	    out.push('{');
+	    for (i, key) in keys.into_iter().enumerate() {
+		if i > 0 {
+		    out.push(',');
+		}
+		out.push_str(&serde_json::Value::from(key.as_str()).to_string());
+		out.push(':');
+		canonical_json(&fields[key], out);
+	    }
+	    out.push('}');
+	},
+	serde_json::Value::Array(items) => {
+	    out.push('[');
+	    for (i, item) in items.iter().enumerate() {
+		if i > 0 {
+		    out.push(',');
+		}
+		canonical_json(item, out);
+	    }
+	    out.push(']');
+	},
+	scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+/*
+ * The signed payload, the same KonfigSet always gives the same bytes
+ * whatever the order of its fields in the YAML it came from.
+ */
+pub fn canonical(konfigset: &KonfigSet) -> Result<Vec<u8>, String> {
+    let spec = serde_json::to_value(&konfigset.spec).map_err(|err| err.to_string())?;
+    let payload = serde_json::json!({
+	"namespace": konfigset.metadata.namespace,
+	"name": konfigset.metadata.name,
+	"spec": spec,
+    });
+
+    let mut out = String::new();
+    canonical_json(&payload, &mut out);
+    Ok(out.into_bytes())
+}
+
+/* the sources of the KonfigSet whose content isn't covered by the signature */
+pub fn unpinned(konfigset: &KonfigSet) -> Vec<String> {
+    let mut unpinned = vec![];
+    let configs = match &konfigset.spec.configurations {
+	Some(configs) => configs,
+	None => return unpinned,
+    };
+
+    for file in configs.files.iter().flatten() {
+	if !file.content_source().is_ok_and(|source| source.is_pinned()) {
+	    unpinned.push(format!("{}:{} ({})", kind::FILE, file.destination, file.source));
+	}
+    }
+    for user in configs.users.iter().flatten() {
+	if let Some(source) = user.authorized_keys.as_ref().and_then(|keys| keys.source.as_ref()) {
+	    unpinned.push(format!("{}:{} ({})", kind::AUTHORIZED_KEYS, user.name, source));
+	}
+    }
+    unpinned
+}
+
+fn pinned(konfigset: &KonfigSet) -> Result<(), String> {
+    let unpinned = unpinned(konfigset);
+
+    match unpinned.is_empty() {
+	true => Ok(()),
+	false => Err(format!("the content of {} isn't covered by the signature, only inline content, http(s) with a checksum and git commits can be signed", unpinned.join(", "))),
+    }
+}
+
+pub fn sign(konfigset: &KonfigSet, key: &SigningKey) -> Result<String, String> {
+    pinned(konfigset)?;
+    let payload = canonical(konfigset)?;
+    Ok(BASE64.encode(key.sign(&payload).to_bytes()))
+}
+
+/*
+ * Succeeds when the KonfigSet is signed by any of the trusted keys.
+ */
+pub fn verify(konfigset: &KonfigSet, trusted: &[VerifyingKey]) -> Result<(), String> {
+    let encoded = match konfigset.metadata.annotations.as_ref().and_then(|annotations| annotations.get(ANNOTATION)) {
+	Some(encoded) => encoded,
+	None => return Err(String::from("not signed")),
+    };
+
+    let bytes = BASE64.decode(encoded.trim()).map_err(|err| format!("malformed signature: {}", err))?;
+    let signature = Signature::from_slice(&bytes).map_err(|err| format!("malformed signature: {}", err))?;
+    let payload = canonical(konfigset)?;
+
+    if !trusted.iter().any(|key| key.verify(&payload, &signature).is_ok()) {
+	return Err(String::from("the signature does not match any trusted key (tampered or signed by an unknown key)"));
+    }
+    pinned(konfigset)
+}
+
+/* PKCS#8 PEM keys, e.g: openssl genpkey -algorithm ed25519 -out konfig.key */
+pub fn read_signing_key(path: &Path) -> Result<SigningKey, String> {
+    SigningKey::read_pkcs8_pem_file(path).map_err(|err| format!("Unable to read the signing key {}: {}", path.display(), err))
+}
+
+/* e.g: openssl pkey -in konfig.key -pubout -out konfig.pub */
+pub fn read_verifying_key(path: &Path) -> Result<VerifyingKey, String> {
+    VerifyingKey::read_public_key_pem_file(path).map_err(|err| format!("Unable to read the public key {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn konfigset(files: serde_json::Value) -> KonfigSet {
+	serde_json::from_value(serde_json::json!({
+	    "apiVersion": "runfc.br/v1alpha",
+	    "kind": "KonfigSet",
+	    "metadata": { "name": "motd", "namespace": "default" },
+	    "spec": { "nodes": ["pi"], "configurations": { "files": files } },
+	})).unwrap()
+    }
+
+    fn signed(mut konfigset: KonfigSet, key: &SigningKey) -> KonfigSet {
+	let signature = sign(&konfigset, key).unwrap();
+	konfigset.metadata.annotations = Some([(ANNOTATION.to_string(), signature)].into());
+	konfigset
+    }
+
+    fn motd() -> KonfigSet {
+	konfigset(serde_json::json!([{ "source": "static://", "destination": "/etc/motd", "content": "hello\n" }]))
+    }
+
+    #[test]
+    fn round_trip() {
+	let key = SigningKey::from_bytes(&[7; 32]);
+	let other = SigningKey::from_bytes(&[8; 32]);
+	let konfigset = signed(motd(), &key);
+
+	assert_eq!(verify(&konfigset, &[key.verifying_key()]), Ok(()));
+	assert_eq!(verify(&konfigset, &[other.verifying_key(), key.verifying_key()]), Ok(()));
+	assert!(verify(&konfigset, &[other.verifying_key()]).is_err());
+	assert_eq!(verify(&motd(), &[key.verifying_key()]), Err(String::from("not signed")));
+    }
+
+    #[test]
+    fn canonical_whatever_the_field_order() {
+	let a: serde_json::Value = serde_json::from_str(r#"{"b": 1, "a": {"d": null, "c": [2, "x"]}}"#).unwrap();
+	let mut out = String::new();
+	canonical_json(&a, &mut out);
+	assert_eq!(out, r#"{"a":{"c":[2,"x"]},"b":1}"#);
+    }
+
+    #[test]
+    fn tampered() {
+	let key = SigningKey::from_bytes(&[7; 32]);
+	let trusted = [key.verifying_key()];
+	let konfigset = signed(motd(), &key);
+
+	let mut content = konfigset.clone();
+	content.spec.configurations.as_mut().unwrap().files.as_mut().unwrap()[0].content = Some(String::from("pwned\n"));
+	assert!(verify(&content, &trusted).is_err());
+
+	let mut nodes = konfigset.clone();
+	nodes.spec.nodes = Some(vec![String::from("pi"), String::from("rock")]);
+	assert!(verify(&nodes, &trusted).is_err());
+
+	let mut moved = konfigset.clone();
+	moved.metadata.namespace = Some(String::from("other"));
+	assert!(verify(&moved, &trusted).is_err());
+
+	let mut garbage = konfigset.clone();
+	garbage.metadata.annotations = Some([(ANNOTATION.to_string(), String::from("not base64!"))].into());
+	assert!(verify(&garbage, &trusted).unwrap_err().starts_with("malformed signature"));
+    }
+
+    #[test]
+    fn only_pinned_sources() {
+	let key = SigningKey::from_bytes(&[7; 32]);
+	let pinned = konfigset(serde_json::json!([
+	    { "source": "https://example.com/ca.pem", "destination": "/etc/ca.pem",
+	      "checksum": "sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08" },
+	    { "source": "git+https://example.com/app.git//etc?ref=0123456789abcdef0123456789abcdef01234567", "destination": "/etc/app" },
+	]));
+	assert_eq!(verify(&signed(pinned, &key), &[key.verifying_key()]), Ok(()));
+
+	for source in ["k8s://configmap/motd#motd", "https://example.com/motd", "git+https://example.com/app.git//motd?ref=main", "git+https://example.com/app.git//motd"] {
+	    let unpinned = konfigset(serde_json::json!([{ "source": source, "destination": "/etc/motd" }]));
+	    assert!(sign(&unpinned, &key).is_err(), "{}", source);
+
+	    /* signed anyway, e.g: by an older konfigm */
+	    let payload = canonical(&unpinned).unwrap();
+	    let mut forced = unpinned.clone();
+	    forced.metadata.annotations = Some([(ANNOTATION.to_string(), BASE64.encode(key.sign(&payload).to_bytes()))].into());
+	    assert!(verify(&forced, &[key.verifying_key()]).unwrap_err().contains("isn't covered by the signature"), "{}", source);
+	}
+
+	let mut keys = motd();
+	keys.spec.configurations.as_mut().unwrap().users = Some(vec![serde_json::from_value(serde_json::json!({
+	    "name": "alice", "authorizedKeys": { "source": "k8s://secret/alice", "key": "keys" },
+	})).unwrap()]);
+	assert_eq!(unpinned(&keys), vec!["authorizedkeys:alice (k8s://secret/alice)"]);
+    }
+}
@@ -0,0 +1,336 @@
+/*
+ * validation - checks of the KonfigSet and KonfigNode specs which don't need
+ * the cluster nor the node, shared by konfigd (before applying), the konfigm
+ * admission webhook and `konfigm validate`.
+ */
+use crate::konfignode::KonfigNodeSpec;
+use crate::konfigset::FileSource;
+use crate::konfigset::KonfigAuthorizedKeys;
+use crate::konfigset::KonfigFile;
+use crate::konfigset::KonfigSetSpec;
+use crate::konfigset::KonfigUser;
+
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use std::fmt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+/*
+ * A problem with a single field, e.g:
+ *   spec.configurations.files[0].destination: must be an absolute path
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/*
+ * Both the dotted (net.ipv4.ip_forward) and the slashed form
+ * (net/ipv4/ip_forward) are accepted, like sysctl(8) does; the returned
+ * path is relative to /proc/sys.
+ */
+pub fn sysctl_path(name: &str) -> Result<PathBuf, String> {
+    let separator = if name.contains('/') { '/' } else { '.' };
+
+    if name.is_empty() || name.split(separator).any(|part| part.is_empty()) {
+	return Err(format!("invalid sysctl name {:?}", name));
+    }
+
+    let relative = PathBuf::from(name.replace(separator, "/"));
+    if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+	return Err(format!("invalid sysctl name {:?}", name));
+    }
+    Ok(relative)
+}
+
+/* file destinations are absolute and can't escape the root directory */
+pub fn destination(path: &str) -> Result<(), String> {
+    let path = Path::new(path);
+
+    if !path.is_absolute() {
+	return Err(String::from("must be an absolute path"));
+    }
+    if path.components().any(|c| matches!(c, Component::ParentDir | Component::CurDir)) {
+	return Err(String::from("must not contain . or .. components"));
+    }
+    if path.file_name().is_none() {
+	return Err(String::from("must name a file"));
+    }
+    Ok(())
+}
+
+/* the names of most k8s objects (e.g: ConfigMaps) are DNS subdomains */
+pub fn object_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+	&& name.len() <= 253
+	&& name.split('.').all(|part| is_dns_label(part, 253));
+
+    if !valid {
+	return Err(format!("invalid object name {:?}", name));
+    }
+    Ok(())
+}
+
+/* and namespaces are DNS labels */
+pub fn namespace(name: &str) -> Result<(), String> {
+    if !is_dns_label(name, 63) {
+	return Err(format!("invalid namespace {:?}", name));
+    }
+    Ok(())
+}
+
+fn is_dns_label(label: &str, max: usize) -> bool {
+    !label.is_empty()
+	&& label.len() <= max
+	&& !label.starts_with('-')
+	&& !label.ends_with('-')
+	&& label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/* the keys of the data of ConfigMaps and Secrets */
+pub fn config_key(key: &str) -> Result<(), String> {
+    let valid = !key.is_empty()
+	&& key.len() <= 253
+	&& key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+    if !valid {
+	return Err(format!("invalid key {:?}", key));
+    }
+    Ok(())
+}
+
+/* returns the field and the problem of the content source of a file */
+pub fn file_source(file: &KonfigFile) -> Result<(), (&'static str, String)> {
+    match file.content_source()? {
+	FileSource::Static => file.static_content().map(|_| ()),
+	_ if file.content_base64.is_some() => Err(("contentBase64", String::from("only applies to static:// sources"))),
+	_ => Ok(()),
+    }
+}
+
+pub fn authorized_keys_source(keys: &KonfigAuthorizedKeys) -> Result<(), (&'static str, String)> {
+    keys.content_source().map(|_| ())
+}
+
+/* user and group names as accepted by useradd(8) */
+pub fn account_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+	&& name.len() <= 32
+	&& !name.starts_with('-')
+	&& name.strip_suffix('$').unwrap_or(name).chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+
+    if !valid {
+	return Err(format!("invalid name {:?}", name));
+    }
+    Ok(())
+}
+
+/*
+ * The comment (GECOS), home and shell of a user end up in a passwd(5) row:
+ * a ':' or a newline would add fields or rows, and the comment is itself
+ * split on ','.
+ */
+pub fn passwd_field(value: &str, comma_separated: bool) -> Result<(), String> {
+    if value.chars().any(|c| c == ':' || c.is_control() || (comma_separated && c == ',')) {
+	return Err(format!("invalid value {:?}, ':'{} and control characters are not allowed", value, if comma_separated { ", ','" } else { "" }));
+    }
+    Ok(())
+}
+
+/* the passwd fields of a user, with the name of the invalid one */
+pub fn user_fields(user: &KonfigUser) -> Result<(), (&'static str, String)> {
+    if let Some(home) = &user.home {
+	destination(home).and_then(|_| passwd_field(home, false)).map_err(|err| ("home", err))?;
+    }
+    if let Some(shell) = &user.shell {
+	passwd_field(shell, false).map_err(|err| ("shell", err))?;
+    }
+    if let Some(comment) = &user.comment {
+	passwd_field(comment, true).map_err(|err| ("comment", err))?;
+    }
+    Ok(())
+}
+
+fn ensure(ensure: &Option<String>) -> Result<(), String> {
+    match ensure.as_deref() {
+	None | Some("present") | Some("absent") => Ok(()),
+	Some(other) => Err(format!("unsupported value {:?}, valid values are: present, absent", other)),
+    }
+}
+
+fn label_selector(selector: &LabelSelector) -> Vec<(String, String)> {
+    let mut errors = vec![];
+
+    for (i, requirement) in selector.match_expressions.iter().flatten().enumerate() {
+	let field = format!("matchExpressions[{}]", i);
+	let values = requirement.values.clone().unwrap_or_default();
+
+	match requirement.operator.as_str() {
+	    "In" | "NotIn" if values.is_empty() => errors.push((format!("{}.values", field), String::from("must not be empty for In and NotIn"))),
+	    "Exists" | "DoesNotExist" if !values.is_empty() => errors.push((format!("{}.values", field), String::from("must be empty for Exists and DoesNotExist"))),
+	    "In" | "NotIn" | "Exists" | "DoesNotExist" => {},
+	    other => errors.push((format!("{}.operator", field), format!("unsupported operator {:?}", other))),
+	}
+    }
+    errors
+}
+
+pub fn konfigset(spec: &KonfigSetSpec) -> Vec<FieldError> {
+    let mut errors: Vec<FieldError> = vec![];
+    let mut error = |field: String, message: String| errors.push(FieldError{ field, message });
+
+    if let Some(selector) = &spec.node_selector {
+	for (field, message) in label_selector(selector) {
+	    error(format!("spec.nodeSelector.{}", field), message);
+	}
+    }
+    for (i, kref) in spec.requires.iter().flatten().enumerate() {
+	if kref.name.as_deref().unwrap_or_default().is_empty() {
+	    error(format!("spec.requires[{}].name", i), String::from("is required"));
+	}
+    }
+    for (i, kref) in spec.before.iter().flatten().enumerate() {
+	if kref.name.as_deref().unwrap_or_default().is_empty() {
+	    error(format!("spec.before[{}].name", i), String::from("is required"));
+	}
+    }
+    for (i, window) in spec.schedule.iter().flatten().enumerate() {
+	if let Err((name, err)) = window.validate() {
+	    error(format!("spec.schedule[{}].{}", i, name), err);
+	}
+    }
+
+    let configs = match &spec.configurations {
+	Some(configs) => configs,
+	None => return errors,
+    };
+
+    for (i, sysctl) in configs.sysctls.iter().flatten().enumerate() {
+	if let Err(err) = sysctl_path(&sysctl.name) {
+	    error(format!("spec.configurations.sysctls[{}].name", i), err);
+	}
+    }
+
+    for (i, file) in configs.files.iter().flatten().enumerate() {
+	let field = format!("spec.configurations.files[{}]", i);
+
+	if let Err(err) = destination(&file.destination) {
+	    error(format!("{}.destination", field), err);
+	}
+	if let Err((name, err)) = file_source(file) {
+	    error(format!("{}.{}", field, name), err);
+	}
+	if let Some(mode) = file.mode {
+	    if mode > 0o7777 {
+		error(format!("{}.mode", field), format!("invalid mode {:o}", mode));
+	    }
+	}
+    }
+
+    for (i, group) in configs.groups.iter().flatten().enumerate() {
+	let field = format!("spec.configurations.groups[{}]", i);
+
+	if let Err(err) = account_name(&group.name) {
+	    error(format!("{}.name", field), err);
+	}
+	if let Err(err) = ensure(&group.ensure) {
+	    error(format!("{}.ensure", field), err);
+	}
+    }
+
+    for (i, user) in configs.users.iter().flatten().enumerate() {
+	let field = format!("spec.configurations.users[{}]", i);
+
+	if let Err(err) = account_name(&user.name) {
+	    error(format!("{}.name", field), err);
+	}
+	if let Err(err) = ensure(&user.ensure) {
+	    error(format!("{}.ensure", field), err);
+	}
+	for (j, group) in user.groups.iter().flatten().enumerate() {
+	    if let Err(err) = account_name(group) {
+		error(format!("{}.groups[{}]", field, j), err);
+	    }
+	}
+	if let Err((name, err)) = user_fields(user) {
+	    error(format!("{}.{}", field, name), err);
+	}
+	if let Some(keys) = &user.authorized_keys {
+	    if let Err((name, err)) = authorized_keys_source(keys) {
+		error(format!("{}.authorizedKeys.{}", field, name), err);
+	    }
+	}
+    }
+
+    /* a single KonfigSet can't manage the same resource twice either */
+    let mut seen = vec![];
+    for entry in configs.entries() {
+	if seen.contains(&entry.id) {
+	    error(String::from("spec.configurations"), format!("{} is defined more than once", entry.id));
+	}
+	seen.push(entry.id);
+    }
+
+    if let Err(err) = configs.dependency_graph() {
+	error(String::from("spec.configurations"), err);
+    }
+
+    errors
+}
+
+pub fn konfignode(spec: &KonfigNodeSpec) -> Vec<FieldError> {
+    let mut errors: Vec<FieldError> = vec![];
+
+    for (i, kref) in spec.configsets.iter().flatten().enumerate() {
+	if kref.name.as_deref().unwrap_or_default().is_empty() {
+	    errors.push(FieldError{ field: format!("spec.configsets[{}].name", i), message: String::from("is required") });
+	}
+	if kref.namespace.as_deref().unwrap_or_default().is_empty() {
+	    errors.push(FieldError{ field: format!("spec.configsets[{}].namespace", i), message: String::from("is required") });
+	}
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(user: serde_json::Value) -> KonfigSetSpec {
+	serde_json::from_value(serde_json::json!({ "configurations": { "users": [user] } })).unwrap()
+    }
+
+    #[test]
+    fn passwd_fields() {
+	assert_eq!(passwd_field("Jane Doe,Room 1,,", false), Ok(()));
+	assert_eq!(passwd_field("/usr/bin/zsh", true), Ok(()));
+	assert!(passwd_field("Jane Doe,Room 1", true).is_err());
+	assert!(passwd_field("/bin/sh:0:0", false).is_err());
+	assert!(passwd_field("/bin/sh\nroot::0:0::/root:/bin/sh", false).is_err());
+	assert!(passwd_field("tab\there", false).is_err());
+
+	let valid = spec(serde_json::json!({ "name": "jane", "comment": "Jane Doe", "home": "/srv/jane", "shell": "/bin/bash" }));
+	assert_eq!(konfigset(&valid), vec![]);
+
+	let fields = [
+	    ("comment", "Jane Doe,Room 1"),
+	    ("comment", "Jane:Doe"),
+	    ("shell", "/bin/sh\nevil::0:0::/:/bin/sh"),
+	    ("home", "/srv/jane:x"),
+	    ("home", "/srv/jane\r"),
+	];
+	for (name, value) in fields {
+	    let errors = konfigset(&spec(serde_json::json!({ "name": "jane", name: value })));
+	    assert_eq!(errors.len(), 1, "{} {:?}", name, value);
+	    assert_eq!(errors[0].field, format!("spec.configurations.users[0].{}", name));
+	}
+    }
+}
@@ -0,0 +1,778 @@
+/*
+ * accounts - manages local users, groups and their ssh authorized_keys by
+ * editing the /etc databases (passwd, shadow, group and gshadow) directly.
+ *
+ * Every path is resolved against `root`, so the very same code manages the
+ * running host (root = /) or an alternate tree such as a chroot, a mounted
+ * image or a temporary directory.
+ */
+use crate::errors::Error;
+use crate::local::rooted;
+use crate::local::open_directory;
+use crate::local::write_atomic;
+use crate::provider::Context;
+use crate::provider::Parsed;
+use crate::provider::Resource;
+use crate::provider::ResourceId;
+use crate::provider::ResourceProvider;
+use crate::provider::ResourceResult;
+use crate::sources;
+use konfig_api as api;
+
+use futures::future::BoxFuture;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/* range used when allocating uids/gids, same as the useradd(8) defaults */
+const FIRST_ID: u32 = 1000;
+const LAST_ID: u32 = 60000;
+
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+pub const GROUP_KIND: &str = api::konfigset::kind::GROUP;
+pub const USER_KIND: &str = api::konfigset::kind::USER;
+pub const AUTHORIZED_KEYS_KIND: &str = api::konfigset::kind::AUTHORIZED_KEYS;
+
+fn is_absent(ensure: &Option<String>) -> Result<bool, Error> {
+    match ensure.as_deref() {
+	None | Some("present") => Ok(false),
+	Some("absent") => Ok(true),
+	Some(other) => {
+	    let errmsg = format!("ensure must be either 'present' or 'absent', got: {}", other);
+	    Err(Error::KonfigError(errmsg))
+	}
+    }
+}
+
+fn days_since_epoch() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+	Ok(elapsed) => elapsed.as_secs() / 86400,
+	Err(_) => 0,
+    }
+}
+
+/*
+ * A colon separated database from /etc (passwd, shadow, group or gshadow),
+ * rows are keyed by their first field.
+ */
+#[derive(Clone, Debug, PartialEq)]
+struct Table {
+    path: PathBuf,
+    exists: bool,
+    mode: u32,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+
+    fn load(root: &Path, name: &str, fields: usize, mode: u32) -> Result<Table, Error> {
+	let path = rooted(root, name)?;
+	let content = match fs::read_to_string(&path) {
+	    Ok(content) => content,
+	    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+		return Ok(Table{ path, exists: false, mode, rows: vec![] });
+	    },
+	    Err(err) => return Err(Error::Io(err)),
+	};
+
+	let mut rows = vec![];
+	for line in content.lines().filter(|line| !line.is_empty()) {
+	    let mut row: Vec<String> = line.split(':').map(String::from).collect();
+	    if row.len() < fields {
+		row.resize(fields, String::new());
+	    }
+	    rows.push(row);
+	}
+
+	Ok(Table{ path, exists: true, mode, rows })
+    }
+
+    fn find(&self, name: &str) -> Option<&Vec<String>> {
+	self.rows.iter().find(|row| row[0] == name)
+    }
+
+    fn upsert(&mut self, new: Vec<String>) {
+	match self.rows.iter_mut().find(|row| row[0] == new[0]) {
+	    Some(row) => *row = new,
+	    None => self.rows.push(new),
+	}
+    }
+
+    fn remove(&mut self, name: &str) {
+	self.rows.retain(|row| row[0] != name);
+    }
+
+    fn ids(&self) -> Vec<u32> {
+	self.rows.iter().filter_map(|row| row[2].parse().ok()).collect()
+    }
+
+    /* the next free id after the highest one allocated in the regular range */
+    fn next_id(&self) -> Result<u32, Error> {
+	let highest = self.ids().into_iter().filter(|id| (FIRST_ID..LAST_ID).contains(id)).max();
+	match highest {
+	    None => Ok(FIRST_ID),
+	    Some(id) if id + 1 < LAST_ID => Ok(id + 1),
+	    Some(_) => {
+		let errmsg = format!("No free id left in {}", self.path.display());
+		Err(Error::KonfigError(errmsg))
+	    }
+	}
+    }
+
+    fn save(&self) -> Result<(), Error> {
+	let mut content = String::new();
+	for row in &self.rows {
+	    content.push_str(&row.join(":"));
+	    content.push('\n');
+	}
+
+	/* keep the permissions and ownership of the database we are replacing */
+	let (mode, owner) = match fs::metadata(&self.path) {
+	    Ok(meta) => (meta.permissions().mode() & 0o7777, Some((meta.uid(), meta.gid()))),
+	    Err(_) => (self.mode, None),
+	};
+	write_atomic(&self.path, content.as_bytes(), mode, owner)
+    }
+}
+
+/* splits the members field of group/gshadow entries */
+fn members(field: &str) -> Vec<String> {
+    field.split(',').filter(|m| !m.is_empty()).map(String::from).collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Databases {
+    passwd: Table,
+    shadow: Table,
+    group: Table,
+    gshadow: Table,
+}
+
+impl Databases {
+
+    fn load(root: &Path) -> Result<Databases, Error> {
+	Ok(Databases{
+	    passwd: Table::load(root, "/etc/passwd", 7, 0o644)?,
+	    shadow: Table::load(root, "/etc/shadow", 9, 0o600)?,
+	    group: Table::load(root, "/etc/group", 4, 0o644)?,
+	    gshadow: Table::load(root, "/etc/gshadow", 4, 0o600)?,
+	})
+    }
+
+    /*
+     * Writes back only the databases that differs from `current`, a missing
+     * shadow or gshadow is never created as the system is not using them.
+     */
+    fn save(&self, current: &Databases) -> Result<(), Error> {
+	let tables = [
+	    (&self.group, &current.group),
+	    (&self.gshadow, &current.gshadow),
+	    (&self.passwd, &current.passwd),
+	    (&self.shadow, &current.shadow),
+	];
+	for (desired, current) in tables {
+	    if desired != current {
+		desired.save()?;
+	    }
+	}
+	Ok(())
+    }
+
+    /*
+     * Lists which entries differs from `current`, only the entry names are
+     * reported so password hashes never leak into logs.
+     */
+    fn diff(&self, current: &Databases) -> Vec<String> {
+	let mut changes = vec![];
+	let tables = [
+	    ("group", &self.group, &current.group),
+	    ("gshadow", &self.gshadow, &current.gshadow),
+	    ("passwd", &self.passwd, &current.passwd),
+	    ("shadow", &self.shadow, &current.shadow),
+	];
+
+	for (db, desired, current) in tables {
+	    for row in &desired.rows {
+		match current.find(&row[0]) {
+		    None => changes.push(format!("{}: add {}", db, row[0])),
+		    Some(old) if old != row => changes.push(format!("{}: update {}", db, row[0])),
+		    Some(_) => {},
+		}
+	    }
+	    for row in current.rows.iter().filter(|row| desired.find(&row[0]).is_none()) {
+		changes.push(format!("{}: remove {}", db, row[0]));
+	    }
+	}
+	changes
+    }
+
+    fn group_gid(&self, name: &str) -> Option<u32> {
+	self.group.find(name).and_then(|row| row[2].parse().ok())
+    }
+
+    fn set_membership(&mut self, user: &str, group: &str, member: bool) {
+	for table in [&mut self.group, &mut self.gshadow] {
+	    for row in table.rows.iter_mut().filter(|row| row[0] == group) {
+		let mut list = members(&row[3]);
+		let is_member = list.iter().any(|m| m == user);
+
+		if member && !is_member {
+		    list.push(user.to_string());
+		} else if !member && is_member {
+		    list.retain(|m| m != user);
+		}
+		row[3] = list.join(",");
+	    }
+	}
+    }
+
+    fn add_group(&mut self, name: &str, gid: u32) {
+	let (password, members) = match self.group.find(name) {
+	    Some(row) => (row[1].clone(), row[3].clone()),
+	    None => (String::from("x"), String::new()),
+	};
+	self.group.upsert(vec![name.to_string(), password, gid.to_string(), members.clone()]);
+
+	if self.gshadow.exists {
+	    let (password, admins) = match self.gshadow.find(name) {
+		Some(row) => (row[1].clone(), row[2].clone()),
+		None => (String::from("!"), String::new()),
+	    };
+	    self.gshadow.upsert(vec![name.to_string(), password, admins, members]);
+	}
+    }
+}
+
+/*
+ * The accounts konfigd created, kept in <state_dir>/accounts as one
+ * `kind:name` per line: unless a KonfigPolicy allows it, the other accounts
+ * of the node (e.g: the ubuntu user of the image) are left alone.
+ */
+struct Created {
+    path: PathBuf,
+    ids: Vec<String>,
+}
+
+impl Created {
+
+    fn load(state_dir: &Path) -> Result<Created, Error> {
+	let path = state_dir.join("accounts");
+	let ids = match fs::read_to_string(&path) {
+	    Ok(content) => content.lines().filter(|line| !line.is_empty()).map(String::from).collect(),
+	    Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+	    Err(err) => return Err(Error::Io(err)),
+	};
+	Ok(Created{ path, ids })
+    }
+
+    fn contains(&self, id: &ResourceId) -> bool {
+	self.ids.contains(&id.to_string())
+    }
+
+    /* records the account as created by us, or forgets it once removed */
+    fn record(state_dir: &Path, id: &ResourceId, created: bool) -> Result<(), Error> {
+	let mut accounts = Created::load(state_dir)?;
+	if accounts.contains(id) == created {
+	    return Ok(());
+	}
+	match created {
+	    true => accounts.ids.push(id.to_string()),
+	    false => accounts.ids.retain(|other| *other != id.to_string()),
+	}
+
+	let mut content = String::new();
+	for id in &accounts.ids {
+	    content.push_str(id);
+	    content.push('\n');
+	}
+	fs::create_dir_all(state_dir)?;
+	write_atomic(&accounts.path, content.as_bytes(), 0o600, None)
+    }
+}
+
+/*
+ * Refuses the accounts konfigd didn't create unless a KonfigPolicy of the
+ * namespace allows adopting them, the missing ones are created by us.
+ */
+fn adoptable(ctx: &Context, kind: &str, name: &str) -> Result<(), Error> {
+    if ctx.adopt_accounts {
+	return Ok(());
+    }
+
+    let dbs = Databases::load(&ctx.root)?;
+    let exists = match kind {
+	GROUP_KIND => dbs.group.find(name).is_some(),
+	_ => dbs.passwd.find(name).is_some(),
+    };
+    if exists && !Created::load(&ctx.state_dir)?.contains(&ResourceId::new(kind, name)) {
+	let errmsg = format!("The {} {} was not created by konfigd, managing it is not allowed by any KonfigPolicy of namespace {}",
+			     kind, name, ctx.namespace);
+	return Err(Error::KonfigError(errmsg));
+    }
+    Ok(())
+}
+
+/*
+ * A local group, managed in /etc/group (and /etc/gshadow when present).
+ */
+#[derive(Debug)]
+pub struct Group {
+    root: PathBuf,
+    state_dir: PathBuf,
+    spec: api::KonfigGroup,
+}
+
+impl Group {
+
+    pub fn new(root: &Path, state_dir: &Path, spec: &api::KonfigGroup) -> Self {
+	Self{
+	    root: root.to_path_buf(),
+	    state_dir: state_dir.to_path_buf(),
+	    spec: spec.clone(),
+	}
+    }
+
+    fn desired(&self, current: &Databases) -> Result<Databases, Error> {
+	let mut dbs = current.clone();
+	let name = self.spec.name.as_str();
+
+	if is_absent(&self.spec.ensure)? {
+	    if let Some(gid) = dbs.group_gid(name) {
+		if let Some(user) = dbs.passwd.rows.iter().find(|row| row[3] == gid.to_string()) {
+		    let errmsg = format!("Unable to remove group {}, it is the primary group of user {}", name, user[0]);
+		    return Err(Error::KonfigError(errmsg));
+		}
+	    }
+	    dbs.group.remove(name);
+	    dbs.gshadow.remove(name);
+	    return Ok(dbs);
+	}
+
+	let gid = match (self.spec.gid, dbs.group_gid(name)) {
+	    (Some(gid), _) => gid,
+	    (None, Some(gid)) => gid,
+	    (None, None) => dbs.group.next_id()?,
+	};
+	if let Some(other) = dbs.group.rows.iter().find(|row| row[2] == gid.to_string() && row[0] != name) {
+	    let errmsg = format!("Unable to use gid {} for group {}, it is already used by {}", gid, name, other[0]);
+	    return Err(Error::KonfigError(errmsg));
+	}
+	dbs.add_group(name, gid);
+
+	Ok(dbs)
+    }
+}
+
+impl Resource for Group {
+
+    fn id(&self) -> ResourceId {
+	ResourceId::new(GROUP_KIND, &self.spec.name)
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+	let current = Databases::load(&self.root)?;
+	Ok(self.desired(&current)? != current)
+    }
+
+    fn apply(&self) -> Result<(), Error> {
+	let current = Databases::load(&self.root)?;
+	let desired = self.desired(&current)?;
+	desired.save(&current)?;
+
+	let name = self.spec.name.as_str();
+	match (current.group.find(name), desired.group.find(name)) {
+	    (None, Some(_)) => Created::record(&self.state_dir, &self.id(), true),
+	    (Some(_), None) => Created::record(&self.state_dir, &self.id(), false),
+	    _ => Ok(()),
+	}
+    }
+
+    fn describe_diff(&self) -> String {
+	let current = match Databases::load(&self.root) {
+	    Ok(current) => current,
+	    Err(err) => return format!("unknown ({})", err),
+	};
+	match self.desired(&current) {
+	    Ok(desired) => desired.diff(&current).join(", "),
+	    Err(err) => format!("unknown ({})", err),
+	}
+    }
+}
+
+/*
+ * A local user account, managed in /etc/passwd, /etc/shadow and its
+ * supplementary groups in /etc/group.
+ */
+#[derive(Debug)]
+pub struct User {
+    root: PathBuf,
+    state_dir: PathBuf,
+    spec: api::KonfigUser,
+}
+
+impl User {
+
+    pub fn new(root: &Path, state_dir: &Path, spec: &api::KonfigUser) -> Self {
+	Self{
+	    root: root.to_path_buf(),
+	    state_dir: state_dir.to_path_buf(),
+	    spec: spec.clone(),
+	}
+    }
+
+    fn desired(&self, current: &Databases) -> Result<Databases, Error> {
+	let mut dbs = current.clone();
+	let spec = &self.spec;
+	let name = spec.name.as_str();
+	let existing = dbs.passwd.find(name).cloned();
+
+	if is_absent(&spec.ensure)? {
+	    dbs.passwd.remove(name);
+	    dbs.shadow.remove(name);
+	    for group in current.group.rows.iter().map(|row| row[0].clone()) {
+		dbs.set_membership(name, &group, false);
+	    }
+	    return Ok(dbs);
+	}
+
+	let uid = match (spec.uid, &existing) {
+	    (Some(uid), _) => uid,
+	    (None, Some(row)) => row[2].parse().unwrap_or_default(),
+	    (None, None) => dbs.passwd.next_id()?,
+	};
+	if let Some(other) = dbs.passwd.rows.iter().find(|row| row[2] == uid.to_string() && row[0] != name) {
+	    let errmsg = format!("Unable to use uid {} for user {}, it is already used by {}", uid, name, other[0]);
+	    return Err(Error::KonfigError(errmsg));
+	}
+
+	/*
+	 * The primary group is (in order) the one requested, the one the user
+	 * already has, or a group with the user's name created on demand.
+	 */
+	let gid = match (spec.gid, &existing, dbs.group_gid(name)) {
+	    (Some(gid), _, _) => {
+		if !dbs.group.ids().contains(&gid) {
+		    let errmsg = format!("The primary group {} of user {} does not exist", gid, name);
+		    return Err(Error::KonfigError(errmsg));
+		}
+		gid
+	    },
+	    (None, Some(row), _) => row[3].parse().unwrap_or_default(),
+	    (None, None, Some(gid)) => gid,
+	    (None, None, None) => {
+		let gid = if dbs.group.ids().contains(&uid) { dbs.group.next_id()? } else { uid };
+		dbs.add_group(name, gid);
+		gid
+	    },
+	};
+
+	let field = |idx: usize, wanted: &Option<String>, default: String| -> String {
+	    match (wanted, &existing) {
+		(Some(value), _) => value.clone(),
+		(None, Some(row)) => row[idx].clone(),
+		(None, None) => default,
+	    }
+	};
+	let comment = field(4, &spec.comment, String::new());
+	let home = field(5, &spec.home, format!("/home/{}", name));
+	let shell = field(6, &spec.shell, String::from(DEFAULT_SHELL));
+	dbs.passwd.upsert(vec![
+	    name.to_string(), String::from("x"), uid.to_string(), gid.to_string(), comment, home, shell,
+	]);
+
+	if dbs.shadow.exists {
+	    let mut row = match dbs.shadow.find(name) {
+		Some(row) => row.clone(),
+		None => vec![
+		    name.to_string(), String::from("*"), days_since_epoch().to_string(),
+		    String::from("0"), String::from("99999"), String::from("7"),
+		    String::new(), String::new(), String::new(),
+		],
+	    };
+	    match spec.locked {
+		Some(true) if !row[1].starts_with('!') => row[1] = format!("!{}", row[1]),
+		Some(false) if row[1].starts_with('!') => {
+		    let unlocked = row[1].trim_start_matches('!');
+		    row[1] = if unlocked.is_empty() { String::from("*") } else { unlocked.to_string() };
+		},
+		_ => {},
+	    }
+	    dbs.shadow.upsert(row);
+	}
+
+	if let Some(groups) = &spec.groups {
+	    for group in groups {
+		if dbs.group.find(group).is_none() {
+		    let errmsg = format!("The supplementary group {} of user {} does not exist", group, name);
+		    return Err(Error::KonfigError(errmsg));
+		}
+	    }
+	    for group in dbs.group.rows.iter().map(|row| row[0].clone()).collect::<Vec<String>>() {
+		dbs.set_membership(name, &group, groups.contains(&group));
+	    }
+	}
+
+	Ok(dbs)
+    }
+
+    fn home(&self, dbs: &Databases) -> Result<Option<PathBuf>, Error> {
+	match dbs.passwd.find(&self.spec.name) {
+	    Some(row) => rooted(&self.root, &row[5]).map(Some),
+	    None => Ok(None),
+	}
+    }
+}
+
+impl Resource for User {
+
+    fn id(&self) -> ResourceId {
+	ResourceId::new(USER_KIND, &self.spec.name)
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+	let current = Databases::load(&self.root)?;
+	let desired = self.desired(&current)?;
+
+	if is_absent(&self.spec.ensure)? {
+	    return Ok(desired != current);
+	}
+
+	let home_missing = match self.home(&desired)? {
+	    Some(home) => !home.is_dir(),
+	    None => false,
+	};
+	Ok(desired != current || home_missing)
+    }
+
+    fn describe_diff(&self) -> String {
+	let current = match Databases::load(&self.root) {
+	    Ok(current) => current,
+	    Err(err) => return format!("unknown ({})", err),
+	};
+	let desired = match self.desired(&current) {
+	    Ok(desired) => desired,
+	    Err(err) => return format!("unknown ({})", err),
+	};
+
+	let mut changes = desired.diff(&current);
+	if let (Ok(Some(home)), Some(row)) = (self.home(&desired), desired.passwd.find(&self.spec.name)) {
+	    if !home.is_dir() {
+		changes.push(format!("create home {}", row[5]));
+	    }
+	}
+	changes.join(", ")
+    }
+
+    fn apply(&self) -> Result<(), Error> {
+	let current = Databases::load(&self.root)?;
+	let desired = self.desired(&current)?;
+	desired.save(&current)?;
+
+	/* the group with the user's name may have been created on demand */
+	let name = self.spec.name.as_str();
+	match (current.passwd.find(name), desired.passwd.find(name)) {
+	    (None, Some(_)) => Created::record(&self.state_dir, &self.id(), true)?,
+	    (Some(_), None) => Created::record(&self.state_dir, &self.id(), false)?,
+	    _ => {},
+	}
+	if current.group.find(name).is_none() && desired.group.find(name).is_some() {
+	    Created::record(&self.state_dir, &ResourceId::new(GROUP_KIND, name), true)?;
+	}
+
+	if is_absent(&self.spec.ensure)? {
+	    /* like userdel(8) without -r, the home directory is kept */
+	    return Ok(());
+	}
+
+	if let (Some(home), Some(row)) = (self.home(&desired)?, desired.passwd.find(&self.spec.name)) {
+	    if !home.is_dir() {
+		let uid = row[2].parse().unwrap_or_default();
+		let gid = row[3].parse().unwrap_or_default();
+
+		fs::create_dir_all(&home)?;
+		let home = open_directory(&home)?;
+		home.set_permissions(fs::Permissions::from_mode(0o700))?;
+		std::os::unix::fs::fchown(&home, Some(uid), Some(gid))?;
+	    }
+	}
+	Ok(())
+    }
+}
+
+/*
+ * The ~/.ssh/authorized_keys of a (managed or not) local user, the content
+ * is fully replaced by the keys defined in the KonfigSet.
+ */
+#[derive(Debug)]
+pub struct AuthorizedKeys {
+    root: PathBuf,
+    user: String,
+    content: String,
+}
+
+impl AuthorizedKeys {
+
+    pub fn new(root: &Path, user: &str, keys: &[String]) -> Self {
+	let mut content = String::new();
+	for key in keys.iter().map(|key| key.trim()).filter(|key| !key.is_empty()) {
+	    content.push_str(key);
+	    content.push('\n');
+	}
+
+	Self{
+	    root: root.to_path_buf(),
+	    user: user.to_string(),
+	    content,
+	}
+    }
+
+    /* returns the authorized_keys path and the owner of the user */
+    fn target(&self) -> Result<Option<(PathBuf, u32, u32)>, Error> {
+	let passwd = Table::load(&self.root, "/etc/passwd", 7, 0o644)?;
+	let row = match passwd.find(&self.user) {
+	    Some(row) => row,
+	    None => return Ok(None),
+	};
+
+	let path = rooted(&self.root, &row[5])?.join(".ssh").join("authorized_keys");
+	Ok(Some((path, row[2].parse().unwrap_or_default(), row[3].parse().unwrap_or_default())))
+    }
+}
+
+impl Resource for AuthorizedKeys {
+
+    fn id(&self) -> ResourceId {
+	ResourceId::new(AUTHORIZED_KEYS_KIND, &self.user)
+    }
+
+    fn describe_diff(&self) -> String {
+	let keys = self.content.lines().count();
+	format!("write {} key(s) into ~{}/.ssh/authorized_keys", keys, self.user)
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+	/* the user may not exist yet, it is going to be created before us */
+	let (path, uid, gid) = match self.target()? {
+	    Some(target) => target,
+	    None => return Ok(true),
+	};
+
+	/* a symlink is drift, it gets replaced by a regular file */
+	let meta = match fs::symlink_metadata(&path) {
+	    Ok(meta) => meta,
+	    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+	    Err(err) => return Err(Error::Io(err)),
+	};
+	if !meta.is_file() {
+	    return Ok(true);
+	}
+	let content = fs::read(&path)?;
+
+	Ok(content != self.content.as_bytes()
+	    || meta.permissions().mode() & 0o777 != 0o600
+	    || meta.uid() != uid
+	    || meta.gid() != gid)
+    }
+
+    fn apply(&self) -> Result<(), Error> {
+	let (path, uid, gid) = match self.target()? {
+	    Some(target) => target,
+	    None => {
+		let errmsg = format!("Unable to manage authorized_keys, user {} does not exist", self.user);
+		return Err(Error::KonfigError(errmsg));
+	    }
+	};
+
+	/* only changed through its descriptor, a symlink in its place is refused */
+	if let Some(ssh_dir) = path.parent() {
+	    if fs::symlink_metadata(ssh_dir).is_err() {
+		fs::create_dir_all(ssh_dir)?;
+		let ssh_dir = open_directory(ssh_dir)?;
+		ssh_dir.set_permissions(fs::Permissions::from_mode(0o700))?;
+		std::os::unix::fs::fchown(&ssh_dir, Some(uid), Some(gid))?;
+	    }
+	}
+	write_atomic(&path, self.content.as_bytes(), 0o600, Some((uid, gid)))
+    }
+}
+
+pub struct GroupProvider;
+
+impl ResourceProvider for GroupProvider {
+
+    fn kind(&self) -> &'static str {
+	GROUP_KIND
+    }
+
+    fn parse<'a>(&'a self, configs: &'a api::konfigset::Configuration, ctx: &'a Context) -> BoxFuture<'a, Vec<Parsed>> {
+	let parsed: Vec<Parsed> = configs.groups.iter().flatten()
+	    .map(|group| -> Parsed {
+		let invalid = api::validation::account_name(&group.name)
+		    .map_err(Error::KonfigError)
+		    .and_then(|_| adoptable(ctx, GROUP_KIND, &group.name));
+		match invalid {
+		    Ok(()) => Ok(Box::new(Group::new(&ctx.root, &ctx.state_dir, group))),
+		    Err(err) => Err(ResourceResult::failed(ResourceId::new(GROUP_KIND, &group.name), err)),
+		}
+	    })
+	    .collect();
+
+	Box::pin(futures::future::ready(parsed))
+    }
+}
+
+/*
+ * Users and their authorized_keys, the keys come after all the users as they
+ * need the home directory of their owner.
+ */
+pub struct UserProvider;
+
+impl ResourceProvider for UserProvider {
+
+    fn kind(&self) -> &'static str {
+	USER_KIND
+    }
+
+    fn parse<'a>(&'a self, configs: &'a api::konfigset::Configuration, ctx: &'a Context) -> BoxFuture<'a, Vec<Parsed>> {
+	Box::pin(async move {
+	    let mut parsed: Vec<Parsed> = vec![];
+	    let users = match &configs.users {
+		Some(users) => users,
+		None => return parsed,
+	    };
+
+	    for user in users {
+		let invalid = api::validation::account_name(&user.name)
+		    .and_then(|_| api::validation::user_fields(user).map_err(|(name, err)| format!("{} {}", name, err)))
+		    .map_err(Error::KonfigError)
+		    .and_then(|_| adoptable(ctx, USER_KIND, &user.name));
+		match invalid {
+		    Ok(()) => parsed.push(Ok(Box::new(User::new(&ctx.root, &ctx.state_dir, user)))),
+		    Err(err) => parsed.push(Err(ResourceResult::failed(ResourceId::new(USER_KIND, &user.name), err))),
+		}
+	    }
+
+	    for user in users {
+		let keys = match &user.authorized_keys {
+		    Some(keys) if user.ensure.as_deref() != Some("absent") => keys,
+		    _ => continue,
+		};
+
+		let keys = match adoptable(ctx, USER_KIND, &user.name) {
+		    Ok(()) => sources::authorized_keys_from(keys, ctx).await,
+		    Err(err) => Err(err),
+		};
+		match keys {
+		    Ok(keys) => parsed.push(Ok(Box::new(AuthorizedKeys::new(&ctx.root, &user.name, &keys)))),
+		    Err(err) => parsed.push(Err(ResourceResult::failed(ResourceId::new(AUTHORIZED_KEYS_KIND, &user.name), err))),
+		}
+	    }
+	    parsed
+	})
+    }
+}
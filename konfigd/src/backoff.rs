@@ -0,0 +1,193 @@
+/*
+ * backoff - the failed resources of each KonfigSet are retried with an
+ * exponential backoff (with jitter, up to a cap) instead of every
+ * reconciliation, which would only repeat the same errors.
+ *
+ * A resource waiting for its retry is left out of the KonfigSet before it
+ * is parsed, so the content sources it reads (e.g: a missing ConfigMap) are
+ * not fetched again either.  Changing its desired state in the KonfigSet,
+ * or the ConfigMaps and Secrets it reads from (only their resourceVersion
+ * is fetched), retries it right away.
+ */
+use crate::provider::Context;
+use crate::provider::Outcome;
+use crate::provider::ResourceId;
+use crate::provider::ResourceResult;
+use konfig_api as api;
+use konfig_api::konfigset::kind;
+
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use chrono::Utc;
+use k8s_openapi::api::core::v1::ConfigMap as KubeConfigMap;
+use k8s_openapi::api::core::v1::Secret as KubeSecret;
+use kube::Api as KubeApi;
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+struct Failure {
+    attempts: u32,
+    reason: String,
+
+    /* the desired state which failed and the versions of its sources, see inputs() */
+    input: String,
+
+    next_retry: DateTime<Utc>,
+}
+
+#[derive(Default)]
+pub struct Backoff {
+    failures: Mutex<BTreeMap<(api::ConfigsetRef, ResourceId), Failure>>,
+}
+
+/*
+ * base * 2^(attempts - 1) up to the cap, of which a random half is taken
+ * off so the nodes failing together don't retry together.
+ */
+pub fn delay(attempts: u32, base: u64, cap: u64) -> Duration {
+    let exponent = attempts.saturating_sub(1).min(32);
+    let delay = base.saturating_mul(1 << exponent).min(cap);
+    let jitter = rand::thread_rng().gen_range(0..=delay / 2);
+
+    Duration::from_secs(delay - jitter)
+}
+
+/* the ConfigMaps and Secrets the resources read their content from */
+fn sources(configs: &api::konfigset::Configuration) -> Vec<(ResourceId, &'static str, Option<String>, String)> {
+    let mut sources = vec![];
+
+    for file in configs.files.iter().flatten() {
+	if let Ok(api::FileSource::ConfigMap{ namespace, name, .. }) = file.content_source() {
+	    sources.push((ResourceId::new(kind::FILE, &file.destination), "configmap", namespace, name));
+	}
+    }
+    for user in configs.users.iter().flatten() {
+	let id = ResourceId::new(kind::AUTHORIZED_KEYS, &user.name);
+	match user.authorized_keys.as_ref().map(|keys| keys.content_source()) {
+	    Some(Ok(Some(api::KeysSource::ConfigMap{ namespace, name, .. }))) => sources.push((id, "configmap", namespace, name)),
+	    Some(Ok(Some(api::KeysSource::Secret{ namespace, name, .. }))) => sources.push((id, "secret", namespace, name)),
+	    _ => {},
+	}
+    }
+    sources
+}
+
+/*
+ * The desired state of each of the resources `ids` (see
+ * api::konfigset::Entry), along with the resourceVersion of the objects
+ * they read from.
+ */
+async fn inputs(konfigset: &api::KonfigSet, ids: &[ResourceId], ctx: &Context) -> BTreeMap<ResourceId, String> {
+    let configs = match &konfigset.spec.configurations {
+	Some(configs) => configs,
+	None => return BTreeMap::new(),
+    };
+    let mut inputs: BTreeMap<ResourceId, String> = configs.entries().into_iter()
+	.filter(|entry| ids.contains(&entry.id))
+	.map(|entry| (entry.id, entry.value.to_string()))
+	.collect();
+
+    for (id, kind, namespace, name) in sources(configs) {
+	let input = match inputs.get_mut(&id) {
+	    Some(input) => input,
+	    None => continue,
+	};
+
+	let namespace = namespace.unwrap_or(ctx.namespace.clone());
+	let metadata = match kind {
+	    "secret" => KubeApi::<KubeSecret>::namespaced(ctx.kube_client.clone(), &namespace).get_metadata_opt(&name).await
+		.map(|object| object.map(|object| object.metadata)),
+	    _ => KubeApi::<KubeConfigMap>::namespaced(ctx.kube_client.clone(), &namespace).get_metadata_opt(&name).await
+		.map(|object| object.map(|object| object.metadata)),
+	};
+	let version = match metadata {
+	    Ok(Some(metadata)) => metadata.resource_version.unwrap_or_default(),
+	    Ok(None) => String::from("missing"),
+	    Err(err) => {
+		log::debug!("Unable to get the version of {} {}/{}: {}", kind, namespace, name, err);
+		String::from("unknown")
+	    }
+	};
+	input.push_str(&format!(" {}/{}/{}@{}", kind, namespace, name, version));
+    }
+    inputs
+}
+
+impl Backoff {
+
+    /*
+     * The resources of the KonfigSet still waiting for their retry, the
+     * ones whose desired state changed since they failed are forgotten.
+     */
+    pub async fn waiting(&self, kref: &api::ConfigsetRef, konfigset: &api::KonfigSet, ctx: &Context) -> Vec<ResourceResult> {
+	let failed: Vec<ResourceId> = self.failures.lock().unwrap().keys()
+	    .filter(|(other, _)| other == kref)
+	    .map(|(_, id)| id.clone())
+	    .collect();
+	if failed.is_empty() {
+	    return vec![];
+	}
+
+	let inputs = inputs(konfigset, &failed, ctx).await;
+	let now = Utc::now();
+	let mut failures = self.failures.lock().unwrap();
+	let mut waiting = vec![];
+
+	failures.retain(|(other, id), failure| {
+	    if other != kref {
+		return true;
+	    }
+	    if inputs.get(id) != Some(&failure.input) {
+		return false;
+	    }
+	    if failure.next_retry > now {
+		let reason = format!("failed {} time(s), retrying at {}: {}", failure.attempts,
+				     failure.next_retry.to_rfc3339_opts(SecondsFormat::Secs, true), failure.reason);
+		waiting.push(ResourceResult{ id: id.clone(), outcome: Outcome::Waiting(reason) });
+	    }
+	    true
+	});
+	waiting
+    }
+
+    /*
+     * Failed resources wait longer on every attempt, the applied ones start
+     * over.
+     */
+    pub async fn record(&self, kref: &api::ConfigsetRef, konfigset: &api::KonfigSet, results: &[ResourceResult], base: u64, cap: u64, ctx: &Context) {
+	let failed: Vec<ResourceId> = results.iter()
+	    .filter(|result| matches!(result.outcome, Outcome::Failed(_)))
+	    .map(|result| result.id.clone())
+	    .collect();
+	let inputs = inputs(konfigset, &failed, ctx).await;
+	let mut failures = self.failures.lock().unwrap();
+
+	for result in results {
+	    let key = (kref.clone(), result.id.clone());
+
+	    match &result.outcome {
+		Outcome::Failed(reason) => {
+		    let attempts = failures.get(&key).map(|failure| failure.attempts).unwrap_or(0) + 1;
+		    let next_retry = Utc::now() + delay(attempts, base, cap);
+		    let input = inputs.get(&result.id).cloned().unwrap_or_default();
+		    failures.insert(key, Failure{ attempts, reason: reason.clone(), input, next_retry });
+		},
+		Outcome::Changed(_) | Outcome::Unchanged => {
+		    failures.remove(&key);
+		},
+		Outcome::Skipped(_) | Outcome::Drifted(_) | Outcome::Waiting(_) => {},
+	    }
+	}
+    }
+
+    /* the KonfigSets no longer assigned to the node start over */
+    pub fn retain(&self, assigned: &[api::ConfigsetRef]) {
+	self.failures.lock().unwrap().retain(|(kref, _), _| assigned.contains(kref));
+    }
+
+    pub fn next_retry(&self) -> Option<DateTime<Utc>> {
+	self.failures.lock().unwrap().values().map(|failure| failure.next_retry).min()
+    }
+}
@@ -0,0 +1,232 @@
+/*
+ * bootstrap - joins the cluster with a short-lived join token instead of a
+ * privileged kubeconfig (see api::konfignoderegistration).
+ *
+ * Everything is kept in the state directory:
+ *   identity    the age identity the node token is sealed for
+ *   token       the node ServiceAccount token, renewed by konfigm
+ *   kubeconfig  reads the token file, so the renewed tokens are picked up
+ */
+use crate::errors::Error;
+use crate::local::write_atomic;
+use konfig_api as api;
+use konfig_api::KonfigNodeRegistration;
+
+use age::secrecy::ExposeSecret;
+use age::x25519::Identity;
+use kube::Api as KubeApi;
+use kube::Client as KubeClient;
+use kube::Config as KubeConfig;
+use kube::Error as KubeError;
+use kube::api::PostParams as KubePostParams;
+use kube::config::AuthInfo;
+use kube::config::Context as KubeContext;
+use kube::config::KubeConfigOptions;
+use kube::config::Kubeconfig;
+use kube::config::NamedAuthInfo;
+use kube::config::NamedContext;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/* keeps the token file up to date with the token renewed by konfigm */
+pub struct Credentials {
+    name: String,
+    registration_api: KubeApi<KonfigNodeRegistration>,
+    identity: Identity,
+    token_path: PathBuf,
+}
+
+async fn load(path: &Path) -> Result<KubeClient, Error> {
+    let kubeconfig = Kubeconfig::read_from(path).map_err(|err| Error::KonfigError(format!("Unable to read {}: {}", path.display(), err)))?;
+    let config = KubeConfig::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default()).await
+	.map_err(|err| Error::KonfigError(format!("Invalid kubeconfig {}: {}", path.display(), err)))?;
+
+    Ok(KubeClient::try_from(config)?)
+}
+
+fn load_or_generate_identity(path: &Path) -> Result<Identity, Error> {
+    if path.exists() {
+	return api::sealed::read_identities(path).map_err(Error::KonfigError)?
+	    .pop()
+	    .ok_or(Error::KonfigError(format!("{} contains no identity", path.display())));
+    }
+
+    let identity = Identity::generate();
+    let content = format!("# public key: {}\n{}\n", identity.to_public(), identity.to_string().expose_secret());
+    write_atomic(path, content.as_bytes(), 0o600, None)?;
+    Ok(identity)
+}
+
+/*
+ * Same cluster as the bootstrap kubeconfig, but authenticated with the node
+ * token.
+ */
+fn node_kubeconfig(bootstrap: &Kubeconfig, name: &str, token_path: &Path) -> Result<Kubeconfig, Error> {
+    let context = bootstrap.current_context.as_ref()
+	.and_then(|current| bootstrap.contexts.iter().find(|context| context.name == *current))
+	.and_then(|context| context.context.clone())
+	.ok_or(Error::KonfigError(String::from("the bootstrap kubeconfig has no current context")))?;
+    let cluster = bootstrap.clusters.iter()
+	.find(|cluster| cluster.name == context.cluster)
+	.cloned()
+	.ok_or(Error::KonfigError(format!("the bootstrap kubeconfig has no cluster {}", context.cluster)))?;
+
+    let user = api::konfignoderegistration::account_name(name);
+    Ok(Kubeconfig{
+	clusters: vec![cluster],
+	auth_infos: vec![NamedAuthInfo{
+	    name: user.clone(),
+	    auth_info: Some(AuthInfo{ token_file: Some(token_path.to_string_lossy().to_string()), ..AuthInfo::default() }),
+	}],
+	contexts: vec![NamedContext{
+	    name: user.clone(),
+	    context: Some(KubeContext{ cluster: context.cluster.clone(), user: Some(user.clone()), ..KubeContext::default() }),
+	}],
+	current_context: Some(user),
+	..Kubeconfig::default()
+    })
+}
+
+async fn register(registration_api: &KubeApi<KonfigNodeRegistration>, name: &str, public_key: &str) -> Result<(), Error> {
+    let registration = KonfigNodeRegistration::new(name, api::konfignoderegistration::KonfigNodeRegistrationSpec{
+	public_key: public_key.to_string(),
+    });
+
+    match registration_api.create(&KubePostParams::default(), &registration).await {
+	Ok(_) => Ok(()),
+	Err(KubeError::Api(resp)) if resp.code == 409 => {
+	    /* we already asked, unless another host registered with the same name */
+	    let existing = registration_api.get(name).await?;
+	    if existing.spec.public_key != public_key {
+		return Err(Error::KonfigError(format!("KonfigNodeRegistration {} already exists with another public key", name)));
+	    }
+	    Ok(())
+	},
+	Err(err) => Err(Error::Kube(err)),
+    }
+}
+
+/*
+ * The client of the nodes which didn't join through a registration: from
+ * the given kubeconfig, or the usual ones (in-cluster, KUBECONFIG,
+ * ~/.kube/config).
+ */
+pub async fn configured_client(kubeconfig: Option<&Path>, context: Option<String>) -> Result<KubeClient, Error> {
+    let options = KubeConfigOptions{ context, ..KubeConfigOptions::default() };
+
+    let config = match kubeconfig {
+	Some(path) => {
+	    let kubeconfig = Kubeconfig::read_from(path).map_err(|err| Error::KonfigError(format!("Unable to read {}: {}", path.display(), err)))?;
+	    KubeConfig::from_custom_kubeconfig(kubeconfig, &options).await
+		.map_err(|err| Error::KonfigError(format!("Invalid kubeconfig {}: {}", path.display(), err)))?
+	},
+	None if options.context.is_some() => KubeConfig::from_kubeconfig(&options).await.map_err(|err| Error::KonfigError(err.to_string()))?,
+	None => KubeConfig::infer().await.map_err(|err| Error::KonfigError(err.to_string()))?,
+    };
+    Ok(KubeClient::try_from(config)?)
+}
+
+/*
+ * Returns a client authenticated as the node: from the state directory when
+ * it already joined, otherwise registers with the bootstrap kubeconfig and
+ * waits for the registration to be approved.
+ */
+pub async fn client(name: &str, state_dir: &Path, bootstrap: Option<&Path>) -> Result<Option<(KubeClient, Credentials)>, Error> {
+    let kubeconfig_path = state_dir.join("kubeconfig");
+    let token_path = state_dir.join("token");
+
+    if !kubeconfig_path.exists() {
+	let bootstrap = match bootstrap {
+	    Some(bootstrap) => bootstrap,
+	    None => return Ok(None),
+	};
+	fs::create_dir_all(state_dir)?;
+	let identity = load_or_generate_identity(&state_dir.join("identity"))?;
+	let bootstrap_kubeconfig = Kubeconfig::read_from(bootstrap)
+	    .map_err(|err| Error::KonfigError(format!("Unable to read {}: {}", bootstrap.display(), err)))?;
+	let registration_api: KubeApi<KonfigNodeRegistration> = KubeApi::all(load(bootstrap).await?);
+
+	let public_key = identity.to_public().to_string();
+	register(&registration_api, name, &public_key).await?;
+	log::info!("Registered as {} with public key {}, waiting for the approval", name, public_key);
+
+	loop {
+	    let registration = registration_api.get(name).await?;
+	    if let Some(sealed) = registration.status.and_then(|status| status.token) {
+		let token = api::sealed::unseal(&sealed, std::slice::from_ref(&identity)).map_err(Error::KonfigError)?;
+		write_atomic(&token_path, &token, 0o600, None)?;
+		break;
+	    }
+	    tokio::time::sleep(Duration::from_secs(10)).await;
+	}
+
+	let kubeconfig = node_kubeconfig(&bootstrap_kubeconfig, name, &token_path)?;
+	let content = serde_yaml::to_string(&kubeconfig).map_err(|err| Error::KonfigError(err.to_string()))?;
+	write_atomic(&kubeconfig_path, content.as_bytes(), 0o600, None)?;
+	log::info!("Registration approved, joined the cluster with {}", kubeconfig_path.display());
+    }
+
+    let identity = load_or_generate_identity(&state_dir.join("identity"))?;
+    let kube_client = load(&kubeconfig_path).await?;
+    let credentials = Credentials{
+	name: name.to_string(),
+	registration_api: KubeApi::all(kube_client.clone()),
+	identity,
+	token_path,
+    };
+    Ok(Some((kube_client, credentials)))
+}
+
+/*
+ * Removes the credentials (and the cache) of a node which left, joining
+ * again requires a new bootstrap.
+ */
+pub fn forget(state_dir: &Path) -> Result<(), Error> {
+    for name in ["kubeconfig", "token", "identity"] {
+	let path = state_dir.join(name);
+	if path.exists() {
+	    fs::remove_file(&path)?;
+	}
+    }
+
+    /* the downloads of the http(s):// sources */
+    let cache = state_dir.join("cache");
+    if cache.exists() {
+	fs::remove_dir_all(&cache)?;
+    }
+    Ok(())
+}
+
+impl Credentials {
+
+    /* its public key is in the registration, so content can be sealed for the node as well */
+    pub fn identity(&self) -> Identity {
+	self.identity.clone()
+    }
+
+    pub async fn run(&self) {
+	loop {
+	    if let Err(err) = self.refresh().await {
+		log::warn!("Unable to refresh my token: {}", err);
+	    }
+	    tokio::time::sleep(Duration::from_secs(60)).await;
+	}
+    }
+
+    async fn refresh(&self) -> Result<(), Error> {
+	let registration = self.registration_api.get(&self.name).await?;
+	let sealed = match registration.status.and_then(|status| status.token) {
+	    Some(sealed) => sealed,
+	    None => return Ok(()),
+	};
+
+	let token = api::sealed::unseal(&sealed, std::slice::from_ref(&self.identity)).map_err(Error::KonfigError)?;
+	if fs::read(&self.token_path).ok().as_ref() != Some(&token) {
+	    write_atomic(&self.token_path, &token, 0o600, None)?;
+	    log::info!("Renewed my token");
+	}
+	Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+/*
+ * config - konfigd settings, from the configuration file
+ * (/etc/konfig/konfigd.toml, or YAML when named *.yaml), the environment
+ * and the command line, each one overriding the previous.
+ *
+ * for example:
+ *
+ *   knodename = "pi"
+ *   interval = 120
+ *   jitter = 30
+ *   labels = ["role=web", "zone=lab"]
+ *   allowed_kinds = ["file", "sysctl"]
+ *   log_format = "json"
+ *
+ * Only the Reloadable settings are applied again on SIGHUP, the others need
+ * a restart.
+ */
+use konfig_api as api;
+
+use clap::Args;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub const DEFAULT_PATH: &str = "/etc/konfig/konfigd.toml";
+
+/*
+ * Both the command line flags and the keys of the configuration file, none
+ * has a default here so we can tell what was actually given.
+ */
+#[derive(Args, Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+
+    /// Defines the knode name to use when register in control plane (default: system hostname)
+    #[arg(short, long, env = "KONFIGD_KNODENAME")]
+    pub knodename: Option<String>,
+
+    /// Root directory of the managed system, every file, sysctl and /etc path is relative to it (default: /)
+    #[arg(long, env = "KONFIGD_ROOT")]
+    pub root: Option<PathBuf>,
+
+    /// Kubeconfig to use when the node didn't join with --bootstrap-kubeconfig (default: in-cluster or ~/.kube/config)
+    #[arg(long, env = "KONFIGD_KUBECONFIG")]
+    pub kubeconfig: Option<PathBuf>,
+
+    /// Context of the kubeconfig to use (default: its current context)
+    #[arg(long, env = "KONFIGD_CONTEXT")]
+    pub context: Option<String>,
+
+    /// Namespace of the Lease renewed as a heartbeat, must match konfigm's (default: default)
+    #[arg(long, env = "KONFIGD_LEASE_NAMESPACE")]
+    pub lease_namespace: Option<String>,
+
+    /// Seconds the heartbeat Lease is valid for, it is renewed three times as often (default: 40)
+    #[arg(long, env = "KONFIGD_LEASE_DURATION")]
+    pub lease_duration: Option<u64>,
+
+    /// ed25519 public key (PEM), when any is given only the KonfigSets signed by one of them are applied
+    #[arg(long = "trusted-key")]
+    pub trusted_keys: Option<Vec<PathBuf>>,
+
+    /// age identity file (AGE-SECRET-KEY-1...) decrypting the sealed content of the KonfigSets
+    #[arg(long, env = "KONFIGD_IDENTITY_FILE")]
+    pub identity_file: Option<PathBuf>,
+
+    /// Kubeconfig with a join token, used once to register the node and get its own credentials
+    #[arg(long, env = "KONFIGD_BOOTSTRAP_KUBECONFIG")]
+    pub bootstrap_kubeconfig: Option<PathBuf>,
+
+    /// Where the node credentials are kept once it joined (default: /var/lib/konfigd)
+    #[arg(long, env = "KONFIGD_STATE_DIR")]
+    pub state_dir: Option<PathBuf>,
+
+    /// Deletes the KonfigNode when stopping, instead of keeping it (and its KonfigSets) for the next start
+    #[arg(long, env = "KONFIGD_DEREGISTER_ON_EXIT", num_args = 0..=1, default_missing_value = "true")]
+    pub deregister_on_exit: Option<bool>,
+
+    /// Serves /healthz and /metrics on this address (e.g: 127.0.0.1:9100)
+    #[arg(long, env = "KONFIGD_HEALTH_ADDR")]
+    pub health_addr: Option<SocketAddr>,
+
+    /// Seconds between two reconciliations (default: 60)
+    #[arg(long, env = "KONFIGD_INTERVAL")]
+    pub interval: Option<u64>,
+
+    /// Up to this many seconds are randomly added to the interval, so nodes don't all hit the API at once (default: 0)
+    #[arg(long, env = "KONFIGD_JITTER")]
+    pub jitter: Option<u64>,
+
+    /// Extra label (key=value) of the KonfigNode, can be repeated
+    #[arg(long = "label")]
+    pub labels: Option<Vec<String>>,
+
+    /// error, warn, info, debug or trace, RUST_LOG takes precedence (default: info)
+    #[arg(long, env = "KONFIGD_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// text or json (default: text)
+    #[arg(long, env = "KONFIGD_LOG_FORMAT")]
+    pub log_format: Option<String>,
+
+    /// Seconds before retrying a failed resource the first time, doubled on every failure (default: 10)
+    #[arg(long, env = "KONFIGD_RETRY_BASE")]
+    pub retry_base: Option<u64>,
+
+    /// Maximum seconds between two retries of a failed resource (default: 600)
+    #[arg(long, env = "KONFIGD_RETRY_CAP")]
+    pub retry_cap: Option<u64>,
+
+    /// Kinds of resources this node accepts to manage, can be repeated (default: all)
+    #[arg(long = "allowed-kind")]
+    pub allowed_kinds: Option<Vec<String>>,
+
+    /// PEM bundle of the CAs trusted by the https:// sources, on top of the system's
+    #[arg(long, env = "KONFIGD_HTTP_CA")]
+    pub http_ca: Option<PathBuf>,
+
+    /// Seconds a download of an http(s):// source may take (default: 30)
+    #[arg(long, env = "KONFIGD_HTTP_TIMEOUT")]
+    pub http_timeout: Option<u64>,
+
+    /// Largest content of an http(s):// source, in bytes (default: 67108864)
+    #[arg(long, env = "KONFIGD_HTTP_MAX_SIZE")]
+    pub http_max_size: Option<u64>,
+}
+
+/* the settings applied again on SIGHUP */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reloadable {
+    pub interval: u64,
+    pub jitter: u64,
+    pub labels: BTreeMap<String, String>,
+    pub log_level: LevelFilter,
+    pub retry_base: u64,
+    pub retry_cap: u64,
+    pub allowed_kinds: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub knodename: Option<String>,
+    pub root: PathBuf,
+    pub kubeconfig: Option<PathBuf>,
+    pub context: Option<String>,
+    pub lease_namespace: String,
+    pub lease_duration: u64,
+    pub trusted_keys: Vec<PathBuf>,
+    pub identity_file: Option<PathBuf>,
+    pub bootstrap_kubeconfig: Option<PathBuf>,
+    pub state_dir: PathBuf,
+    pub deregister_on_exit: bool,
+    pub health_addr: Option<SocketAddr>,
+    pub json_logs: bool,
+    pub http_ca: Option<PathBuf>,
+    pub http_timeout: u64,
+    pub http_max_size: u64,
+    pub reloadable: Reloadable,
+}
+
+impl Options {
+
+    /* the options given here win over the other's */
+    fn or(self, other: Options) -> Options {
+	Options{
+	    knodename: self.knodename.or(other.knodename),
+	    root: self.root.or(other.root),
+	    kubeconfig: self.kubeconfig.or(other.kubeconfig),
+	    context: self.context.or(other.context),
+	    lease_namespace: self.lease_namespace.or(other.lease_namespace),
+	    lease_duration: self.lease_duration.or(other.lease_duration),
+	    trusted_keys: self.trusted_keys.or(other.trusted_keys),
+	    identity_file: self.identity_file.or(other.identity_file),
+	    bootstrap_kubeconfig: self.bootstrap_kubeconfig.or(other.bootstrap_kubeconfig),
+	    state_dir: self.state_dir.or(other.state_dir),
+	    deregister_on_exit: self.deregister_on_exit.or(other.deregister_on_exit),
+	    health_addr: self.health_addr.or(other.health_addr),
+	    interval: self.interval.or(other.interval),
+	    jitter: self.jitter.or(other.jitter),
+	    labels: self.labels.or(other.labels),
+	    log_level: self.log_level.or(other.log_level),
+	    log_format: self.log_format.or(other.log_format),
+	    retry_base: self.retry_base.or(other.retry_base),
+	    retry_cap: self.retry_cap.or(other.retry_cap),
+	    allowed_kinds: self.allowed_kinds.or(other.allowed_kinds),
+	    http_ca: self.http_ca.or(other.http_ca),
+	    http_timeout: self.http_timeout.or(other.http_timeout),
+	    http_max_size: self.http_max_size.or(other.http_max_size),
+	}
+    }
+}
+
+fn parse_labels(labels: &[String]) -> Result<BTreeMap<String, String>, String> {
+    let mut parsed = BTreeMap::new();
+
+    for label in labels {
+	match label.split_once('=') {
+	    Some((key, value)) if !key.is_empty() => {
+		parsed.insert(key.to_string(), value.to_string());
+	    },
+	    _ => return Err(format!("invalid label {:?}, expected key=value", label)),
+	}
+    }
+    Ok(parsed)
+}
+
+fn read(path: &Path) -> Result<Options, String> {
+    let content = fs::read_to_string(path).map_err(|err| format!("Unable to read {}: {}", path.display(), err))?;
+
+    let options = match path.extension().and_then(|ext| ext.to_str()) {
+	Some("yaml") | Some("yml") => serde_yaml::from_str(&content).map_err(|err| err.to_string()),
+	_ => toml::from_str(&content).map_err(|err| err.to_string()),
+    };
+    options.map_err(|err| format!("Invalid configuration {}: {}", path.display(), err))
+}
+
+/*
+ * The configuration file is optional unless it was explicitly given.
+ */
+pub fn load(path: Option<&Path>, flags: &Options) -> Result<Settings, String> {
+    let file = match path {
+	Some(path) => read(path)?,
+	None if Path::new(DEFAULT_PATH).exists() => read(Path::new(DEFAULT_PATH))?,
+	None => Options::default(),
+    };
+    let options = flags.clone().or(file);
+
+    let log_level = match &options.log_level {
+	Some(level) => LevelFilter::from_str(level).map_err(|_| format!("invalid log level {:?}", level))?,
+	None => LevelFilter::Info,
+    };
+    let json_logs = match options.log_format.as_deref() {
+	None | Some("text") => false,
+	Some("json") => true,
+	Some(other) => return Err(format!("invalid log format {:?}, valid values are: text, json", other)),
+    };
+    let known = [
+	api::konfigset::kind::GROUP,
+	api::konfigset::kind::USER,
+	api::konfigset::kind::AUTHORIZED_KEYS,
+	api::konfigset::kind::SYSCTL,
+	api::konfigset::kind::FILE,
+    ];
+    for kind in options.allowed_kinds.iter().flatten() {
+	if !known.contains(&kind.as_str()) {
+	    return Err(format!("unknown kind {:?}, valid values are: {}", kind, known.join(", ")));
+	}
+    }
+
+    /* on load and on reload alike, konfigd would reconcile in a busy loop */
+    if options.interval == Some(0) {
+	return Err(String::from("invalid interval 0, it must be at least 1 second"));
+    }
+
+    Ok(Settings{
+	knodename: options.knodename,
+	root: options.root.unwrap_or(PathBuf::from("/")),
+	kubeconfig: options.kubeconfig,
+	context: options.context,
+	lease_namespace: options.lease_namespace.unwrap_or(String::from("default")),
+	lease_duration: options.lease_duration.unwrap_or(40),
+	trusted_keys: options.trusted_keys.unwrap_or_default(),
+	identity_file: options.identity_file,
+	bootstrap_kubeconfig: options.bootstrap_kubeconfig,
+	state_dir: options.state_dir.unwrap_or(PathBuf::from("/var/lib/konfigd")),
+	deregister_on_exit: options.deregister_on_exit.unwrap_or(false),
+	health_addr: options.health_addr,
+	json_logs,
+	http_ca: options.http_ca,
+	http_timeout: options.http_timeout.unwrap_or(30),
+	http_max_size: options.http_max_size.unwrap_or(64 * 1024 * 1024),
+	reloadable: Reloadable{
+	    interval: options.interval.unwrap_or(60),
+	    jitter: options.jitter.unwrap_or(0),
+	    labels: parse_labels(&options.labels.unwrap_or_default())?,
+	    log_level,
+	    retry_base: options.retry_base.unwrap_or(10).max(1),
+	    retry_cap: options.retry_cap.unwrap_or(600).max(1),
+	    allowed_kinds: options.allowed_kinds,
+	},
+    })
+}
@@ -7,6 +7,9 @@ pub enum Error {
     #[error("kube error: {0}")]
     Kube(#[from] kube::Error),
 
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
     #[error("Konfig Error: {0}")]
     KonfigError(String),
 
@@ -0,0 +1,210 @@
+/*
+ * file - regular files whose content comes from the KonfigSet itself or
+ * from a content source (see sources.rs).  A source may also provide a
+ * whole directory tree, managed as a single resource.
+ *
+ * The content is bytes: a file drifted when its size, then its sha256,
+ * differs, so the current file is never loaded at once.  It is written
+ * atomically (see local.rs).
+ */
+use crate::errors::Error;
+use crate::local::file_sha256;
+use crate::local::rooted;
+use crate::local::sha256;
+use crate::local::write_atomic;
+use crate::provider::Context;
+use crate::provider::Parsed;
+use crate::provider::Resource;
+use crate::provider::ResourceId;
+use crate::provider::ResourceProvider;
+use crate::provider::ResourceResult;
+use crate::sources;
+use crate::sources::Blob;
+use crate::sources::Content;
+use konfig_api as api;
+
+use futures::future::BoxFuture;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+pub const KIND: &str = api::konfigset::kind::FILE;
+
+pub struct File {
+    destination: String,
+    path: PathBuf,
+    content: Vec<u8>,
+
+    /* of content, see local::sha256 */
+    digest: String,
+    mode: u32,
+
+    /* the digests of sealed content are left out of the diffs */
+    sealed: bool,
+}
+
+impl File {
+
+    pub fn new(ctx: &Context, destination: &str, blob: Blob, mode: u32) -> Result<Self, Error> {
+	Ok(Self{
+	    destination: destination.to_string(),
+	    path: rooted(&ctx.root, destination)?,
+	    digest: sha256(&blob.content),
+	    content: blob.content,
+	    mode,
+	    sealed: blob.sealed,
+	})
+    }
+}
+
+impl Resource for File {
+
+    fn id(&self) -> ResourceId {
+	ResourceId::new(KIND, &self.destination)
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+	let meta = match fs::metadata(&self.path) {
+	    Ok(meta) => meta,
+	    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+	    Err(err) => return Err(err.into()),
+	};
+
+	if meta.permissions().mode() & 0o7777 != self.mode || meta.len() != self.content.len() as u64 {
+	    return Ok(true);
+	}
+	Ok(file_sha256(&self.path)? != self.digest)
+    }
+
+    fn apply(&self) -> Result<(), Error> {
+	write_atomic(&self.path, &self.content, self.mode, None)
+    }
+
+    fn describe_diff(&self) -> String {
+	let meta = match fs::metadata(&self.path) {
+	    Ok(meta) => meta,
+	    Err(_) => return format!("create {} ({} bytes, mode {:o})", self.destination, self.content.len(), self.mode),
+	};
+
+	let mut changes = vec![];
+	match file_sha256(&self.path) {
+	    Ok(digest) if digest == self.digest => {},
+	    Ok(_) if self.sealed => changes.push(format!("content {} -> {} bytes", meta.len(), self.content.len())),
+	    Ok(digest) => changes.push(format!("content {} -> {} bytes, sha256 {:.12} -> {:.12}", meta.len(), self.content.len(), digest, self.digest)),
+	    Err(_) => changes.push(format!("content replaced by {} bytes", self.content.len())),
+	}
+	if meta.permissions().mode() & 0o7777 != self.mode {
+	    changes.push(format!("mode {:o} -> {:o}", meta.permissions().mode() & 0o7777, self.mode));
+	}
+	format!("update {} ({})", self.destination, changes.join(", "))
+    }
+}
+
+/*
+ * The files of a directory (e.g: from a git repository) under destination,
+ * the files which are not part of it are left alone.  The executable ones
+ * get the execute bits wherever their mode has the read ones, like a git
+ * checkout does: 0644 becomes 0755 and 0640 becomes 0750.
+ */
+pub struct Tree {
+    destination: String,
+    files: Vec<File>,
+}
+
+impl Tree {
+
+    pub fn new(ctx: &Context, destination: &str, files: Vec<(String, Blob)>, mode: u32) -> Result<Self, Error> {
+	let files = files.into_iter()
+	    .map(|(name, blob)| {
+		let mode = if blob.executable { mode | ((mode & 0o444) >> 2) } else { mode };
+		File::new(ctx, &format!("{}/{}", destination.trim_end_matches('/'), name), blob, mode)
+	    })
+	    .collect::<Result<Vec<File>, Error>>()?;
+
+	Ok(Self{
+	    destination: destination.to_string(),
+	    files,
+	})
+    }
+
+    fn drifted(&self) -> Result<Vec<&File>, Error> {
+	let mut drifted = vec![];
+
+	for file in &self.files {
+	    if file.check()? {
+		drifted.push(file);
+	    }
+	}
+	Ok(drifted)
+    }
+}
+
+impl Resource for Tree {
+
+    fn id(&self) -> ResourceId {
+	ResourceId::new(KIND, &self.destination)
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+	Ok(!self.drifted()?.is_empty())
+    }
+
+    fn apply(&self) -> Result<(), Error> {
+	for file in self.drifted()? {
+	    if let Some(parent) = file.path.parent() {
+		fs::create_dir_all(parent)?;
+	    }
+	    file.apply()?;
+	}
+	Ok(())
+    }
+
+    fn describe_diff(&self) -> String {
+	match self.drifted() {
+	    Ok(drifted) => {
+		let changes: Vec<String> = drifted.iter().map(|file| file.describe_diff()).collect();
+		format!("{} of {} files under {}: {}", changes.len(), self.files.len(), self.destination, changes.join("; "))
+	    },
+	    Err(err) => format!("unable to check the files under {}: {}", self.destination, err),
+	}
+    }
+}
+
+pub struct FileProvider;
+
+impl ResourceProvider for FileProvider {
+
+    fn kind(&self) -> &'static str {
+	KIND
+    }
+
+    fn parse<'a>(&'a self, configs: &'a api::konfigset::Configuration, ctx: &'a Context) -> BoxFuture<'a, Vec<Parsed>> {
+	Box::pin(async move {
+	    let mut parsed: Vec<Parsed> = vec![];
+
+	    for file_opt in configs.files.iter().flatten() {
+		let id = ResourceId::new(KIND, &file_opt.destination);
+		let mode = file_opt.mode.unwrap_or(0o644);
+
+		let invalid = api::validation::destination(&file_opt.destination)
+		    .map_err(|err| format!("destination {}", err))
+		    .and_then(|_| api::validation::file_source(file_opt).map_err(|(field, err)| format!("{} {}", field, err)));
+		if let Err(err) = invalid {
+		    parsed.push(Err(ResourceResult::failed(id, Error::KonfigError(err))));
+		    continue;
+		}
+
+		let resource = match sources::file_content_from(file_opt.clone(), ctx).await {
+		    Ok(Content::File(content)) => File::new(ctx, &file_opt.destination, content, mode).map(|file| Box::new(file) as Box<dyn Resource>),
+		    Ok(Content::Tree(files)) => Tree::new(ctx, &file_opt.destination, files, mode).map(|tree| Box::new(tree) as Box<dyn Resource>),
+		    Err(err) => Err(err),
+		};
+		match resource {
+		    Ok(resource) => parsed.push(Ok(resource)),
+		    Err(err) => parsed.push(Err(ResourceResult::failed(id, err))),
+		}
+	    }
+	    parsed
+	})
+    }
+}
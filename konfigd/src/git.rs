@@ -0,0 +1,276 @@
+/*
+ * git - the content of the git+<repository> file sources, read with the git
+ * command.
+ *
+ * Every repository is cloned once (bare) into <state_dir>/cache/git, named
+ * after the sha256 of its url, then fetched again when a ref has to be
+ * resolved, at most every REFETCH.  A ref pinned to a commit the clone
+ * already has never needs a fetch.
+ *
+ * The commits the refs resolved to are kept for the status of the
+ * KonfigNode (status.revisions).
+ */
+use crate::errors::Error;
+use crate::local::sha256;
+use crate::sources::Blob;
+use crate::sources::Content;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::process::Command;
+
+/* how long a fetched repository is trusted to be up to date */
+const REFETCH: Duration = Duration::from_secs(30);
+
+pub struct Repositories {
+    cache: PathBuf,
+    fetched: Mutex<BTreeMap<String, Instant>>,
+
+    /* by revision() */
+    resolved: Mutex<BTreeMap<String, String>>,
+}
+
+/* how a repository and ref are named in status.revisions */
+pub fn revision(repository: &str, reference: Option<&str>) -> String {
+    format!("{}?ref={}", repository, reference.unwrap_or("HEAD"))
+}
+
+fn is_commit(reference: &str) -> bool {
+    reference.len() == 40 && reference.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+async fn git(dir: Option<&Path>, args: &[&str]) -> Result<Vec<u8>, Error> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+	command.arg("--git-dir").arg(dir);
+    }
+    command.args(args)
+	.env("GIT_TERMINAL_PROMPT", "0")
+	.stdin(Stdio::null())
+	.kill_on_drop(true);
+
+    let output = match command.output().await {
+	Ok(output) => output,
+	Err(err) => return Err(Error::KonfigError(format!("Unable to run git: {}", err))),
+    };
+    if !output.status.success() {
+	/* the first line is the one that matters, it ends up in failedReason */
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	let errmsg = format!("git {} failed: {}", args.first().unwrap_or(&""), stderr.lines().next().unwrap_or_default());
+	return Err(Error::KonfigError(errmsg));
+    }
+    Ok(output.stdout)
+}
+
+impl Repositories {
+
+    pub fn new(cache: PathBuf) -> Self {
+	Self{
+	    cache,
+	    fetched: Mutex::new(BTreeMap::new()),
+	    resolved: Mutex::new(BTreeMap::new()),
+	}
+    }
+
+    /* clones or fetches the repository when needed, returns its directory */
+    async fn update(&self, repository: &str, reference: &str) -> Result<PathBuf, Error> {
+	let dir = self.cache.join(sha256(repository.as_bytes()));
+	let dir_arg = dir.to_string_lossy().to_string();
+
+	if !dir.exists() {
+	    std::fs::create_dir_all(&self.cache)?;
+	    git(None, &["clone", "--bare", "--quiet", "--", repository, &dir_arg]).await?;
+	    self.fetched.lock().unwrap().insert(repository.to_string(), Instant::now());
+	    return Ok(dir);
+	}
+
+	if is_commit(reference) && git(Some(&dir), &["cat-file", "-e", &format!("{}^{{commit}}", reference)]).await.is_ok() {
+	    return Ok(dir);
+	}
+	let fresh = match self.fetched.lock().unwrap().get(repository) {
+	    Some(at) => at.elapsed() < REFETCH,
+	    None => false,
+	};
+	if !fresh {
+	    git(Some(&dir), &["fetch", "--quiet", "--prune", "--tags", "--force", "origin", "+refs/heads/*:refs/heads/*"]).await?;
+	    self.fetched.lock().unwrap().insert(repository.to_string(), Instant::now());
+	}
+	Ok(dir)
+    }
+
+    /*
+     * The content of path (the root of the repository when empty) at the
+     * ref, its HEAD by default.
+     */
+    pub async fn read(&self, repository: &str, path: &str, reference: Option<&str>) -> Result<Content, Error> {
+	let dir = self.update(repository, reference.unwrap_or("HEAD")).await?;
+	let resolve = format!("{}^{{commit}}", reference.unwrap_or("HEAD"));
+	let commit = match git(Some(&dir), &["rev-parse", "--verify", "--quiet", &resolve]).await {
+	    Ok(commit) => String::from_utf8_lossy(&commit).trim().to_string(),
+	    Err(_) => {
+		let errmsg = format!("{} has no branch, tag or commit {}", repository, reference.unwrap_or("HEAD"));
+		return Err(Error::KonfigError(errmsg));
+	    }
+	};
+	self.resolved.lock().unwrap().insert(revision(repository, reference), commit.clone());
+
+	let object = match path {
+	    "" => format!("{}^{{tree}}", commit),
+	    path => format!("{}:{}", commit, path),
+	};
+	let kind = match git(Some(&dir), &["cat-file", "-t", &object]).await {
+	    Ok(kind) => String::from_utf8_lossy(&kind).trim().to_string(),
+	    Err(_) => return Err(Error::KonfigError(format!("{} has no {} at {}", repository, path, commit))),
+	};
+
+	match kind.as_str() {
+	    "blob" => {
+		Ok(Content::File(Blob::new(git(Some(&dir), &["cat-file", "blob", &object]).await?)))
+	    },
+	    "tree" => {
+		let listing = git(Some(&dir), &["ls-tree", "-r", "-z", &object]).await?;
+		let mut files = vec![];
+
+		/* <mode> <type> <object>\t<path>, symbolic links and submodules are left out */
+		for entry in listing.split(|byte| *byte == 0).filter(|entry| !entry.is_empty()) {
+		    let entry = String::from_utf8_lossy(entry);
+		    let (meta, name) = entry.split_once('\t').unwrap_or_default();
+		    match meta.split(' ').collect::<Vec<&str>>()[..] {
+			[mode @ ("100644" | "100755"), "blob", blob] => {
+			    let blob = Blob{ executable: mode == "100755", ..Blob::new(git(Some(&dir), &["cat-file", "blob", blob]).await?) };
+			    files.push((name.to_string(), blob));
+			},
+			_ => log::warn!("{}: skipping {}/{}, only regular files are managed", repository, path, name),
+		    }
+		}
+		Ok(Content::Tree(files))
+	    },
+	    other => Err(Error::KonfigError(format!("{} of {} is a {}, not a file nor a directory", path, repository, other))),
+	}
+    }
+
+    /* the commits the given revisions resolved to, the last time they were read */
+    pub fn revisions(&self, revisions: &[String]) -> BTreeMap<String, String> {
+	let resolved = self.resolved.lock().unwrap();
+
+	revisions.iter()
+	    .filter_map(|revision| resolved.get(revision).map(|commit| (revision.clone(), commit.clone())))
+	    .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn run(dir: &Path, args: &[&str]) -> String {
+	let output = std::process::Command::new("git")
+	    .args(["-c", "user.name=konfig", "-c", "user.email=konfig@example.com", "-c", "init.defaultBranch=main"])
+	    .args(args)
+	    .current_dir(dir)
+	    .output()
+	    .unwrap();
+	assert!(output.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+	String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    /*
+     * A bare repository with two commits on main, the first one tagged v1:
+     *   etc/app/app.conf        "v1\n" then "v2\n"
+     *   etc/app/run.sh          executable
+     *   etc/app/conf.d/extra    in a sub directory
+     *   etc/app/current         a symbolic link
+     * Returns its directory, its url and the commit of v1.
+     */
+    fn repository(name: &str) -> (PathBuf, String, String) {
+	let dir = std::env::temp_dir().join(format!("konfigd-git-{}-{}", name, std::process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	let work = dir.join("work");
+	fs::create_dir_all(work.join("etc/app/conf.d")).unwrap();
+
+	run(&work, &["init", "--quiet"]);
+	fs::write(work.join("etc/app/app.conf"), "v1\n").unwrap();
+	fs::write(work.join("etc/app/run.sh"), "#!/bin/sh\n").unwrap();
+	fs::set_permissions(work.join("etc/app/run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+	fs::write(work.join("etc/app/conf.d/extra"), "extra\n").unwrap();
+	std::os::unix::fs::symlink("app.conf", work.join("etc/app/current")).unwrap();
+	run(&work, &["add", "."]);
+	run(&work, &["commit", "--quiet", "-m", "v1"]);
+	run(&work, &["tag", "v1"]);
+	let v1 = run(&work, &["rev-parse", "HEAD"]);
+
+	fs::write(work.join("etc/app/app.conf"), "v2\n").unwrap();
+	run(&work, &["commit", "--quiet", "-am", "v2"]);
+	run(&dir, &["clone", "--quiet", "--bare", "work", "app.git"]);
+
+	(dir.clone(), format!("file://{}", dir.join("app.git").display()), v1)
+    }
+
+    fn file(content: Content) -> Vec<u8> {
+	match content {
+	    Content::File(blob) => blob.content,
+	    Content::Tree(_) => panic!("a tree, not a file"),
+	}
+    }
+
+    #[tokio::test]
+    async fn refs_tags_and_commits() {
+	let (dir, url, v1) = repository("refs");
+	let repositories = Repositories::new(dir.join("cache"));
+
+	assert_eq!(file(repositories.read(&url, "etc/app/app.conf", None).await.unwrap()), b"v2\n");
+	assert_eq!(file(repositories.read(&url, "etc/app/app.conf", Some("main")).await.unwrap()), b"v2\n");
+	assert_eq!(file(repositories.read(&url, "etc/app/app.conf", Some("v1")).await.unwrap()), b"v1\n");
+	assert_eq!(file(repositories.read(&url, "etc/app/app.conf", Some(&v1)).await.unwrap()), b"v1\n");
+
+	/* a single clone serves them all */
+	assert_eq!(fs::read_dir(dir.join("cache")).unwrap().count(), 1);
+
+	let revisions = repositories.revisions(&[revision(&url, Some("v1")), revision(&url, Some(&v1)), revision(&url, Some("other"))]);
+	assert_eq!(revisions, BTreeMap::from([
+	    (revision(&url, Some("v1")), v1.clone()),
+	    (revision(&url, Some(&v1)), v1.clone()),
+	]));
+
+	let err = repositories.read(&url, "etc/app/app.conf", Some("v9")).await.err().unwrap().to_string();
+	assert!(err.contains("has no branch, tag or commit v9"), "{}", err);
+	let err = repositories.read(&url, "etc/missing", None).await.err().unwrap().to_string();
+	assert!(err.contains("has no etc/missing"), "{}", err);
+
+	fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn trees_hold_only_regular_files() {
+	let (dir, url, _) = repository("tree");
+	let repositories = Repositories::new(dir.join("cache"));
+
+	let files = match repositories.read(&url, "etc/app", Some("v1")).await.unwrap() {
+	    Content::Tree(files) => files,
+	    Content::File(_) => panic!("a file, not a tree"),
+	};
+	let listed: Vec<(&str, &[u8], bool)> = files.iter()
+	    .map(|(name, blob)| (name.as_str(), blob.content.as_slice(), blob.executable))
+	    .collect();
+	assert_eq!(listed, vec![
+	    ("app.conf", b"v1\n".as_slice(), false),
+	    ("conf.d/extra", b"extra\n".as_slice(), false),
+	    ("run.sh", b"#!/bin/sh\n".as_slice(), true),
+	]);
+
+	/* the root of the repository */
+	match repositories.read(&url, "", None).await.unwrap() {
+	    Content::Tree(files) => assert_eq!(files.len(), 3),
+	    Content::File(_) => panic!("a file, not a tree"),
+	}
+
+	fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ * health - /healthz and /metrics (Prometheus text format), for the process
+ * supervisor and the monitoring of the node.
+ */
+use axum::Router;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+/* updated by every reconciliation of the KonfigNode */
+pub struct Metrics {
+    started: u64,
+    reconciles: AtomicU64,
+    failures: AtomicU64,
+    last_reconcile: AtomicU64,
+    synced: AtomicBool,
+
+    /* the longest expected time between two reconciliations */
+    period: AtomicU64,
+}
+
+impl Metrics {
+
+    pub fn new(period: u64) -> Self {
+	Self{
+	    started: now(),
+	    reconciles: AtomicU64::new(0),
+	    failures: AtomicU64::new(0),
+	    last_reconcile: AtomicU64::new(0),
+	    synced: AtomicBool::new(false),
+	    period: AtomicU64::new(period),
+	}
+    }
+
+    pub fn reconciled(&self, synced: bool) {
+	self.reconciles.fetch_add(1, Ordering::Relaxed);
+	if !synced {
+	    self.failures.fetch_add(1, Ordering::Relaxed);
+	}
+	self.last_reconcile.store(now(), Ordering::Relaxed);
+	self.synced.store(synced, Ordering::Relaxed);
+    }
+
+    pub fn set_period(&self, period: u64) {
+	self.period.store(period, Ordering::Relaxed);
+    }
+
+    /* healthy as long as the reconciliations keep going, failed or not */
+    fn is_healthy(&self) -> bool {
+	let last = self.last_reconcile.load(Ordering::Relaxed).max(self.started);
+	now().saturating_sub(last) <= 3 * self.period.load(Ordering::Relaxed)
+    }
+
+    fn render(&self) -> String {
+	let mut text = String::new();
+	let metrics = [
+	    ("konfigd_reconciles_total", "counter", "Reconciliations of the KonfigNode", self.reconciles.load(Ordering::Relaxed)),
+	    ("konfigd_reconcile_failures_total", "counter", "Reconciliations which didn't apply every KonfigSet", self.failures.load(Ordering::Relaxed)),
+	    ("konfigd_last_reconcile_timestamp_seconds", "gauge", "When the last reconciliation ended", self.last_reconcile.load(Ordering::Relaxed)),
+	    ("konfigd_synced", "gauge", "Whether every KonfigSet was applied by the last reconciliation", self.synced.load(Ordering::Relaxed) as u64),
+	];
+
+	for (name, kind, help, value) in metrics {
+	    let _ = writeln!(text, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+	}
+	text
+    }
+}
+
+async fn healthz(State(metrics): State<Arc<Metrics>>) -> (StatusCode, &'static str) {
+    match metrics.is_healthy() {
+	true => (StatusCode::OK, "ok\n"),
+	false => (StatusCode::SERVICE_UNAVAILABLE, "the reconciliations stopped\n"),
+    }
+}
+
+async fn metrics(State(metrics): State<Arc<Metrics>>) -> String {
+    metrics.render()
+}
+
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), std::io::Error> {
+    let app = Router::new()
+	.route("/healthz", get(healthz))
+	.route("/metrics", get(self::metrics))
+	.with_state(metrics);
+
+    log::info!("Serving /healthz and /metrics on {}", addr);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await
+}
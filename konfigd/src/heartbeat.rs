@@ -0,0 +1,92 @@
+/*
+ * heartbeat - renews a coordination.k8s.io Lease for this node, so konfigm
+ * can tell when konfigd (or the whole host) is gone.
+ */
+use konfig_api as api;
+
+use chrono::Utc;
+use k8s_openapi::api::coordination::v1::Lease;
+use k8s_openapi::api::coordination::v1::LeaseSpec;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use kube::Api as KubeApi;
+use kube::Client as KubeClient;
+use kube::Error as KubeError;
+use kube::api::ObjectMeta;
+use kube::api::Patch as KubePatch;
+use kube::api::PatchParams as KubePatchParams;
+use kube::api::PostParams as KubePostParams;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub struct Heartbeat {
+    name: String,
+    lease_api: KubeApi<Lease>,
+
+    /* how long the Lease is valid without being renewed */
+    duration: u64,
+}
+
+impl Heartbeat {
+
+    pub fn new(kube_client: KubeClient, name: &str, namespace: &str, duration: u64) -> Self {
+	Self{
+	    name: name.to_string(),
+	    lease_api: KubeApi::namespaced(kube_client, namespace),
+	    duration,
+	}
+    }
+
+    /*
+     * Renews the Lease a few times per duration, so a single missed renewal
+     * doesn't make the node look gone.
+     */
+    pub async fn run(&self) {
+	let interval = Duration::from_secs((self.duration / 3).max(1));
+
+	loop {
+	    if let Err(err) = self.renew().await {
+		log::warn!("Unable to renew my Lease: {:?}", err);
+	    }
+	    tokio::time::sleep(interval).await;
+	}
+    }
+
+    async fn renew(&self) -> Result<(), KubeError> {
+	let lease_name = api::konfignode::lease_name(&self.name);
+	let now = MicroTime(Utc::now());
+
+	match self.lease_api.get_opt(&lease_name).await? {
+	    Some(_) => {
+		let patch = serde_json::json!({
+		    "spec": {
+			"holderIdentity": self.name,
+			"leaseDurationSeconds": self.duration,
+			"renewTime": now,
+		    }
+		});
+		self.lease_api.patch(&lease_name, &KubePatchParams::default(), &KubePatch::Merge(&patch)).await?;
+	    },
+	    None => {
+		let mut labels = BTreeMap::new();
+		labels.insert(String::from("konfignodes.runfc.br/name"), self.name.clone());
+
+		let lease = Lease{
+		    metadata: ObjectMeta{
+			name: Some(lease_name),
+			labels: Some(labels),
+			..ObjectMeta::default()
+		    },
+		    spec: Some(LeaseSpec{
+			holder_identity: Some(self.name.clone()),
+			lease_duration_seconds: Some(self.duration as i32),
+			acquire_time: Some(now.clone()),
+			renew_time: Some(now),
+			..LeaseSpec::default()
+		    }),
+		};
+		self.lease_api.create(&KubePostParams::default(), &lease).await?;
+	    },
+	}
+	Ok(())
+    }
+}
@@ -1,15 +1,28 @@
 
-use configc::Manager;
-use configc;
+use crate::backoff;
+use crate::backoff::Backoff;
+use crate::bootstrap;
+use crate::config;
 use crate::errors::Error;
+use crate::git;
+use crate::git::Repositories;
+use crate::health::Metrics;
+use crate::http::Fetcher;
+use crate::provider;
+use crate::status;
+use crate::status::StatusWriter;
 use konfig_api as api;
 
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use chrono::Utc;
+use ed25519_dalek::VerifyingKey;
 use futures::StreamExt;
-use k8s_openapi::api::core::v1::ConfigMap as KubeConfigMap;
 use kube::Api as KubeApi;
 use kube::Client as KubeClient;
 use kube::Error as KubeError;
 use kube::api::DeleteParams as KubeDeleteParams;
+use kube::api::ListParams as KubeListParams;
 use kube::api::Patch as KubePatch;
 use kube::api::PatchParams as KubePatchParams;
 use kube::api::PostParams as KubePostParams;
@@ -18,11 +31,16 @@ use kube::runtime::controller::Action as KubeAction;
 use kube::runtime::controller::Controller as KubeController;
 use kube::runtime::reflector as kube_reflector;
 use kube::runtime::watcher as kube_watcher;
+use rand::Rng;
 use log;
-use std::boxed::Box;
 use std::collections::BTreeMap;
 use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use tokio::sync::Notify;
 use std::time::Duration;
 
 /*
@@ -33,7 +51,43 @@ use std::time::Duration;
 #[derive(Clone)]
 pub struct KNodeMgr {
     name: String,
-    reconcilation_interval: u64,
+
+    /* the settings applied again on SIGHUP */
+    reloadable: Arc<RwLock<config::Reloadable>>,
+
+    metrics: Arc<Metrics>,
+
+    /* where the local system lives, every managed path is relative to it */
+    root: PathBuf,
+
+    /* the kinds of resources konfigd knows how to manage */
+    registry: Arc<provider::Registry>,
+
+    /* when defined, only the KonfigSets signed by one of these are applied */
+    trusted_keys: Arc<Vec<VerifyingKey>>,
+
+    /* decrypts the sealed content of the KonfigSets */
+    identities: Arc<Vec<age::x25519::Identity>>,
+
+    /* downloads (and caches) the http(s):// sources */
+    http: Fetcher,
+
+    /* the clones of the git+ sources, and the commits they resolved to */
+    git: Arc<Repositories>,
+
+    /* the local state forgotten when leaving (see bootstrap.rs) */
+    state_dir: PathBuf,
+
+    /* notified once our KonfigNode was deleted and we cleaned up */
+    deleted: Arc<Notify>,
+
+    /* the failed resources, retried later and later */
+    backoff: Arc<Backoff>,
+
+    /* the reconciliations which failed in a row, e.g: the API is unreachable */
+    failed_cycles: Arc<AtomicU32>,
+
+    status: StatusWriter,
 
     kube_client: KubeClient,
     knode_api: KubeApi<api::KonfigNode>,
@@ -44,247 +98,396 @@ struct KnodeManagerCtx {
     knode_mgr: KNodeMgr,
 }
 
+fn tern<T>(expr: bool, when_true: T, when_false: T) -> T {
+    if expr {
+	when_true
+    } else {
+	when_false
+    }
+}
+
 /*
- * Read content from file's .content key if it has defined, otherwise returns an empty
- * string.
- *
- * for example:
- *
- *   kind: KonfigSet
- *   metadata: [ ... ]
- *   spec:
- *     configuration:
- *      files:
- *       - source: static://
- *         [ ... ]
- *         content: |
- *           This is the file content that we expecting.
- *
+ * Orders the KonfigSets assigned to a node by their requires/before
+ * references, the ones referenced by these but not assigned to this node
+ * are left out of the graph.
  */
-fn read_static_content(file: api::KonfigFile) -> String {
-    let content = match file.content {
-	Some(content) => content,
-	None => String::from(""),
-    };
+fn konfigsets_graph(konfigsets: &[(api::ConfigsetRef, api::KonfigSet)]) -> api::Graph<api::ConfigsetRef> {
+    let mut graph = api::Graph::new();
 
-    content
-}
+    for (kref, _) in konfigsets {
+	graph.add_node(kref.clone());
+    }
 
+    for (kref, konfigset) in konfigsets {
+	let namespace = kref.namespace.clone().unwrap_or_default();
 
-/*
- * Read content from file's .content key if it has defined, otherwise returns an empty
- * string.
- *
- * for example:
- *
- *   kind: KonfigSet
- *   metadata: [ ... ]
- *   spec:
- *     configuration:
- *      files:
- *       - source: k8s://configmap
- *         key: content  # the configmap's data key where the content should be read from
- *         [ ... ]
- *
- */
-async fn read_content_configmap(file: &api::KonfigFile, ctx: Arc<KnodeManagerCtx>, konfigset_namespace: &str) -> Result<String, Error> {
-    let namespace = match &file.namespace {
-	Some(ns) => ns.to_string(),
-	None => konfigset_namespace.to_string(),
-    };
-    let configmaps: KubeApi<KubeConfigMap> = KubeApi::namespaced(ctx.knode_mgr.kube_client.clone(), &namespace);
-
-    let name = file.source.replace("k8s://configmap/", "");
-    let key = match file.key.clone() {
-	Some(key) => key,
-	None => {
-	    let errmsg = format!("For k8s://configmap object the `.key` field is required, got: {:?}", file);
-	    return Err(Error::KonfigError(errmsg));
+	for requirement in konfigset.spec.requires.iter().flatten() {
+	    let requirement = requirement.in_namespace(&namespace);
+	    if graph.contains(&requirement) {
+		graph.add_requirement(kref, &requirement);
+	    }
 	}
-    };
+	for dependent in konfigset.spec.before.iter().flatten() {
+	    let dependent = dependent.in_namespace(&namespace);
+	    if graph.contains(&dependent) {
+		graph.add_requirement(&dependent, kref);
+	    }
+	}
+    }
+    graph
+}
 
-    let content = match configmaps.get(&name).await {
-	Err(_) => {
-	    let errmsg = format!("Unable to find Configmap with name: {}/{}", namespace, name);
-	    return Err(Error::KonfigError(errmsg));
-	},
-	Ok(configmap) => {
-	    let data = match configmap.data {
-		Some(data) => data,
-		None => {
-		    let errmsg = format!("Expected .data inside configmap {}/{}, but couldn't find one?", namespace, name);
-		    return Err(Error::KonfigError(errmsg));
-		}
-	    };
+/*
+ * Whether the drift of a KonfigSet may be applied now: both its own and the
+ * node maintenance windows must be open, unless either one is overridden.
+ */
+fn in_maintenance_window(knode: &api::KonfigNode, konfigset: &api::KonfigSet) -> Result<bool, String> {
+    if api::schedule::is_overridden(&knode.metadata) || api::schedule::is_overridden(&konfigset.metadata) {
+	return Ok(true);
+    }
 
-	    let content = match data.get(&key) {
-		Some(content) => content,
-		None => {
-		    let errmsg = format!("The configmap '{}/{}' does not contain '{}' inside its data", namespace, name, key);
-		    return Err(Error::KonfigError(errmsg));
-		}
-	    };
-	    content.to_string()
-	},
-    };
+    let now = Utc::now();
+    let node_windows = api::schedule::node_windows(&knode.metadata)?;
+    let windows = konfigset.spec.schedule.clone().unwrap_or_default();
+    Ok(api::schedule::is_open(&node_windows, now)? && api::schedule::is_open(&windows, now)?)
+}
 
-    Ok(content)
+/* what bootstrapped nodes get when updating anything but the status of their KonfigNode */
+fn forbidden(err: &KubeError) -> bool {
+    matches!(err, KubeError::Api(response) if response.code == 403)
 }
 
-async fn file_content_from(file: api::KonfigFile, ctx: Arc<KnodeManagerCtx>, konfigset_namespace: &str) -> Result<String, Error> {
-    let content = match file.source.as_str() {
-	src if src.starts_with("static://") => read_static_content(file),
-	src if src.starts_with("k8s://configmap") => read_content_configmap(&file, ctx.clone(), konfigset_namespace).await?,
+async fn knode_reconcile(knode: Arc<api::KonfigNode>, ctx: Arc<KnodeManagerCtx>) -> Result<KubeAction, Error> {
+    let me = knode.metadata.name.clone().unwrap();
 
-	/*
-	 * When reaching here, it means none of the k8s:// above
-	 * matches.  Therefore, it must a mailformed/unsupported k8s
-	 * content object
-	 */
-	src if src.starts_with("k8s://") => {
-	    let errmsg = format!("KonfigFile {:?} is mallformed or unsupported: valid values are: k8s://configmap", file);
-	    return Err(Error::KonfigError(errmsg));
-	}
+    if ctx.knode_mgr.name != me {
+	return Ok(ctx.knode_mgr.requeue());
+    }
 
-	// else
-	_ => {
-	    let errmsg = format!("file source {} is not supported", file.source);
-	    return Err(Error::KonfigError(errmsg));
-	}
-    };
+    if knode.metadata.deletion_timestamp.is_some() {
+	log::warn!("My KonfigNode is being deleted, leaving");
+	ctx.knode_mgr.finalize().await?;
+	ctx.knode_mgr.deleted.notify_one();
+	return Ok(KubeAction::await_change());
+    }
 
-    Ok(content)
-}
+    let mut konfigsets: Vec<(api::ConfigsetRef, api::KonfigSet)> = vec![];
+    for config in knode.konfigsets() {
+	let (kfg_name, kfg_namespace) = config.names();
 
-async fn drifted_configs(konfigset: &api::KonfigSet, ctx: Arc<KnodeManagerCtx>) -> Vec<Box<dyn configc::Manager + Send>> {
-    let mut drifted: Vec<Box<dyn configc::Manager + Send>> = Vec::new();
-    let name = konfigset.metadata.name.clone().expect("Unable to read konfigset name");
-    let namespace = konfigset.metadata.namespace.clone().expect("Unable to read konfigset namespace");
-    let me = ctx.knode_mgr.name.as_str();
-
-    let configs = match &konfigset.spec.configurations {
-	Some(cfg) => cfg,
-	None => {
-	    /* no configuration, we can simply return */
-	    return vec![];
+	let konfigset_api: KubeApi<api::KonfigSet> = KubeApi::namespaced(ctx.knode_mgr.kube_client.clone(), &kfg_namespace);
+	match konfigset_api.get_opt(&kfg_name).await? {
+	    Some(konfigset) => konfigsets.push((config, konfigset)),
+	    None => log::warn!("KonfigSet {}/{} assigned to me does not exist", kfg_namespace, kfg_name),
 	}
-    };
-
-    // handle sysctls
-    log::debug!("handling sysctls for config: {}", name);
-    if let Some(sysctls) = &configs.sysctls {
-	for sysctl_opt in sysctls {
-	    let sysctl = configc::Sysctl::new(sysctl_opt.name.as_str(), sysctl_opt.value.as_str());
-	    log::debug!("Managing sysctl: {:?}", sysctl);
+    }
+    ctx.knode_mgr.backoff.retain(&knode.konfigsets());
 
-	    match sysctl.is_different() {
-		Err(err) => {
-		    log::error!("Unable to check state of sysctl, error: {}", err);
-		},
-		Ok(is_different) => {
-		    if is_different {
-			drifted.push(Box::new(sysctl));
-		    }
-		}
+    /*
+     * Unsigned or tampered KonfigSets are left out before anything else, so
+     * they can't even take part in the conflict resolution.
+     */
+    let mut unsigned: Vec<String> = vec![];
+    if !ctx.knode_mgr.trusted_keys.is_empty() {
+	konfigsets.retain(|(kref, konfigset)| match api::signature::verify(konfigset, &ctx.knode_mgr.trusted_keys) {
+	    Ok(()) => true,
+	    Err(err) => {
+		log::error!("{}: refused, {}", kref, err);
+		unsigned.push(format!("{}: {}", kref, err));
+		false
 	    }
-	}
+	});
     }
+    /* the conditions are only written along with the state, once everything is done */
+    let mut conditions: Vec<api::Condition> = vec![];
+    conditions.push(if unsigned.is_empty() {
+	api::Condition::new(api::signature::SIGNATURE_INVALID, false, "Verified", "every KonfigSet is signed by a trusted key or no key is trusted")
+    } else {
+	api::Condition::new(api::signature::SIGNATURE_INVALID, true, "Refused", &unsigned.join("; "))
+    });
 
-    // handle files
-    log::debug!("handling files for config: {}", name);
-    if let Some(files) = &configs.files {
-	for file_opt in files {
-	    let dest = file_opt.destination.as_str();
-	    let mode = match file_opt.mode {
-		Some(mode) => mode,
-		None => 0644,
-	    };
-	    let content = match file_content_from(file_opt.clone(), ctx.clone(), &namespace).await {
-		Ok(content) => content,
-		Err(err) => {
-		    log::error!("Unable to get file content from {:?}, error: {}", file_opt, err);
-
-		    if let Err(err) = ctx.knode_mgr.patch_status_state(me, api::KonfigNodeState::FAILED, Some(false)).await {
-			log::error!("Unable to update node state, {:?}", err);
-		    };
-		    continue;
-		}
-	    };
-	    let file = configc::File::new(dest, content.as_str(), mode, 0);
+    /*
+     * Resources managed by several KonfigSets go to the one with the highest
+     * priority, the KonfigSets that cannot be told apart are not applied.
+     */
+    let assigned: Vec<(api::ConfigsetRef, &api::KonfigSet)> = konfigsets.iter()
+	.map(|(kref, konfigset)| (kref.clone(), konfigset))
+	.collect();
+    let resolution = api::conflict::resolve(&assigned);
+    let conflicts: Vec<String> = resolution.conflicts.iter().map(|conflict| conflict.to_string()).collect();
+    for conflict in &conflicts {
+	log::error!("Conflict: {}", conflict);
+    }
+    conditions.push(if conflicts.is_empty() {
+	api::Condition::new(api::condition::CONFLICT, false, "NoConflict", "no resource is managed by more than one KonfigSet")
+    } else {
+	api::Condition::new(api::condition::CONFLICT, true, "SamePriority", &conflicts.join("; "))
+    });
+    let refused = resolution.refused();
+
+    /* enforced here as well, in case konfigm assigned it before a policy changed */
+    let policy_api: KubeApi<api::KonfigPolicy> = KubeApi::all(ctx.knode_mgr.kube_client.clone());
+    let policies = policy_api.list(&KubeListParams::default()).await?.items;
+    let labels = knode.metadata.labels.clone().unwrap_or_default();
+
+    let graph = konfigsets_graph(&konfigsets);
+    let order = match graph.order() {
+	Ok(order) => order,
+	Err(cycle) => {
+	    log::error!("Unable to order the KonfigSets assigned to me: {}", cycle);
+	    ctx.knode_mgr.status.write(|status| {
+		status::set_state(status, api::KonfigNodeState::Syncing)?;
+		status::set_state(status, api::KonfigNodeState::Failed)?;
+		status.synced = Some(false);
+		status.failed_reason = Some(format!("unable to order the KonfigSets: {}", cycle));
+		status.last_attempt = Some(api::condition::now());
+		status::set_conditions(status, conditions);
+		Ok(())
+	    }).await?;
+	    ctx.knode_mgr.metrics.reconciled(false);
+	    return Ok(ctx.knode_mgr.requeue());
+	}
+    };
 
-	    log::debug!("Managing file: {:?}", file_opt);
+    /* what went wrong, for the failedReason of the status */
+    let mut failures: Vec<String> = unsigned.clone();
+    let mut changed = false;
+    let mut pending: Vec<String> = vec![];
+    let mut paused: Vec<String> = vec![];
+    let mut broken: Vec<api::ConfigsetRef> = vec![];
+    for kref in order {
+	let konfigset = match konfigsets.iter().find(|(other, _)| *other == kref) {
+	    Some((_, konfigset)) => konfigset,
+	    None => continue,
+	};
+	let (kfg_name, kfg_namespace) = kref.names();
+	log::debug!("Reconciling for {:?}", konfigset);
 
-	    match file.is_different() {
-		Err(err) => {
-		    log::error!("Unable to check the state of file, error: {}", err);
-		},
-		Ok(is_different) => {
-		    if is_different {
-			drifted.push(Box::new(file));
-		    }
-		}
-	    }
+	/*
+	 * A KonfigSet is skipped when any of the ones it requires could not
+	 * be applied or is not assigned to this node at all.
+	 */
+	let missing: Vec<String> = konfigset.spec.requires.iter().flatten()
+	    .map(|requirement| requirement.in_namespace(&kfg_namespace))
+	    .filter(|requirement| broken.contains(requirement) || !graph.contains(requirement))
+	    .map(|requirement| requirement.to_string())
+	    .collect();
+	if !missing.is_empty() {
+	    failures.push(format!("{}: requires {} which could not be applied", kref, missing.join(", ")));
+	    broken.push(kref.clone());
+
+	    log::error!("{}/{}: skipped, requires {} which could not be applied", kfg_namespace, kfg_name, missing.join(", "));
+	    continue;
 	}
-    }
 
-    drifted
-}
+	if refused.contains(&kref) {
+	    failures.push(format!("{}: conflicts with another KonfigSet", kref));
+	    broken.push(kref.clone());
 
-fn tern<T>(expr: bool, when_true: T, when_false: T) -> T {
-    if expr {
-	when_true
-    } else {
-	when_false
-    }
-}
-
-async fn knode_reconcile(knode: Arc<api::KonfigNode>, ctx: Arc<KnodeManagerCtx>) -> Result<KubeAction, KubeError> {
-    let me = knode.metadata.name.clone().unwrap();
+	    log::error!("{}/{}: refused, it conflicts with another KonfigSet", kfg_namespace, kfg_name);
+	    continue;
+	}
 
-    if ctx.knode_mgr.name != me {
-	return Ok(ctx.knode_mgr.requeue());
-    }
+	let applicable = api::konfigpolicy::applicable(&policies, &kfg_namespace);
+	let mut violations = match &konfigset.spec.configurations {
+	    Some(configs) => api::konfigpolicy::violations(&applicable, &kfg_namespace, configs),
+	    None => vec![],
+	};
+	match api::konfigpolicy::allows_node(&applicable, &labels) {
+	    Ok(true) => {},
+	    Ok(false) => violations.push(format!("this node is not allowed by any KonfigPolicy of namespace {}", kfg_namespace)),
+	    Err(err) => violations.push(format!("invalid KonfigPolicy node selector: {}", err)),
+	}
+	if !violations.is_empty() {
+	    failures.push(format!("{}: refused by policy: {}", kref, violations.join("; ")));
+	    broken.push(kref.clone());
 
-    if let Some(configs) = &knode.spec.configsets {
-	for config in configs {
-	    let mut errors = 0;
-	    let kfg_name = config.name.clone().unwrap();
-	    let kfg_namespace = config.namespace.clone().unwrap();
+	    log::error!("{}/{}: refused by policy: {}", kfg_namespace, kfg_name, violations.join("; "));
+	    continue;
+	}
 
-	    let konfigsets = KubeApi::namespaced(ctx.knode_mgr.kube_client.clone(), &kfg_namespace);
-	    if let Some(konfigset) = konfigsets.get_opt(&kfg_name).await? {
-		log::debug!("Reconciling for {:?}", konfigset);
+	let in_window = match in_maintenance_window(&knode, konfigset) {
+	    Ok(in_window) => in_window,
+	    Err(err) => {
+		failures.push(format!("{}: {}", kref, err));
+		broken.push(kref.clone());
 
-		let drifted = drifted_configs(&konfigset, ctx.clone()).await;
-		if drifted.len() > 0 {
-		    log::debug!("Alright, we have some work to do");
-		    ctx.knode_mgr.patch_status_state(&me, api::KonfigNodeState::SYNCING, Some(false)).await?;
+		log::error!("{}/{}: refused, {}", kfg_namespace, kfg_name, err);
+		continue;
+	    }
+	};
 
-		    for configc_mgr in drifted_configs(&konfigset, ctx.clone()).await {
-			if let Err(err) = configc_mgr.ensure() {
-			    errors += 1;
+	/* pausing wins over the maintenance windows, and their override */
+	let is_paused = knode.is_paused() || konfigset.is_paused();
+	if konfigset.is_paused() {
+	    paused.push(kref.to_string());
+	}
+	let held = match (is_paused, in_window) {
+	    (true, _) => Some("paused"),
+	    (false, false) => Some("waiting for a maintenance window"),
+	    (false, true) => None,
+	};
 
-			    log::error!("Failed to apply configuration: {}", err);
-			}
+	/* the resources waiting for their retry aren't even parsed */
+	let mut pctx = ctx.knode_mgr.provider_ctx(&kfg_namespace);
+	pctx.adopt_accounts = api::konfigpolicy::allows_existing_accounts(&applicable);
+	let waiting = ctx.knode_mgr.backoff.waiting(&kref, konfigset, &pctx).await;
+	let waiting_ids: Vec<provider::ResourceId> = waiting.iter().map(|result| result.id.clone()).collect();
+	let mut retried = konfigset.clone();
+	retried.spec.configurations = konfigset.spec.configurations.as_ref().map(|configs| configs.without(&waiting_ids));
+
+	let mut parsed = ctx.knode_mgr.registry.parse(&retried, &pctx).await;
+	parsed.extend(waiting.into_iter().map(Err));
+
+	/* the kinds this node doesn't accept fail, without touching anything */
+	if let Some(allowed_kinds) = ctx.knode_mgr.settings().allowed_kinds {
+	    parsed = parsed.into_iter().map(|resource| {
+		let id = provider::parsed_id(&resource);
+		match allowed_kinds.contains(&id.kind) {
+		    true => resource,
+		    false => {
+			let reason = format!("kind {} is not allowed on this node", id.kind);
+			Err(provider::ResourceResult{ id, outcome: provider::Outcome::Failed(reason) })
 		    }
 		}
+	    }).collect();
+	}
+	parsed.retain(|resource| {
+	    let id = provider::parsed_id(resource);
+	    match resolution.overridden_by(&kref, &id) {
+		Some(winner) => {
+		    log::info!("{}/{}: {} overridden by {}", kfg_namespace, kfg_name, id, winner);
+		    false
+		},
+		None => true,
+	    }
+	});
+
+	let results = match provider::Plan::new(konfigset, parsed) {
+	    Err(rejected) => rejected,
+	    Ok(plan) if held.is_some() => plan.report(),
+	    Ok(plan) => {
+		if plan.is_drifted() {
+		    log::debug!("Alright, we have some work to do");
+		}
+		plan.apply()
+	    },
+	};
 
-		let state = tern(errors == 0, api::KonfigNodeState::READY, api::KonfigNodeState::FAILED);
-		let ready = tern(errors == 0, Some(true), None);
-		ctx.knode_mgr.patch_status_state(&me, state, ready).await?;
+	let mut failed = false;
+	for result in &results {
+	    match &result.outcome {
+		provider::Outcome::Changed(diff) => log::info!("{}/{}: {} changed: {}", kfg_namespace, kfg_name, result.id, diff),
+		provider::Outcome::Unchanged => log::debug!("{}/{}: {} unchanged", kfg_namespace, kfg_name, result.id),
+		provider::Outcome::Failed(reason) => log::error!("{}/{}: {} failed: {}", kfg_namespace, kfg_name, result.id, reason),
+		provider::Outcome::Skipped(reason) => log::warn!("{}/{}: {} skipped: {}", kfg_namespace, kfg_name, result.id, reason),
+		provider::Outcome::Waiting(reason) => log::debug!("{}/{}: {} not retried yet, {}", kfg_namespace, kfg_name, result.id, reason),
+		provider::Outcome::Drifted(diff) => log::warn!("{}/{}: {} drifted, {}: {}", kfg_namespace, kfg_name, result.id, held.unwrap_or_default(), diff),
+	    }
+	    match &result.outcome {
+		provider::Outcome::Failed(reason) | provider::Outcome::Waiting(reason) => failures.push(format!("{}: {}: {}", kref, result.id, reason)),
+		provider::Outcome::Changed(_) => changed = true,
+		_ => {},
 	    }
+	    failed |= !result.is_applied() && !matches!(result.outcome, provider::Outcome::Drifted(_));
+	}
+
+	let settings = ctx.knode_mgr.settings();
+	ctx.knode_mgr.backoff.record(&kref, konfigset, &results, settings.retry_base, settings.retry_cap, &pctx).await;
+
+	if results.iter().any(|result| matches!(result.outcome, provider::Outcome::Drifted(_))) {
+	    pending.push(format!("{} ({})", kref, held.unwrap_or_default()));
+	}
+
+	if failed {
+	    broken.push(kref.clone());
 	}
     }
 
-    Ok(ctx.knode_mgr.requeue())
+    conditions.push(if pending.is_empty() {
+	api::Condition::new(api::schedule::MAINTENANCE_PENDING, false, "UpToDate", "no drift is waiting to be applied")
+    } else {
+	api::Condition::new(api::schedule::MAINTENANCE_PENDING, true, "DriftHeld", &pending.join(", "))
+    });
+
+    conditions.push(match (knode.is_paused(), paused.is_empty()) {
+	(true, _) => api::Condition::new(api::condition::PAUSED, true, "NodePaused", "the node is paused, its drift is only reported"),
+	(false, false) => api::Condition::new(api::condition::PAUSED, true, "KonfigSetsPaused", &paused.join(", ")),
+	(false, true) => api::Condition::new(api::condition::PAUSED, false, "Active", "neither the node nor its KonfigSets are paused"),
+    });
+
+    /* holding the drift back is no failure, but the node isn't synced either */
+    let succeeded = failures.is_empty();
+    let state = match (succeeded, knode.is_paused()) {
+	(false, _) => api::KonfigNodeState::Failed,
+	(true, true) => api::KonfigNodeState::Paused,
+	(true, false) => api::KonfigNodeState::Ready,
+    };
+    let ready = tern(succeeded, Some(pending.is_empty()), None);
+    let next_retry = ctx.knode_mgr.backoff.next_retry();
+
+    /* the commits of the git sources still in use */
+    let sources: Vec<String> = konfigsets.iter()
+	.filter_map(|(_, konfigset)| konfigset.spec.configurations.as_ref())
+	.flat_map(|configs| configs.files.iter().flatten())
+	.filter_map(|file| match file.content_source() {
+	    Ok(api::FileSource::Git{ repository, reference, .. }) => Some(git::revision(&repository, reference.as_deref())),
+	    _ => None,
+	})
+	.collect();
+    let revisions = ctx.knode_mgr.git.revisions(&sources);
+    let now = api::condition::now();
+    ctx.knode_mgr.status.write(|status| {
+	status::set_state(status, api::KonfigNodeState::Syncing)?;
+	status::set_state(status, state)?;
+	if let Some(synced) = ready {
+	    status.synced = Some(synced);
+	}
+	status.failed_reason = status::failed_reason(&failures);
+	status.next_retry = next_retry.map(|at| at.to_rfc3339_opts(SecondsFormat::Secs, true));
+	status.last_attempt = Some(now.clone());
+	if succeeded {
+	    status.last_success = Some(now.clone());
+	}
+	if changed {
+	    status.last_change = Some(now);
+	}
+	status.revisions = tern(revisions.is_empty(), None, Some(revisions));
+	status::set_conditions(status, conditions);
+	Ok(())
+    }).await?;
+    ctx.knode_mgr.metrics.reconciled(succeeded);
+    ctx.knode_mgr.failed_cycles.store(0, Ordering::Relaxed);
+
+    Ok(ctx.knode_mgr.requeue_until(next_retry))
 }
 
-fn knode_error_policy(_knode: Arc<api::KonfigNode>, _error: &KubeError, ctx: Arc<KnodeManagerCtx>) -> KubeAction {
-    ctx.knode_mgr.requeue()
+/*
+ * The whole reconciliation failed (e.g: the API server is unreachable), it
+ * is retried sooner than the interval at first, then backs off.
+ */
+fn knode_error_policy(_knode: Arc<api::KonfigNode>, error: &Error, ctx: Arc<KnodeManagerCtx>) -> KubeAction {
+    let attempts = ctx.knode_mgr.failed_cycles.fetch_add(1, Ordering::Relaxed) + 1;
+    let settings = ctx.knode_mgr.settings();
+    let delay = backoff::delay(attempts, settings.retry_base, settings.retry_cap);
+
+    /* the reconciliation returned early, it still counts */
+    ctx.knode_mgr.metrics.reconciled(false);
+
+    log::warn!("Reconciliation failed {} time(s) in a row, retrying in {}s: {}", attempts, delay.as_secs(), error);
+    KubeAction::requeue(delay)
 }
 
 impl KNodeMgr {
 
+    /*
+     * Only our own KonfigNode is watched, which is also all a node
+     * ServiceAccount may list (see KonfigNodeRegistration).
+     */
+    fn watcher_config(&self) -> kube_watcher::Config {
+	kube_watcher::Config::default().fields(&format!("metadata.name={}", self.name))
+    }
+
     /*
      * watcher returns a Future object that watches on updates from this own node.
      */
@@ -292,7 +495,7 @@ impl KNodeMgr {
 	let (_reader, writer) = kube_reflector::store();
 	let reflector = kube_reflector::reflector(
 	    writer,
-	    kube_watcher(self.knode_api.clone(), kube_watcher::Config::default()),
+	    kube_watcher(self.knode_api.clone(), self.watcher_config()),
 	);
 
 	// return the reflector future, to be used by tokio::select!
@@ -311,7 +514,7 @@ impl KNodeMgr {
 	    knode_mgr: self.clone(),
 	});
 
-	KubeController::new(self.knode_api.clone(), kube_watcher::Config::default())
+	KubeController::new(self.knode_api.clone(), self.watcher_config())
 	    .run(knode_reconcile, knode_error_policy, ctx)
 	    .for_each(|reconcile| async move {
 		if let Err(err) = reconcile {
@@ -320,97 +523,204 @@ impl KNodeMgr {
 	    })
     }
 
-    pub async fn patch_status(&self, name: &str, new_status: api::KonfigNodeStatus) -> Result<(), KubeError> {
-	if let Some(me) = self.knode_api.get_opt(name).await? {
-	    let mut new_me = me.clone();
-	    let new_status = Some(new_status);
-	    new_me.status = new_status;
-
-	    let opts = KubePatchParams::default();
-	    self.knode_api.patch_status(name, &opts, &KubePatch::Merge(new_me)).await?;
-	}
-
-	Ok(())
-    }
-
     /*
-     * A wrapper from patch_status() that simply updates the KonfigNode state which is common path in the code.
+     * A wrapper from the status writer that simply updates the KonfigNode state which is common path in the code.
      */
-    pub async fn patch_status_state(&self, name: &str, state: api::KonfigNodeState, synced: Option<bool>) -> Result<(), KubeError> {
-	if let Some(me) = self.knode_api.get_opt(name).await? {
-	    let opts = KubePatchParams::default();
-
-	    let mut new_me = me.clone();
-	    let mut new_status = me.status.clone().unwrap();
-
-	    new_status.state = Some(state.to_string());
+    pub async fn patch_status_state(&self, state: api::KonfigNodeState, synced: Option<bool>) -> Result<(), Error> {
+	self.status.write(|status| {
+	    status::set_state(status, state)?;
 	    if let Some(sync) = synced {
-		new_status.synced = Some(sync);
+		status.synced = Some(sync);
 	    }
-
-	    new_me.status = Some(new_status);
-	    self.knode_api.patch_status(name, &opts, &KubePatch::Merge(new_me)).await?;
-	}
+	    Ok(())
+	}).await?;
 
 	Ok(())
     }
 
+    /* ours, along with the ones of the configuration */
     pub fn default_labels(&self) -> BTreeMap<String, String> {
-	let mut labels = BTreeMap::new();
+	let mut labels = self.settings().labels;
 
-	labels.insert(String::from("konfignodes.runfc.br/name"), self.name.clone());
-	labels.insert(String::from("konfignodes.runfc.br/managed"), String::from("true"));
+	labels.extend(api::konfignode::default_labels(&self.name));
 	labels
     }
 
-    pub async fn register(&self) -> Result<(), KubeError> {
+    pub fn settings(&self) -> config::Reloadable {
+	self.reloadable.read().unwrap().clone()
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+	self.metrics.clone()
+    }
+
+    /*
+     * Applies the new settings (on SIGHUP), the labels dropped from the
+     * configuration are removed from the KonfigNode.
+     */
+    pub async fn reload(&self, reloadable: config::Reloadable) -> Result<(), KubeError> {
+	let previous = std::mem::replace(&mut *self.reloadable.write().unwrap(), reloadable.clone());
+	self.metrics.set_period(reloadable.interval + reloadable.jitter);
+
+	if previous.labels != reloadable.labels {
+	    let mut labels = serde_json::Map::new();
+	    for key in previous.labels.keys() {
+		labels.insert(key.clone(), serde_json::Value::Null);
+	    }
+	    for (key, value) in self.default_labels() {
+		labels.insert(key, serde_json::Value::from(value));
+	    }
+	    let patch = serde_json::json!({ "metadata": { "labels": labels } });
+	    match self.knode_api.patch(&self.name, &KubePatchParams::default(), &KubePatch::Merge(&patch)).await {
+		Err(err) if forbidden(&err) => log::warn!("The labels of a bootstrapped KonfigNode are managed in the cluster, not updating them"),
+		result => { result?; },
+	    }
+	}
+	Ok(())
+    }
+
+    pub async fn register(&self) -> Result<(), Error> {
 	let name = self.name.as_str();
 
-	match self.knode_api.get_opt(name).await? {
+	let node = match self.knode_api.get_opt(name).await? {
 	    Some(node) => {
-		log::warn!("Interesting! I was already here before, so I'm retaking my position on the control plane: {:?}", node);
+		log::info!("I was already here before, so I'm retaking my position on the control plane with my KonfigSets");
+		node
 	    },
 	    None => {
 		let new = api::konfignode::new(name, self.default_labels());
 		let opts = KubePostParams::default();
-		self.knode_api.create(&opts, &new).await?;
+		self.knode_api.create(&opts, &new).await?
 	    }
 	};
 
-	let status = api::KonfigNodeStatus::default();
-	if let Err(err) = self.patch_status(name, status).await {
+	/* so we get to clean up when the KonfigNode is deleted, konfigm adds it to bootstrapped nodes */
+	let mut finalizers = node.metadata.finalizers.clone().unwrap_or_default();
+	if !finalizers.iter().any(|finalizer| finalizer == api::konfignode::FINALIZER) {
+	    finalizers.push(api::konfignode::FINALIZER.to_string());
+	    let patch = serde_json::json!({ "metadata": { "finalizers": finalizers } });
+	    match self.knode_api.patch(name, &KubePatchParams::default(), &KubePatch::Merge(&patch)).await {
+		Err(err) if forbidden(&err) => log::debug!("Not allowed to add my finalizer, konfigm does"),
+		result => { result?; },
+	    }
+	}
+
+	/* starting over, the timestamps of the previous run are kept */
+	if let Err(err) = self.patch_status_state(api::KonfigNodeState::Starting, Some(false)).await {
 	    log::warn!("Unable to update instance status: {:?}", err);
 	}
 	Ok(())
     }
 
-    pub async fn unregister(&self) -> Result<(), KubeError> {
+    /*
+     * A restart or an upgrade: the KonfigNode keeps its KonfigSets, which
+     * are applied again when we are back.
+     */
+    pub async fn stop(&self) -> Result<(), Error> {
+	self.patch_status_state(api::KonfigNodeState::Stopped, None).await
+    }
+
+    /*
+     * Decommissioning: deletes the KonfigNode (and with it the
+     * registration and the credentials of the node) and forgets about it.
+     */
+    pub async fn leave(&self) -> Result<(), Error> {
 	let name = self.name.as_str();
 
-	if let Some(_) = self.knode_api.get_opt(name).await? {
-	    if let Err(err) = self.patch_status_state(name, api::KonfigNodeState::LEAVING, None).await {
+	if self.knode_api.get_opt(name).await?.is_some() {
+	    self.knode_api.delete(name, &KubeDeleteParams::default()).await?;
+	    self.finalize().await?;
+	}
+	Ok(())
+    }
+
+    /*
+     * Lets the deletion of our KonfigNode go ahead, the local state is
+     * removed last as it holds the credentials to do so.  Bootstrapped
+     * nodes can't update their KonfigNode, konfigm removes the finalizer
+     * once they are leaving.
+     */
+    async fn finalize(&self) -> Result<(), Error> {
+	if let Some(node) = self.knode_api.get_opt(&self.name).await? {
+	    if let Err(err) = self.patch_status_state(api::KonfigNodeState::Leaving, None).await {
 		log::warn!("Unable to update instance status: {:?}", err);
 	    }
 
-	    let opts = KubeDeleteParams{
-		dry_run: false,
-		grace_period_seconds: Some(0),
-		propagation_policy: None,
-		preconditions: None,
-	    };
-	    self.knode_api.delete(name, &opts).await?;
+	    let finalizers: Vec<String> = node.metadata.finalizers.clone().unwrap_or_default().into_iter()
+		.filter(|finalizer| finalizer != api::konfignode::FINALIZER)
+		.collect();
+	    let patch = serde_json::json!({ "metadata": { "finalizers": finalizers } });
+	    match self.knode_api.patch(&self.name, &KubePatchParams::default(), &KubePatch::Merge(&patch)).await {
+		Err(err) if forbidden(&err) => log::info!("konfigm removes my finalizer now that I'm leaving"),
+		result => { result?; },
+	    }
+	}
+
+	if let Err(err) = bootstrap::forget(&self.state_dir) {
+	    log::error!("Unable to remove my local state from {}: {}", self.state_dir.display(), err);
 	}
 	Ok(())
     }
 
+    /* resolves once our KonfigNode was deleted */
+    pub async fn deleted(&self) {
+	self.deleted.notified().await
+    }
+
+    pub fn provider_ctx(&self, namespace: &str) -> provider::Context {
+	provider::Context{
+	    kube_client: self.kube_client.clone(),
+	    root: self.root.clone(),
+	    namespace: namespace.to_string(),
+	    identities: self.identities.clone(),
+	    http: self.http.clone(),
+	    git: self.git.clone(),
+	    state_dir: self.state_dir.clone(),
+	    adopt_accounts: true,
+	}
+    }
+
     pub fn requeue(&self) -> KubeAction {
-	return KubeAction::requeue(Duration::from_secs(self.reconcilation_interval));
+	let settings = self.settings();
+	let jitter = rand::thread_rng().gen_range(0..=settings.jitter);
+
+	return KubeAction::requeue(Duration::from_secs(settings.interval + jitter));
     }
 
-    pub fn new(kube_client: KubeClient, name: String, interval: u64) -> Self {
+    /* the usual interval, unless some failed resource is to be retried sooner */
+    pub fn requeue_until(&self, next_retry: Option<DateTime<Utc>>) -> KubeAction {
+	let settings = self.settings();
+	let delay = match next_retry {
+	    Some(at) => (at - Utc::now()).to_std().unwrap_or_default().as_secs().max(1),
+	    None => settings.interval,
+	};
+
+	match delay < settings.interval {
+	    true => KubeAction::requeue(Duration::from_secs(delay)),
+	    false => self.requeue(),
+	}
+    }
+
+    pub fn new(kube_client: KubeClient, name: String, settings: &config::Settings,
+	       trusted_keys: Vec<VerifyingKey>, identities: Vec<age::x25519::Identity>, http: Fetcher) -> Self {
+	let status = StatusWriter::new(KubeApi::all(kube_client.clone()), &name);
+	let reloadable = settings.reloadable.clone();
+
 	Self{
 	    name: name,
-	    reconcilation_interval: interval,
+	    metrics: Arc::new(Metrics::new(reloadable.interval + reloadable.jitter)),
+	    reloadable: Arc::new(RwLock::new(reloadable)),
+	    root: settings.root.clone(),
+	    registry: Arc::new(provider::Registry::default()),
+	    trusted_keys: Arc::new(trusted_keys),
+	    identities: Arc::new(identities),
+	    http,
+	    git: Arc::new(Repositories::new(settings.state_dir.join("cache/git"))),
+	    state_dir: settings.state_dir.clone(),
+	    deleted: Arc::new(Notify::new()),
+	    backoff: Arc::new(Backoff::default()),
+	    failed_cycles: Arc::new(AtomicU32::new(0)),
+	    status,
 
 	    /* k8s internal references */
 	    kube_client: kube_client.clone(),
@@ -0,0 +1,177 @@
+/*
+ * local - common ground for everything konfigd manages on the local system.
+ *
+ * All the paths are relative to a root directory, which is `/` for the
+ * running host, so the very same code can also configure a chroot, a
+ * mounted image or a temporary directory (e.g: CI running full reconciles).
+ */
+use crate::errors::Error;
+
+use sha2::Digest;
+use sha2::Sha256;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+/*
+ * Resolves an absolute path (e.g: /etc/passwd) under root, a path going up
+ * (..) could escape it and is refused.
+ */
+pub fn rooted(root: &Path, path: &str) -> Result<PathBuf, Error> {
+    let relative = Path::new(path.trim_start_matches('/'));
+
+    if relative.components().any(|component| component == Component::ParentDir) {
+	let errmsg = format!("Refusing to manage {}, it must not contain .. components", path);
+	return Err(Error::KonfigError(errmsg));
+    }
+    Ok(root.join(relative))
+}
+
+/*
+ * Opens a directory without following it when it is a symlink (the
+ * components above it are followed), so what is done relative to it can't
+ * be redirected elsewhere by whoever owns it.
+ */
+pub fn open_directory(path: &Path) -> Result<fs::File, Error> {
+    let opened = fs::OpenOptions::new()
+	.read(true)
+	.custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC)
+	.open(path);
+
+    match opened {
+	Ok(dir) => Ok(dir),
+	Err(err) if matches!(err.raw_os_error(), Some(libc::ELOOP) | Some(libc::ENOTDIR)) => {
+	    let errmsg = format!("Refusing to write into {}, it is not a directory (symlinks are not followed)", path.display());
+	    Err(Error::KonfigError(errmsg))
+	},
+	Err(err) => Err(Error::Io(err)),
+    }
+}
+
+fn c_name(name: &OsStr) -> Result<CString, Error> {
+    CString::new(name.as_bytes()).map_err(|_| Error::KonfigError(format!("Invalid file name {:?}", name)))
+}
+
+/*
+ * Write content into path atomically (temporary file + rename) with the
+ * given mode and, when defined, owner.
+ *
+ * Everything happens relative to the opened parent directory, which may be
+ * owned by someone else (e.g: ~/.ssh): the temporary file gets a random
+ * name, is created exclusively without following symlinks and only
+ * readable by us, then is chown-ed and chmod-ed through its descriptor.
+ */
+pub fn write_atomic(path: &Path, content: &[u8], mode: u32, owner: Option<(u32, u32)>) -> Result<(), Error> {
+    let (parent, name) = match (path.parent(), path.file_name()) {
+	(Some(parent), Some(name)) => (parent, name),
+	_ => {
+	    let errmsg = format!("Unable to find the parent directory of {}", path.display());
+	    return Err(Error::KonfigError(errmsg));
+	}
+    };
+    let dir = open_directory(parent)?;
+    let tmp = format!(".{}.{:016x}.konfig-tmp", name.to_string_lossy(), rand::random::<u64>());
+    let (tmp, name) = (c_name(OsStr::new(&tmp))?, c_name(name)?);
+
+    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), tmp.as_ptr(), flags, 0o600 as libc::c_uint) };
+    if fd < 0 {
+	return Err(Error::Io(io::Error::last_os_error()));
+    }
+    let mut file = unsafe { fs::File::from_raw_fd(fd) };
+
+    let written = (|| -> Result<(), io::Error> {
+	file.write_all(content)?;
+	if let Some((uid, gid)) = owner {
+	    std::os::unix::fs::fchown(&file, Some(uid), Some(gid))?;
+	}
+	/* after the chown, which clears the setuid and setgid bits */
+	file.set_permissions(fs::Permissions::from_mode(mode))?;
+	file.sync_all()?;
+
+	match unsafe { libc::renameat(dir.as_raw_fd(), tmp.as_ptr(), dir.as_raw_fd(), name.as_ptr()) } {
+	    0 => Ok(()),
+	    _ => Err(io::Error::last_os_error()),
+	}
+    })();
+
+    if let Err(err) = written {
+	unsafe { libc::unlinkat(dir.as_raw_fd(), tmp.as_ptr(), 0) };
+	return Err(Error::Io(err));
+    }
+    Ok(())
+}
+
+/* lower case hex */
+pub fn sha256(content: &[u8]) -> String {
+    Sha256::digest(content).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/*
+ * The sha256 of a file, read by chunks so large (or binary) files are never
+ * loaded at once.
+ */
+pub fn file_sha256(path: &Path) -> Result<String, Error> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rooted_paths() {
+	let root = Path::new("/srv/image");
+
+	assert_eq!(rooted(root, "/etc/passwd").unwrap(), PathBuf::from("/srv/image/etc/passwd"));
+	assert_eq!(rooted(root, "etc/./passwd").unwrap(), PathBuf::from("/srv/image/etc/passwd"));
+	assert_eq!(rooted(Path::new("/"), "/etc/passwd").unwrap(), PathBuf::from("/etc/passwd"));
+	assert!(rooted(root, "/../etc/passwd").is_err());
+	assert!(rooted(root, "/home/../../etc/passwd").is_err());
+	assert!(rooted(root, "..").is_err());
+    }
+
+    #[test]
+    fn writes_without_following_symlinks() {
+	use std::os::unix::fs::symlink;
+
+	let dir = std::env::temp_dir().join(format!("konfigd-local-{}", std::process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(dir.join("home/.ssh")).unwrap();
+	fs::create_dir_all(dir.join("etc")).unwrap();
+	fs::write(dir.join("etc/shadow"), "root:*:::\n").unwrap();
+
+	write_atomic(&dir.join("home/.ssh/authorized_keys"), b"ssh-ed25519 AAAA\n", 0o600, None).unwrap();
+	let meta = fs::metadata(dir.join("home/.ssh/authorized_keys")).unwrap();
+	assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+	assert_eq!(fs::read_dir(dir.join("home/.ssh")).unwrap().count(), 1);
+
+	/* a symlinked directory is refused, whatever it points to is left alone */
+	fs::remove_dir_all(dir.join("home/.ssh")).unwrap();
+	symlink(dir.join("etc"), dir.join("home/.ssh")).unwrap();
+	assert!(write_atomic(&dir.join("home/.ssh/shadow"), b"evil\n", 0o644, None).is_err());
+	assert_eq!(fs::read(dir.join("etc/shadow")).unwrap(), b"root:*:::\n");
+
+	/* the file itself being a symlink is replaced, not followed */
+	fs::remove_file(dir.join("home/.ssh")).unwrap();
+	fs::create_dir_all(dir.join("home/.ssh")).unwrap();
+	symlink(dir.join("etc/shadow"), dir.join("home/.ssh/authorized_keys")).unwrap();
+	write_atomic(&dir.join("home/.ssh/authorized_keys"), b"ssh-ed25519 AAAA\n", 0o600, None).unwrap();
+	assert_eq!(fs::read(dir.join("etc/shadow")).unwrap(), b"root:*:::\n");
+	assert!(!fs::symlink_metadata(dir.join("home/.ssh/authorized_keys")).unwrap().file_type().is_symlink());
+    }
+}
@@ -1,21 +1,138 @@
+mod accounts;
+mod backoff;
+mod bootstrap;
+mod config;
 mod errors;
+mod file;
+mod git;
+mod health;
+mod heartbeat;
+mod http;
 mod konfignode;
+mod local;
+mod provider;
+mod sources;
+mod status;
+mod sysctl;
+use bootstrap::Credentials;
+use config::Settings;
+use heartbeat::Heartbeat;
 use konfignode::KNodeMgr;
 
+use konfig_api as api;
 use log;
 use clap::Parser;
+use clap::Subcommand;
 use gethostname::gethostname;
-use kube::Client as KubeClient;
 use kube::runtime::watcher as kube_watcher;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::exit;
+use tokio::signal::unix::SignalKind;
+use tokio::signal::unix::signal;
 
 /// Konfigd - Konfig daemon running on managed machine
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
 
-    /// Defines the knode name to use when register in control plane (default: system hostname)
-    #[arg(short, long)]
-    knodename: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Configuration file, its settings are overridden by the environment and the flags (default: /etc/konfig/konfigd.toml, if any)
+    #[arg(short, long, env = "KONFIGD_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    options: config::Options,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+
+    /// Decommissions the node: deletes its KonfigNode and forgets its credentials (stop the daemon first)
+    Leave,
+}
+
+/* only serves them when configured */
+async fn serve_health(settings: &Settings, me: &KNodeMgr) {
+    let addr = match settings.health_addr {
+	Some(addr) => addr,
+	None => return futures::future::pending().await,
+    };
+
+    if let Err(err) = health::serve(addr, me.metrics()).await {
+	log::error!("Unable to serve /healthz and /metrics on {}: {}", addr, err);
+    }
+}
+
+/*
+ * RUST_LOG, when defined, wins over the configured log level, so it can't
+ * be changed on reload either.
+ */
+fn init_logging(settings: &Settings) {
+    let mut builder = env_logger::Builder::from_default_env();
+
+    if std::env::var_os("RUST_LOG").is_none() {
+	builder.filter_level(log::LevelFilter::Trace);
+    }
+    if settings.json_logs {
+	builder.format(|buf, record| {
+	    let line = serde_json::json!({
+		"time": chrono::Utc::now().to_rfc3339(),
+		"level": record.level().to_string(),
+		"target": record.target(),
+		"message": record.args().to_string(),
+	    });
+	    writeln!(buf, "{}", line)
+	});
+    }
+    builder.init();
+
+    if std::env::var_os("RUST_LOG").is_none() {
+	log::set_max_level(settings.reloadable.log_level);
+    }
+}
+
+/*
+ * Reads the configuration again on SIGHUP, only the reloadable settings
+ * are applied right away.
+ */
+async fn reload(args: &Args, mut current: Settings, me: &KNodeMgr) {
+    let mut sighup = signal(SignalKind::hangup()).expect("unable to handle SIGHUP");
+
+    while sighup.recv().await.is_some() {
+	let settings = match config::load(args.config.as_deref(), &args.options) {
+	    Ok(settings) => settings,
+	    Err(err) => {
+		log::error!("Not reloading, {}", err);
+		continue;
+	    }
+	};
+
+	let mut restart = settings.clone();
+	restart.reloadable = current.reloadable.clone();
+	if restart != current {
+	    log::warn!("Only the interval, jitter, labels, log level, retry delays and allowed kinds are reloaded, restart to apply the other settings");
+	}
+
+	if std::env::var_os("RUST_LOG").is_none() {
+	    log::set_max_level(settings.reloadable.log_level);
+	}
+	if let Err(err) = me.reload(settings.reloadable.clone()).await {
+	    log::error!("Unable to update my labels: {}", err);
+	}
+	log::info!("Configuration reloaded");
+	current.reloadable = settings.reloadable;
+    }
+}
+
+/* renews the node token when it joined with --bootstrap-kubeconfig */
+async fn refresh_credentials(credentials: &Option<Credentials>) {
+    match credentials {
+	Some(credentials) => credentials.run().await,
+	None => futures::future::pending().await,
+    }
 }
 
 async fn register(me: &KNodeMgr) {
@@ -26,11 +143,23 @@ async fn register(me: &KNodeMgr) {
     }
 }
 
-async fn unregister(me: &KNodeMgr) {
-    log::info!("Unregistering myself ...");
+async fn stop(me: &KNodeMgr) {
+    log::info!("Stopping, my KonfigNode is kept until I'm back ...");
 
-    if let Err(err) = me.unregister().await {
-	log::error!("Unable to unregister myself: {}", err);
+    if let Err(err) = me.stop().await {
+	log::error!("Unable to tell I'm stopping: {}", err);
+    }
+}
+
+async fn leave(me: &KNodeMgr) -> bool {
+    log::info!("Leaving ...");
+
+    match me.leave().await {
+	Ok(()) => true,
+	Err(err) => {
+	    log::error!("Unable to leave: {}", err);
+	    false
+	}
     }
 }
 
@@ -57,27 +186,86 @@ fn get_node_name(hostname: Option<String>) -> String {
  */
 #[tokio::main]
 async fn main() -> Result<(), kube_watcher::Error> {
-    env_logger::init();
     let args = Args::parse();
+    let settings = match config::load(args.config.as_deref(), &args.options) {
+	Ok(settings) => settings,
+	Err(err) => {
+	    eprintln!("{}", err);
+	    exit(2);
+	}
+    };
+    init_logging(&settings);
 
-    let name = match args.knodename {
+    let name = match settings.knodename.clone() {
 	Some(name) => name,
 	None => get_node_name(None),
     };
-    let kube_client = KubeClient::try_default().await.unwrap();
+    /* leaving never joins */
+    let bootstrap_kubeconfig = match args.command {
+	Some(Command::Leave) => None,
+	None => settings.bootstrap_kubeconfig.as_deref(),
+    };
+    let (kube_client, credentials) = match bootstrap::client(&name, &settings.state_dir, bootstrap_kubeconfig).await {
+	Ok(Some((kube_client, credentials))) => (kube_client, Some(credentials)),
+	Ok(None) => match bootstrap::configured_client(settings.kubeconfig.as_deref(), settings.context.clone()).await {
+	    Ok(kube_client) => (kube_client, None),
+	    Err(err) => panic!("Unable to connect to the cluster: {}", err),
+	},
+	Err(err) => panic!("Unable to join the cluster: {}", err),
+    };
 
-    log::info!("starting konfigd for {}", name);
-    let me = KNodeMgr::new(kube_client.clone(), name, 60);
+    log::info!("starting konfigd for {} (root: {})", name, settings.root.display());
+    if !settings.root.is_dir() {
+	panic!("The root directory {} does not exist", settings.root.display());
+    }
+    let heartbeat = Heartbeat::new(kube_client.clone(), &name, &settings.lease_namespace, settings.lease_duration);
+    let trusted_keys = settings.trusted_keys.iter()
+	.map(|path| api::signature::read_verifying_key(path).unwrap_or_else(|err| panic!("{}", err)))
+	.collect();
+    let mut identities = match &settings.identity_file {
+	Some(path) => api::sealed::read_identities(path).unwrap_or_else(|err| panic!("{}", err)),
+	None => vec![],
+    };
+    if let Some(credentials) = &credentials {
+	identities.push(credentials.identity());
+    }
+    let fetcher = http::Fetcher::new(settings.state_dir.join("cache/http"), settings.http_ca.as_deref(),
+				     settings.http_timeout, settings.http_max_size)
+	.unwrap_or_else(|err| panic!("{}", err));
+    let me = KNodeMgr::new(kube_client.clone(), name, &settings, trusted_keys, identities, fetcher);
+
+    if let Some(Command::Leave) = args.command {
+	match leave(&me).await {
+	    true => exit(0),
+	    false => exit(1),
+	}
+    }
 
     register(&me).await;
-    tokio::select! {
-	_ = me.watcher() => {},
-	_ = me.controller() => {},
+    let mut sigterm = signal(SignalKind::terminate()).expect("unable to handle SIGTERM");
+    let deleted = tokio::select! {
+	_ = me.watcher() => false,
+	_ = me.controller() => false,
+	_ = heartbeat.run() => false,
+	_ = refresh_credentials(&credentials) => false,
+	_ = serve_health(&settings, &me) => false,
+	_ = reload(&args, settings.clone(), &me) => false,
+
+	// our KonfigNode was deleted, we already cleaned up
+	_ = me.deleted() => true,
 
-	// handle CTRL^C as gracefully as we can.
-	_ = tokio::signal::ctrl_c() => {},
+	// handle CTRL^C and SIGTERM as gracefully as we can.
+	_ = tokio::signal::ctrl_c() => false,
+	_ = sigterm.recv() => false,
+    };
+
+    if !deleted {
+	if settings.deregister_on_exit {
+	    leave(&me).await;
+	} else {
+	    stop(&me).await;
+	}
     }
-    unregister(&me).await;
 
     Ok(())
 }
@@ -0,0 +1,473 @@
+/*
+ * provider - pluggable resource kinds.
+ *
+ * Every kind of resource konfigd knows how to manage (file, sysctl, user,
+ * ...) is a ResourceProvider registered in the Registry.  A provider parses
+ * its own entries from the KonfigSet spec into Resources, which can then be
+ * checked, described and applied by the reconcile loop without it knowing
+ * anything about the kind itself.
+ */
+use crate::accounts;
+use crate::errors::Error;
+use crate::file;
+use crate::git::Repositories;
+use crate::http::Fetcher;
+use crate::sysctl;
+use konfig_api as api;
+use konfig_api::Graph;
+
+use futures::future::BoxFuture;
+use kube::Client as KubeClient;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/*
+ * Identifies a resource within a KonfigSet by its kind and name (the file
+ * destination, the sysctl name, the user name, ...).
+ */
+pub type ResourceId = api::ResourceRef;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /* the resource was drifted and has been applied, with what was changed */
+    Changed(String),
+
+    /* the resource is already in the desired state */
+    Unchanged,
+
+    /* the resource could not be parsed, checked or applied */
+    Failed(String),
+
+    /* the resource was not even tried as one of its requirements failed */
+    Skipped(String),
+
+    /* the resource drifted but was left as is, outside of the maintenance windows */
+    Drifted(String),
+
+    /* the resource failed before and is not retried yet, see backoff.rs */
+    Waiting(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ResourceResult {
+    pub id: ResourceId,
+    pub outcome: Outcome,
+}
+
+impl ResourceResult {
+
+    pub fn failed(id: ResourceId, err: Error) -> Self {
+	Self{ id, outcome: Outcome::Failed(err.to_string()) }
+    }
+
+    /* whether dependents of this resource can go ahead */
+    pub fn is_applied(&self) -> bool {
+	matches!(self.outcome, Outcome::Changed(_) | Outcome::Unchanged)
+    }
+}
+
+/*
+ * A single managed item of the local system.
+ */
+pub trait Resource: Send {
+
+    fn id(&self) -> ResourceId;
+
+    /* whether the local system drifted from the desired state */
+    fn check(&self) -> Result<bool, Error>;
+
+    /* brings the local system to the desired state */
+    fn apply(&self) -> Result<(), Error>;
+
+    /* a human readable (and secret free) summary of what apply() changes */
+    fn describe_diff(&self) -> String;
+}
+
+/*
+ * Everything a provider may need to build its resources from a KonfigSet.
+ */
+#[derive(Clone)]
+pub struct Context {
+    pub kube_client: KubeClient,
+
+    /* where the local system lives, every managed path is relative to it */
+    pub root: PathBuf,
+
+    /* the namespace of the KonfigSet being parsed */
+    pub namespace: String,
+
+    /* decrypts the sealed content, see api::sealed */
+    pub identities: Arc<Vec<age::x25519::Identity>>,
+
+    /* downloads the http(s):// sources */
+    pub http: Fetcher,
+
+    /* the clones of the git+ sources */
+    pub git: Arc<Repositories>,
+
+    /* where konfigd keeps its own state, e.g: the accounts it created */
+    pub state_dir: PathBuf,
+
+    /* whether the accounts konfigd didn't create may be managed, see api::konfigpolicy */
+    pub adopt_accounts: bool,
+}
+
+/*
+ * Parsing may fail for a single entry (e.g: its content cannot be fetched),
+ * which is then reported as a failed result for that entry.
+ */
+pub type Parsed = Result<Box<dyn Resource>, ResourceResult>;
+
+pub trait ResourceProvider: Send + Sync {
+
+    /* the kind of resources this provider manages */
+    fn kind(&self) -> &'static str;
+
+    /* builds the resources from the entries of its kind in the configuration */
+    fn parse<'a>(&'a self, configs: &'a api::konfigset::Configuration, ctx: &'a Context) -> BoxFuture<'a, Vec<Parsed>>;
+}
+
+pub struct Registry {
+    providers: Vec<Box<dyn ResourceProvider>>,
+}
+
+impl Registry {
+
+    pub fn new() -> Self {
+	Self{ providers: vec![] }
+    }
+
+    /*
+     * Adds a new kind, providers are parsed in the order they were
+     * registered.
+     */
+    pub fn register(&mut self, provider: Box<dyn ResourceProvider>) {
+	log::debug!("registering resource provider: {}", provider.kind());
+	self.providers.push(provider);
+    }
+
+    pub async fn parse(&self, konfigset: &api::KonfigSet, ctx: &Context) -> Vec<Parsed> {
+	let mut parsed = vec![];
+
+	if let Some(configs) = &konfigset.spec.configurations {
+	    for provider in &self.providers {
+		parsed.append(&mut provider.parse(configs, ctx).await);
+	    }
+	}
+	parsed
+    }
+}
+
+impl Default for Registry {
+
+    /*
+     * The kinds built into konfigd, groups come before users so these can
+     * reference them.
+     */
+    fn default() -> Self {
+	let mut registry = Registry::new();
+
+	registry.register(Box::new(accounts::GroupProvider));
+	registry.register(Box::new(accounts::UserProvider));
+	registry.register(Box::new(sysctl::SysctlProvider));
+	registry.register(Box::new(file::FileProvider));
+	registry
+    }
+}
+
+pub fn parsed_id(parsed: &Parsed) -> ResourceId {
+    match parsed {
+	Ok(resource) => resource.id(),
+	Err(failed) => failed.id.clone(),
+    }
+}
+
+/*
+ * The resources of a KonfigSet sorted so each one comes after the ones it
+ * requires.
+ */
+pub struct Plan {
+    resources: Vec<Parsed>,
+    graph: Graph<ResourceId>,
+}
+
+impl Plan {
+
+    /*
+     * When the dependencies of the configuration are invalid (unknown
+     * references or cycles) nothing can be safely applied, so every resource
+     * is reported as failed instead.
+     */
+    pub fn new(konfigset: &api::KonfigSet, mut resources: Vec<Parsed>) -> Result<Plan, Vec<ResourceResult>> {
+	let graph = match &konfigset.spec.configurations {
+	    Some(configs) => configs.dependency_graph(),
+	    None => Ok(Graph::new()),
+	};
+	let ordered = graph.and_then(|graph| match graph.order() {
+	    Ok(order) => Ok((graph, order)),
+	    Err(cycle) => Err(cycle.to_string()),
+	});
+
+	let (graph, order) = match ordered {
+	    Ok(ordered) => ordered,
+	    Err(reason) => {
+		let reason = format!("invalid dependencies, {}", reason);
+		let rejected = resources.iter()
+		    .map(|parsed| ResourceResult{ id: parsed_id(parsed), outcome: Outcome::Failed(reason.clone()) })
+		    .collect();
+		return Err(rejected);
+	    }
+	};
+
+	/* resources unknown to the graph (e.g: new kinds) go last */
+	resources.sort_by_key(|parsed| {
+	    let id = parsed_id(parsed);
+	    order.iter().position(|other| *other == id).unwrap_or(order.len())
+	});
+
+	Ok(Plan{ resources, graph })
+    }
+
+    /* whether any of the resources needs to be applied (or cannot be checked) */
+    pub fn is_drifted(&self) -> bool {
+	self.resources.iter().any(|parsed| match parsed {
+	    Ok(resource) => resource.check().unwrap_or(true),
+	    Err(_) => false,
+	})
+    }
+
+    /*
+     * Checks and applies every drifted resource in order, the dependents of
+     * a resource which couldn't be applied are skipped.
+     */
+    pub fn apply(self) -> Vec<ResourceResult> {
+	let mut results: Vec<ResourceResult> = vec![];
+	let mut broken: BTreeSet<ResourceId> = BTreeSet::new();
+
+	for parsed in self.resources {
+	    let id = parsed_id(&parsed);
+	    let blocked: Vec<String> = self.graph.requirements(&id).iter()
+		.filter(|requirement| broken.contains(*requirement))
+		.map(|requirement| requirement.to_string())
+		.collect();
+
+	    let result = if !blocked.is_empty() {
+		let reason = format!("requires {} which could not be applied", blocked.join(", "));
+		ResourceResult{ id: id.clone(), outcome: Outcome::Skipped(reason) }
+	    } else {
+		match parsed {
+		    Err(failed) => failed,
+		    Ok(resource) => match resource.check() {
+			Ok(false) => ResourceResult{ id: id.clone(), outcome: Outcome::Unchanged },
+			Ok(true) => apply(resource.as_ref()),
+			Err(err) => ResourceResult::failed(id.clone(), err),
+		    },
+		}
+	    };
+
+	    if !result.is_applied() {
+		broken.insert(id);
+	    }
+	    results.push(result);
+	}
+
+	results
+    }
+
+    /*
+     * Only checks the resources, the drifted ones are reported with what
+     * applying them would change.
+     */
+    pub fn report(self) -> Vec<ResourceResult> {
+	self.resources.into_iter().map(|parsed| match parsed {
+	    Err(failed) => failed,
+	    Ok(resource) => match resource.check() {
+		Ok(false) => ResourceResult{ id: resource.id(), outcome: Outcome::Unchanged },
+		Ok(true) => ResourceResult{ id: resource.id(), outcome: Outcome::Drifted(resource.describe_diff()) },
+		Err(err) => ResourceResult::failed(resource.id(), err),
+	    },
+	}).collect()
+    }
+}
+
+fn apply(resource: &dyn Resource) -> ResourceResult {
+    let diff = resource.describe_diff();
+
+    match resource.apply() {
+	Ok(()) => ResourceResult{ id: resource.id(), outcome: Outcome::Changed(diff) },
+	Err(err) => ResourceResult::failed(resource.id(), err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    /* an empty root directory, with only /etc */
+    fn sandbox(name: &str) -> PathBuf {
+	let root = std::env::temp_dir().join(format!("konfigd-{}-{}", name, std::process::id()));
+	let _ = fs::remove_dir_all(&root);
+	fs::create_dir_all(root.join("etc")).unwrap();
+	root
+    }
+
+    /* nothing in these tests talks to the cluster */
+    fn context(root: &Path) -> Context {
+	let config = kube::Config::new("http://127.0.0.1:9".parse().unwrap());
+
+	Context{
+	    kube_client: KubeClient::try_from(config).unwrap(),
+	    root: root.to_path_buf(),
+	    namespace: String::from("default"),
+	    identities: Arc::new(vec![]),
+	    http: Fetcher::new(root.join("cache/http"), None, 5, 1024).unwrap(),
+	    git: Arc::new(Repositories::new(root.join("cache/git"))),
+	    state_dir: root.to_path_buf(),
+	    adopt_accounts: true,
+	}
+    }
+
+    async fn reconcile(konfigset: &api::KonfigSet, ctx: &Context) -> Vec<ResourceResult> {
+	let parsed = Registry::default().parse(konfigset, ctx).await;
+
+	match Plan::new(konfigset, parsed) {
+	    Ok(plan) => plan.apply(),
+	    Err(rejected) => rejected,
+	}
+    }
+
+    #[tokio::test]
+    async fn reconcile_into_a_root_directory() {
+	let root = sandbox("reconcile");
+	let ctx = context(&root);
+
+	/* the account is owned by whoever runs the tests, so no privileges are needed */
+	let meta = fs::metadata(&root).unwrap();
+	let konfigset: api::KonfigSet = serde_json::from_value(serde_json::json!({
+	    "apiVersion": "runfc.br/v1alpha",
+	    "kind": "KonfigSet",
+	    "metadata": { "name": "sandbox", "namespace": "default" },
+	    "spec": {
+		"configurations": {
+		    "files": [{ "source": "static://", "destination": "/etc/motd", "content": "hello\n", "mode": 0o640 }],
+		    "sysctls": [{ "name": "net.ipv4.ip_forward", "value": "1" }],
+		    "groups": [{ "name": "deploy", "gid": meta.gid() }],
+		    "users": [{ "name": "deploy", "uid": meta.uid(), "gid": meta.gid(), "home": "/srv/deploy" }],
+		},
+	    },
+	})).unwrap();
+
+	let results = reconcile(&konfigset, &ctx).await;
+	assert_eq!(results.len(), 4);
+	for result in &results {
+	    assert!(matches!(result.outcome, Outcome::Changed(_)), "{}: {:?}", result.id, result.outcome);
+	}
+
+	assert_eq!(fs::read_to_string(root.join("etc/motd")).unwrap(), "hello\n");
+	assert_eq!(fs::metadata(root.join("etc/motd")).unwrap().mode() & 0o7777, 0o640);
+	assert_eq!(fs::read_to_string(root.join("proc/sys/net/ipv4/ip_forward")).unwrap(), "1\n");
+	assert!(fs::read_to_string(root.join("etc/passwd")).unwrap().starts_with(&format!("deploy:x:{}:{}:", meta.uid(), meta.gid())));
+	assert!(root.join("srv/deploy").is_dir());
+
+	/* the second time around there is nothing left to do */
+	for result in reconcile(&konfigset, &ctx).await {
+	    assert_eq!(result.outcome, Outcome::Unchanged, "{}", result.id);
+	}
+
+	fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuse_to_escape_the_root_directory() {
+	let root = sandbox("escape");
+	let ctx = context(&root);
+
+	/* an account konfigd didn't create, whose home was never validated */
+	let passwd = "legacy:x:4242:4242::/../../tmp/legacy:/bin/sh\n";
+	fs::write(root.join("etc/passwd"), passwd).unwrap();
+
+	let konfigset: api::KonfigSet = serde_json::from_value(serde_json::json!({
+	    "apiVersion": "runfc.br/v1alpha",
+	    "kind": "KonfigSet",
+	    "metadata": { "name": "escape", "namespace": "default" },
+	    "spec": {
+		"configurations": {
+		    "files": [{ "source": "static://", "destination": "/../motd", "content": "hello\n" }],
+		    "users": [
+			{ "name": "intruder", "home": "/home/../../intruder" },
+			{ "name": "legacy", "authorizedKeys": { "keys": ["ssh-ed25519 AAAA legacy@laptop"] } },
+		    ],
+		},
+	    },
+	})).unwrap();
+
+	for result in reconcile(&konfigset, &ctx).await {
+	    assert!(!result.is_applied(), "{}: {:?}", result.id, result.outcome);
+	}
+	assert!(!root.join("../motd").exists());
+	assert_eq!(fs::read_to_string(root.join("etc/passwd")).unwrap(), passwd);
+	assert!(!Path::new("/tmp/legacy/.ssh").exists());
+
+	fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn adopt_existing_accounts_only_when_allowed() {
+	let root = sandbox("adopt");
+	let mut ctx = context(&root);
+	ctx.adopt_accounts = false;
+
+	let meta = fs::metadata(&root).unwrap();
+	fs::write(root.join("etc/passwd"), "ubuntu:x:4242:4242::/home/ubuntu:/bin/sh
+").unwrap();
+	fs::write(root.join("etc/group"), "ubuntu:x:4242:\nlegacy:x:4343:\n").unwrap();
+
+	let konfigset: api::KonfigSet = serde_json::from_value(serde_json::json!({
+	    "apiVersion": "runfc.br/v1alpha",
+	    "kind": "KonfigSet",
+	    "metadata": { "name": "adopt", "namespace": "default" },
+	    "spec": {
+		"configurations": {
+		    "groups": [{ "name": "legacy", "ensure": "absent" }, { "name": "deploy", "gid": meta.gid() }],
+		    "users": [
+			{ "name": "ubuntu", "authorizedKeys": { "keys": ["ssh-ed25519 AAAA ops@laptop"] } },
+			{ "name": "deploy", "uid": meta.uid(), "gid": meta.gid(), "home": "/srv/deploy",
+			  "authorizedKeys": { "keys": ["ssh-ed25519 AAAA deploy@laptop"] } },
+		    ],
+		},
+	    },
+	})).unwrap();
+
+	let outcomes = |results: Vec<ResourceResult>| -> Vec<(String, bool)> {
+	    let mut outcomes: Vec<(String, bool)> = results.iter().map(|result| (result.id.to_string(), result.is_applied())).collect();
+	    outcomes.sort();
+	    outcomes
+	};
+	let expected = vec![
+	    (String::from("authorizedkeys:deploy"), true),
+	    (String::from("authorizedkeys:ubuntu"), false),
+	    (String::from("group:deploy"), true),
+	    (String::from("group:legacy"), false),
+	    (String::from("user:deploy"), true),
+	    (String::from("user:ubuntu"), false),
+	];
+
+	/* the accounts we created remain ours the next time around */
+	assert_eq!(outcomes(reconcile(&konfigset, &ctx).await), expected);
+	assert_eq!(outcomes(reconcile(&konfigset, &ctx).await), expected);
+	assert!(fs::read_to_string(root.join("etc/group")).unwrap().contains("legacy:"));
+	assert!(!root.join("home/ubuntu/.ssh").exists());
+
+	ctx.adopt_accounts = true;
+	let results = reconcile(&konfigset, &ctx).await;
+	assert!(results.iter().all(|result| result.is_applied()), "{:?}", results);
+	assert!(root.join("home/ubuntu/.ssh/authorized_keys").exists());
+	assert!(!fs::read_to_string(root.join("etc/group")).unwrap().contains("legacy:"));
+
+	fs::remove_dir_all(&root).unwrap();
+    }
+}
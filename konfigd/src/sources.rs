@@ -0,0 +1,238 @@
+/*
+ * sources - where the content of the managed resources comes from: inline
+ * in the KonfigSet (static://), from objects in the cluster
+ * (k8s://configmap, k8s://secret), downloaded (http://, https://) or from a
+ * git repository (git+...).
+ */
+use crate::errors::Error;
+use crate::provider::Context;
+use konfig_api as api;
+
+use k8s_openapi::api::core::v1::ConfigMap as KubeConfigMap;
+use k8s_openapi::api::core::v1::Secret as KubeSecret;
+use kube::Api as KubeApi;
+
+/* bytes, nothing assumes the content of a file is text */
+pub struct Blob {
+    pub content: Vec<u8>,
+
+    /* decrypted from sealed content, not even its digest may be shown */
+    pub sealed: bool,
+
+    /* e.g: a script committed to git with mode 100755 */
+    pub executable: bool,
+}
+
+impl Blob {
+
+    pub fn new(content: Vec<u8>) -> Self {
+	Self{ content, sealed: false, executable: false }
+    }
+}
+
+pub enum Content {
+    File(Blob),
+
+    /* the files of a directory, by their path relative to it */
+    Tree(Vec<(String, Blob)>),
+}
+
+/*
+ * Read content from file's .content key, or .contentBase64 for binary
+ * content, if it has defined, otherwise returns an empty content.
+ *
+ * for example:
+ *
+ *   kind: KonfigSet
+ *   metadata: [ ... ]
+ *   spec:
+ *     configuration:
+ *      files:
+ *       - source: static://
+ *         [ ... ]
+ *         content: |
+ *           This is the file content that we expecting.
+ *
+ */
+fn read_static_content(file: api::KonfigFile) -> Result<Vec<u8>, Error> {
+    match file.static_content() {
+	Ok(content) => Ok(content),
+	Err((field, err)) => {
+	    let errmsg = format!("KonfigFile {}: {} {}", file.destination, field, err);
+	    Err(Error::KonfigError(errmsg))
+	}
+    }
+}
+
+
+/* the key is looked up in .data, then in .binaryData */
+async fn read_configmap_key(ctx: &Context, namespace: &str, name: &str, key: &str) -> Result<Vec<u8>, Error> {
+    let configmaps: KubeApi<KubeConfigMap> = KubeApi::namespaced(ctx.kube_client.clone(), namespace);
+
+    let configmap = match configmaps.get(name).await {
+	Ok(configmap) => configmap,
+	Err(_) => {
+	    let errmsg = format!("Unable to find Configmap with name: {}/{}", namespace, name);
+	    return Err(Error::KonfigError(errmsg));
+	}
+    };
+
+    if let Some(content) = configmap.data.as_ref().and_then(|data| data.get(key)) {
+	return Ok(content.as_bytes().to_vec());
+    }
+    match configmap.binary_data.as_ref().and_then(|data| data.get(key)) {
+	Some(content) => Ok(content.0.clone()),
+	None => {
+	    let errmsg = format!("The configmap '{}/{}' does not contain '{}' inside its data nor binaryData", namespace, name, key);
+	    Err(Error::KonfigError(errmsg))
+	}
+    }
+}
+
+async fn read_secret_key(ctx: &Context, namespace: &str, name: &str, key: &str) -> Result<Vec<u8>, Error> {
+    let secrets: KubeApi<KubeSecret> = KubeApi::namespaced(ctx.kube_client.clone(), namespace);
+
+    let secret = match secrets.get(name).await {
+	Ok(secret) => secret,
+	Err(_) => {
+	    let errmsg = format!("Unable to find Secret with name: {}/{}", namespace, name);
+	    return Err(Error::KonfigError(errmsg));
+	}
+    };
+
+    let content = match secret.data.as_ref().and_then(|data| data.get(key)) {
+	Some(content) => content,
+	None => {
+	    let errmsg = format!("The secret '{}/{}' does not contain '{}' inside its data", namespace, name, key);
+	    return Err(Error::KonfigError(errmsg));
+	}
+    };
+
+    Ok(content.0.clone())
+}
+
+/*
+ * Collect the keys for a user's authorized_keys, from the static .keys list
+ * and, when defined, from a configmap or secret.
+ *
+ * for example:
+ *
+ *   users:
+ *     - name: alice
+ *       authorizedKeys:
+ *         keys:
+ *           - ssh-ed25519 AAAA... alice@laptop
+ *         source: k8s://secret/alice-keys
+ *         key: authorized_keys
+ *
+ */
+pub async fn authorized_keys_from(keys: &api::KonfigAuthorizedKeys, ctx: &Context) -> Result<Vec<String>, Error> {
+    let mut authorized: Vec<String> = keys.keys.clone().unwrap_or_default();
+
+    let source = match keys.content_source() {
+	Ok(Some(source)) => source,
+	Ok(None) => return Ok(authorized),
+	Err((field, err)) => {
+	    let errmsg = format!("authorizedKeys.{} {}", field, err);
+	    return Err(Error::KonfigError(errmsg));
+	}
+    };
+
+    let (content, key) = match &source {
+	api::KeysSource::ConfigMap{ namespace, name, key } => {
+	    let namespace = namespace.clone().unwrap_or(ctx.namespace.clone());
+	    (read_configmap_key(ctx, &namespace, name, key).await?, key)
+	},
+	api::KeysSource::Secret{ namespace, name, key } => {
+	    let namespace = namespace.clone().unwrap_or(ctx.namespace.clone());
+	    (read_secret_key(ctx, &namespace, name, key).await?, key)
+	},
+    };
+    let content = match String::from_utf8(content) {
+	Ok(content) => content,
+	Err(_) => {
+	    let errmsg = format!("The authorized keys of {} ({}) are not valid UTF-8", source, key);
+	    return Err(Error::KonfigError(errmsg));
+	}
+    };
+    authorized.extend(content.lines().map(String::from));
+
+    Ok(authorized)
+}
+
+/*
+ * Sealed content (see api::sealed) is decrypted here, right before being
+ * applied: it must never be logged nor written to any status.  Sealed
+ * content is text, binary content is never sealed.  Each file of a git
+ * tree is unsealed on its own.
+ */
+fn unseal(blob: Blob, ctx: &Context) -> Result<Blob, Error> {
+    match std::str::from_utf8(&blob.content) {
+	Ok(text) if api::sealed::is_sealed(text) => {
+	    let content = api::sealed::unseal(text, &ctx.identities).map_err(Error::KonfigError)?;
+	    Ok(Blob{ content, sealed: true, ..blob })
+	},
+	_ => Ok(blob),
+    }
+}
+
+/*
+ * for example, the key `motd` of a ConfigMap in the namespace of the
+ * KonfigSet:
+ *
+ *   files:
+ *     - source: k8s://configmap/motd#motd
+ *       [ ... ]
+ *
+ * or with the key and namespace fields:
+ *
+ *     - source: k8s://configmap/motd
+ *       key: motd
+ *       namespace: default
+ *
+ * or downloaded, see http.rs:
+ *
+ *     - source: https://artifacts.example.com/ca-bundle.pem
+ *       checksum: sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+ *
+ * or a whole directory of a git repository, see git.rs:
+ *
+ *     - source: git+https://git.example.com/app.git//etc/app?ref=v1.2
+ *       destination: /etc/app
+ */
+pub async fn file_content_from(file: api::KonfigFile, ctx: &Context) -> Result<Content, Error> {
+    let source = match file.content_source() {
+	Ok(source) => source,
+	Err((field, err)) => {
+	    let errmsg = format!("KonfigFile {}: {} {}", file.destination, field, err);
+	    return Err(Error::KonfigError(errmsg));
+	}
+    };
+
+    let content = match source {
+	api::FileSource::Static => read_static_content(file)?,
+	api::FileSource::ConfigMap{ namespace, name, key } => {
+	    let namespace = namespace.unwrap_or(ctx.namespace.clone());
+	    read_configmap_key(ctx, &namespace, &name, &key).await?
+	},
+	api::FileSource::Http{ url, sha256 } => {
+	    ctx.http.fetch(&url, sha256.as_deref()).await?
+	},
+	api::FileSource::Git{ repository, path, reference } => {
+	    return match ctx.git.read(&repository, &path, reference.as_deref()).await? {
+		Content::File(blob) => Ok(Content::File(unseal(blob, ctx)?)),
+		Content::Tree(files) => {
+		    let files = files.into_iter()
+			.map(|(path, blob)| match unseal(blob, ctx) {
+			    Ok(blob) => Ok((path, blob)),
+			    Err(err) => Err(Error::KonfigError(format!("{}: {}", path, err))),
+			})
+			.collect::<Result<Vec<(String, Blob)>, Error>>()?;
+		    Ok(Content::Tree(files))
+		},
+	    };
+	},
+    };
+
+    Ok(Content::File(unseal(Blob::new(content), ctx)?))
+}
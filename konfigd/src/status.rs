@@ -0,0 +1,100 @@
+/*
+ * status - writes the status of our KonfigNode.
+ *
+ * Every write of the status is also an event for our own controller, so it
+ * is only patched when something actually changed; the times of the last
+ * attempt and success alone are refreshed every REFRESH at most.
+ */
+use crate::errors::Error;
+use konfig_api as api;
+
+use chrono::DateTime;
+use chrono::Utc;
+use kube::Api as KubeApi;
+use kube::api::Patch as KubePatch;
+use kube::api::PatchParams as KubePatchParams;
+
+/* how stale lastAttempt may get when nothing else changes */
+const REFRESH: i64 = 600;
+
+/* at most this many errors are listed in failedReason */
+const MAX_REASONS: usize = 10;
+
+#[derive(Clone)]
+pub struct StatusWriter {
+    name: String,
+    knode_api: KubeApi<api::KonfigNode>,
+}
+
+fn is_stale(status: &api::KonfigNodeStatus) -> bool {
+    let last_attempt = status.last_attempt.as_deref().and_then(|at| DateTime::parse_from_rfc3339(at).ok());
+
+    match last_attempt {
+	Some(at) => (Utc::now() - at.with_timezone(&Utc)).num_seconds() >= REFRESH,
+	None => true,
+    }
+}
+
+/*
+ * The errors of a reconciliation as a single line, the first ones are
+ * usually the causes of the others.
+ */
+pub fn failed_reason(errors: &[String]) -> Option<String> {
+    match errors.len() {
+	0 => None,
+	n if n <= MAX_REASONS => Some(errors.join("; ")),
+	n => Some(format!("{}; and {} more", errors[..MAX_REASONS].join("; "), n - MAX_REASONS)),
+    }
+}
+
+/* a refused transition keeps the state, and fails the whole write */
+pub fn set_state(status: &mut api::KonfigNodeStatus, state: api::KonfigNodeState) -> Result<(), String> {
+    status.transition(state).map_err(|err| format!("not changing my state, {}", err))
+}
+
+/* adds or replaces the conditions, the other ones are kept */
+pub fn set_conditions(status: &mut api::KonfigNodeStatus, conditions: Vec<api::Condition>) {
+    let mut current = status.conditions.clone().unwrap_or_default();
+
+    for condition in conditions {
+	api::condition::set(&mut current, condition);
+    }
+    status.conditions = Some(current);
+}
+
+impl StatusWriter {
+
+    pub fn new(knode_api: KubeApi<api::KonfigNode>, name: &str) -> Self {
+	Self{
+	    name: name.to_string(),
+	    knode_api,
+	}
+    }
+
+    /*
+     * Applies `update` to the current status, returns whether it had to be
+     * patched.  Nothing is written when `update` fails.
+     */
+    pub async fn write<F: FnOnce(&mut api::KonfigNodeStatus) -> Result<(), String>>(&self, update: F) -> Result<bool, Error> {
+	let me = match self.knode_api.get_opt(&self.name).await? {
+	    Some(me) => me,
+	    None => return Ok(false),
+	};
+	let current = me.status.clone().unwrap_or_else(api::KonfigNodeStatus::default);
+
+	let mut status = current.clone();
+	update(&mut status).map_err(Error::KonfigError)?;
+
+	let mut unchanged = status.clone();
+	unchanged.last_attempt = current.last_attempt.clone();
+	unchanged.last_success = current.last_success.clone();
+	if unchanged == current && (unchanged == status || !is_stale(&current)) {
+	    return Ok(false);
+	}
+
+	status.last_updated = Some(api::konfignode::timestamp());
+	let patch = serde_json::json!({ "status": status });
+	self.knode_api.patch_status(&self.name, &KubePatchParams::default(), &KubePatch::Merge(&patch)).await?;
+	Ok(true)
+    }
+}
@@ -0,0 +1,119 @@
+/*
+ * sysctl - kernel parameters exposed in /proc/sys, resolved under the
+ * konfigd root directory.
+ */
+use crate::errors::Error;
+use crate::local::rooted;
+use crate::provider::Context;
+use crate::provider::Parsed;
+use crate::provider::Resource;
+use crate::provider::ResourceId;
+use crate::provider::ResourceProvider;
+use konfig_api as api;
+
+use futures::future::BoxFuture;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+pub const KIND: &str = api::konfigset::kind::SYSCTL;
+
+#[derive(Debug)]
+pub struct Sysctl {
+    root: PathBuf,
+    name: String,
+    value: String,
+}
+
+/* sysctl(8) compares values ignoring how the fields are separated */
+fn normalize(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+impl Sysctl {
+
+    pub fn new(root: &Path, name: &str, value: &str) -> Self {
+	Self{
+	    root: root.to_path_buf(),
+	    name: name.to_string(),
+	    value: value.to_string(),
+	}
+    }
+
+    /* the sysctl name is checked the same way the admission webhook does */
+    pub fn path(&self) -> Result<PathBuf, Error> {
+	match api::validation::sysctl_path(&self.name) {
+	    Ok(relative) => Ok(rooted(&self.root, "/proc/sys")?.join(relative)),
+	    Err(err) => Err(Error::KonfigError(err)),
+	}
+    }
+
+    /* the current value, None when it does not exist under an alternate root */
+    fn current(&self) -> Result<Option<String>, Error> {
+	let path = self.path()?;
+
+	match fs::read_to_string(&path) {
+	    Ok(current) => Ok(Some(normalize(&current))),
+	    Err(err) if err.kind() == std::io::ErrorKind::NotFound && self.root != Path::new("/") => Ok(None),
+	    Err(err) => {
+		let errmsg = format!("Unable to read sysctl {} from {}: {}", self.name, path.display(), err);
+		Err(Error::KonfigError(errmsg))
+	    }
+	}
+    }
+}
+
+impl Resource for Sysctl {
+
+    fn id(&self) -> ResourceId {
+	ResourceId::new(KIND, &self.name)
+    }
+
+    fn check(&self) -> Result<bool, Error> {
+	Ok(self.current()? != Some(normalize(&self.value)))
+    }
+
+    fn describe_diff(&self) -> String {
+	match self.current() {
+	    Ok(Some(current)) => format!("{}: {} -> {}", self.name, current, normalize(&self.value)),
+	    _ => format!("{}: set to {}", self.name, normalize(&self.value)),
+	}
+    }
+
+    fn apply(&self) -> Result<(), Error> {
+	let path = self.path()?;
+
+	/*
+	 * Entries in a real /proc/sys cannot be created, but under an
+	 * alternate root they are just regular files.
+	 */
+	if self.root != Path::new("/") {
+	    if let Some(parent) = path.parent() {
+		fs::create_dir_all(parent)?;
+	    }
+	}
+
+	if let Err(err) = fs::write(&path, format!("{}\n", self.value)) {
+	    let errmsg = format!("Unable to write sysctl {} into {}: {}", self.name, path.display(), err);
+	    return Err(Error::KonfigError(errmsg));
+	}
+	Ok(())
+    }
+}
+
+pub struct SysctlProvider;
+
+impl ResourceProvider for SysctlProvider {
+
+    fn kind(&self) -> &'static str {
+	KIND
+    }
+
+    fn parse<'a>(&'a self, configs: &'a api::konfigset::Configuration, ctx: &'a Context) -> BoxFuture<'a, Vec<Parsed>> {
+	let parsed: Vec<Parsed> = configs.sysctls.iter().flatten()
+	    .map(|sysctl| -> Parsed { Ok(Box::new(Sysctl::new(&ctx.root, &sysctl.name, &sysctl.value))) })
+	    .collect();
+
+	Box::pin(futures::future::ready(parsed))
+    }
+}
@@ -0,0 +1,22 @@
+/*
+ * cordon - `konfigm cordon` and `konfigm uncordon`, pause and resume the
+ * configuration changes of a node (see KonfigNodeSpec.paused).
+ */
+use konfig_api as api;
+
+use kube::Api as KubeApi;
+use kube::Error as KubeError;
+use kube::api::Patch as KubePatch;
+use kube::api::PatchParams as KubePatchParams;
+
+/* uncordoning removes the field, as if it was never paused */
+pub async fn set_paused(knode_api: &KubeApi<api::KonfigNode>, name: &str, paused: bool) -> Result<(), KubeError> {
+    let value = match paused {
+	true => serde_json::Value::Bool(true),
+	false => serde_json::Value::Null,
+    };
+
+    let patch = serde_json::json!({ "spec": { "paused": value } });
+    knode_api.patch(name, &KubePatchParams::default(), &KubePatch::Merge(&patch)).await?;
+    Ok(())
+}
@@ -0,0 +1,169 @@
+/*
+ * leader - Lease based leader election, so several konfigm replicas can run
+ * while a single one (the holder of the Lease) patches KonfigNodes.
+ *
+ * Same rules as client-go: the holder renews the Lease every retry period
+ * and steps down when it couldn't renew it within the renew deadline, the
+ * others take it over once it wasn't renewed for the lease duration.
+ */
+use chrono::Duration as ChronoDuration;
+use chrono::Utc;
+use k8s_openapi::api::coordination::v1::Lease;
+use k8s_openapi::api::coordination::v1::LeaseSpec;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use kube::Api as KubeApi;
+use kube::Client as KubeClient;
+use kube::Error as KubeError;
+use kube::api::ObjectMeta;
+use kube::api::PostParams as KubePostParams;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::watch;
+
+pub struct LeaderElector {
+    lease_api: KubeApi<Lease>,
+    lease_name: String,
+
+    /* who we are in holderIdentity, must be unique among the replicas */
+    identity: String,
+
+    lease_duration: Duration,
+    renew_deadline: Duration,
+    retry_period: Duration,
+}
+
+impl LeaderElector {
+
+    pub fn new(kube_client: KubeClient, namespace: &str, lease_name: &str, identity: &str,
+	       lease_duration: u64, renew_deadline: u64, retry_period: u64) -> Self {
+	Self{
+	    lease_api: KubeApi::namespaced(kube_client, namespace),
+	    lease_name: lease_name.to_string(),
+	    identity: identity.to_string(),
+	    lease_duration: Duration::from_secs(lease_duration),
+	    renew_deadline: Duration::from_secs(renew_deadline),
+	    retry_period: Duration::from_secs(retry_period),
+	}
+    }
+
+    /*
+     * Keeps trying to acquire (then renew) the Lease, publishing whether we
+     * are the leader on `leading`.
+     */
+    pub async fn run(&self, leading: watch::Sender<bool>) {
+	let mut renewed = Instant::now();
+
+	loop {
+	    let is_leader = *leading.borrow();
+
+	    match self.try_acquire_or_renew().await {
+		Ok(true) => {
+		    renewed = Instant::now();
+		    if !is_leader {
+			log::info!("{} is now the leader", self.identity);
+			leading.send_replace(true);
+		    }
+		},
+		Ok(false) => {
+		    if is_leader {
+			log::warn!("{} lost the leadership", self.identity);
+			leading.send_replace(false);
+		    }
+		},
+		Err(err) => {
+		    log::warn!("Unable to acquire or renew the Lease {}: {:?}", self.lease_name, err);
+		    if is_leader && renewed.elapsed() > self.renew_deadline {
+			log::warn!("{} couldn't renew the Lease within {:?}, stepping down", self.identity, self.renew_deadline);
+			leading.send_replace(false);
+		    }
+		},
+	    }
+	    tokio::time::sleep(self.retry_period).await;
+	}
+    }
+
+    async fn try_acquire_or_renew(&self) -> Result<bool, KubeError> {
+	let now = Utc::now();
+	let duration = self.lease_duration.as_secs() as i32;
+
+	let lease = match self.lease_api.get_opt(&self.lease_name).await? {
+	    Some(lease) => lease,
+	    None => {
+		let lease = Lease{
+		    metadata: ObjectMeta{ name: Some(self.lease_name.clone()), ..ObjectMeta::default() },
+		    spec: Some(LeaseSpec{
+			holder_identity: Some(self.identity.clone()),
+			lease_duration_seconds: Some(duration),
+			acquire_time: Some(MicroTime(now)),
+			renew_time: Some(MicroTime(now)),
+			lease_transitions: Some(0),
+			..LeaseSpec::default()
+		    }),
+		};
+		return match self.lease_api.create(&KubePostParams::default(), &lease).await {
+		    Ok(_) => Ok(true),
+		    Err(KubeError::Api(resp)) if resp.code == 409 => Ok(false),
+		    Err(err) => Err(err),
+		};
+	    }
+	};
+
+	let mut spec = lease.spec.clone().unwrap_or_default();
+	let holder = spec.holder_identity.clone().unwrap_or_default();
+	let expires = spec.renew_time.clone().map(|renew_time| {
+	    renew_time.0 + ChronoDuration::seconds(spec.lease_duration_seconds.unwrap_or(duration) as i64)
+	});
+
+	if holder != self.identity {
+	    /* an empty holder means the previous leader released it */
+	    let expired = holder.is_empty() || expires.is_none_or(|expires| expires < now);
+	    if !expired {
+		return Ok(false);
+	    }
+	    spec.holder_identity = Some(self.identity.clone());
+	    spec.acquire_time = Some(MicroTime(now));
+	    spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+	}
+	spec.lease_duration_seconds = Some(duration);
+	spec.renew_time = Some(MicroTime(now));
+
+	/* the resourceVersion makes sure nobody took it over in the meantime */
+	let mut new_lease = lease.clone();
+	new_lease.spec = Some(spec);
+	match self.lease_api.replace(&self.lease_name, &KubePostParams::default(), &new_lease).await {
+	    Ok(_) => Ok(true),
+	    Err(KubeError::Api(resp)) if resp.code == 409 => Ok(false),
+	    Err(err) => Err(err),
+	}
+    }
+
+    /*
+     * Gives the Lease away when stopping, so another replica takes over
+     * right away instead of waiting for it to expire.
+     */
+    pub async fn release(&self) {
+	let lease = match self.lease_api.get_opt(&self.lease_name).await {
+	    Ok(Some(lease)) => lease,
+	    Ok(None) => return,
+	    Err(err) => {
+		log::warn!("Unable to release the Lease {}: {:?}", self.lease_name, err);
+		return;
+	    }
+	};
+
+	let mut spec = lease.spec.clone().unwrap_or_default();
+	if spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+	    return;
+	}
+	spec.holder_identity = None;
+	spec.lease_duration_seconds = Some(1);
+	spec.renew_time = Some(MicroTime(Utc::now()));
+
+	let mut new_lease = lease.clone();
+	new_lease.spec = Some(spec);
+	match self.lease_api.replace(&self.lease_name, &KubePostParams::default(), &new_lease).await {
+	    Ok(_) => log::info!("{} released the leadership", self.identity),
+	    Err(err) => log::warn!("Unable to release the Lease {}: {:?}", self.lease_name, err),
+	}
+    }
+}
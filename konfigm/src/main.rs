@@ -1,28 +1,268 @@
+mod cordon;
+mod leader;
 mod manager;
+mod nodehealth;
+mod registration;
+mod seal;
+mod selector;
+mod sign;
+mod validate;
+mod webhook;
+use leader::LeaderElector;
 use manager::KonfigManager;
+use nodehealth::NodeHealth;
+use registration::Registrar;
 
 use clap::Parser;
+use clap::Subcommand;
+use gethostname::gethostname;
+use konfig_api as api;
+use kube::Api as KubeApi;
 use kube::Client as KubeClient;
 use kube::runtime::watcher as kube_watcher;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
+use tokio::signal::unix::SignalKind;
+use tokio::signal::unix::signal;
+use tokio::sync::watch;
 
 /// Konfigm - Konfig Manager (k8s operator) that manages the fleet of KonfigNodes (knodes)
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
-struct Args {}
+struct Args {
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Namespace of the KonfigNodes heartbeat Leases (must match konfigd's) and of the leader election Lease
+    #[arg(long, default_value = "default")]
+    lease_namespace: String,
+
+    /// Seconds without heartbeat before a KonfigNode is marked as unknown
+    #[arg(long, default_value_t = 120)]
+    node_timeout: u64,
+
+    /// Seconds a KonfigNode stays unknown or stopped before being deleted (default: never)
+    #[arg(long)]
+    node_retention: Option<u64>,
+
+    /// Name of the Lease held by the leader among the konfigm replicas
+    #[arg(long, default_value = "konfigm-leader")]
+    leader_lease: String,
+
+    /// Identity of this replica in the leader election (default: system hostname)
+    #[arg(long)]
+    identity: Option<String>,
+
+    /// Seconds the other replicas wait before taking over a leadership that isn't renewed
+    #[arg(long, default_value_t = 15)]
+    leader_lease_duration: u64,
+
+    /// Seconds the leader keeps trying to renew the Lease before stepping down
+    #[arg(long, default_value_t = 10)]
+    leader_renew_deadline: u64,
+
+    /// Seconds between attempts to acquire or renew the Lease
+    #[arg(long, default_value_t = 2)]
+    leader_retry_period: u64,
+
+    /// Serves the validating admission webhook on this address (e.g: 0.0.0.0:8443)
+    #[arg(long, requires_all = ["webhook_cert", "webhook_key"])]
+    webhook_addr: Option<SocketAddr>,
+
+    /// PEM certificate (chain) of the admission webhook
+    #[arg(long)]
+    webhook_cert: Option<PathBuf>,
+
+    /// PEM private key of the admission webhook
+    #[arg(long)]
+    webhook_key: Option<PathBuf>,
+
+    /// Namespace of the ServiceAccounts created for the registered KonfigNodes
+    #[arg(long, default_value = "default")]
+    node_namespace: String,
+
+    /// Seconds the KonfigNode tokens are valid for, they are renewed after two thirds
+    #[arg(long, default_value_t = 3600)]
+    node_token_ttl: u64,
+
+    /// Approves every KonfigNodeRegistration, instead of waiting for `konfigm approve`
+    #[arg(long)]
+    auto_approve: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+
+    /// Validates the KonfigSets and KonfigNodes of a YAML file, without a cluster
+    Validate {
+	#[arg(short, long)]
+	file: PathBuf,
+    },
+
+    /// Signs the KonfigSets of a YAML file with an ed25519 PKCS#8 PEM key, printing the signed objects
+    Sign {
+	#[arg(short, long)]
+	file: PathBuf,
+
+	#[arg(short, long)]
+	key: PathBuf,
+    },
+
+    /// Seals a file (or the standard input) for age recipients, printing the content to use in a KonfigFile
+    Seal {
+	/// age recipient (age1...), can be repeated
+	#[arg(short, long)]
+	recipient: Vec<String>,
+
+	/// file with one age recipient per line
+	#[arg(short = 'R', long)]
+	recipients_file: Option<PathBuf>,
+
+	#[arg(short, long)]
+	file: Option<PathBuf>,
+    },
+
+    /// Approves the registration of a KonfigNode, which then gets its own credentials
+    Approve {
+	name: String,
+    },
+
+    /// Pauses a KonfigNode: its konfigd stops applying changes but keeps reporting the drift
+    Cordon {
+	name: String,
+    },
+
+    /// Resumes the configuration changes of a cordoned KonfigNode
+    Uncordon {
+	name: String,
+    },
+}
+
+/*
+ * Only serves the webhook when configured, otherwise never returns so it
+ * doesn't end the select! in main.
+ */
+async fn webhook(args: &Args) {
+    let (addr, cert, key) = match (&args.webhook_addr, &args.webhook_cert, &args.webhook_key) {
+	(Some(addr), Some(cert), Some(key)) => (addr, cert, key),
+	_ => return futures::future::pending().await,
+    };
+
+    if let Err(err) = webhook::serve(*addr, cert, key).await {
+	log::error!("The admission webhook stopped: {}", err);
+    }
+}
+
+/*
+ * Runs the controllers for as long as we are the leader, then waits to be
+ * elected again.
+ */
+async fn lead(mgr: &KonfigManager, health: &NodeHealth, registrar: &Registrar, mut leading: watch::Receiver<bool>) {
+    loop {
+	if leading.wait_for(|is_leader| *is_leader).await.is_err() {
+	    return;
+	}
+
+	tokio::select! {
+	    _ = mgr.controller() => {},
+	    _ = mgr.group_controller() => {},
+	    _ = health.run() => {},
+	    _ = registrar.controller() => {},
+	    _ = leading.wait_for(|is_leader| !*is_leader) => {},
+	}
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), kube_watcher::Error> {
     env_logger::init();
-    let _args = Args::parse();
+    let args = Args::parse();
+
+    match &args.command {
+	Some(Command::Validate{ file }) => match validate::file(file) {
+	    Ok(true) => exit(0),
+	    Ok(false) => exit(1),
+	    Err(err) => {
+		eprintln!("{}", err);
+		exit(2);
+	    }
+	},
+	Some(Command::Sign{ file, key }) => match sign::file(file, key) {
+	    Ok(signed) => {
+		print!("{}", signed);
+		exit(0);
+	    },
+	    Err(err) => {
+		eprintln!("{}", err);
+		exit(1);
+	    }
+	},
+	Some(Command::Seal{ recipient, recipients_file, file }) => match seal::content(file, recipient, recipients_file) {
+	    Ok(sealed) => {
+		print!("{}", sealed);
+		exit(0);
+	    },
+	    Err(err) => {
+		eprintln!("{}", err);
+		exit(1);
+	    }
+	},
+	Some(Command::Approve{ name }) => {
+	    let registration_api: KubeApi<api::KonfigNodeRegistration> = KubeApi::all(KubeClient::try_default().await.unwrap());
+	    match registration::approve(&registration_api, name, "Approved", "approved with konfigm approve").await {
+		Ok(()) => exit(0),
+		Err(err) => {
+		    eprintln!("Unable to approve {}: {}", name, err);
+		    exit(1);
+		}
+	    }
+	},
+	Some(Command::Cordon{ name }) | Some(Command::Uncordon{ name }) => {
+	    let paused = matches!(args.command, Some(Command::Cordon{ .. }));
+	    let knode_api: KubeApi<api::KonfigNode> = KubeApi::all(KubeClient::try_default().await.unwrap());
+	    match cordon::set_paused(&knode_api, name, paused).await {
+		Ok(()) => exit(0),
+		Err(err) => {
+		    eprintln!("Unable to {} {}: {}", if paused { "cordon" } else { "uncordon" }, name, err);
+		    exit(1);
+		}
+	    }
+	},
+	None => {},
+    }
+
+    let identity = match args.identity.clone() {
+	Some(identity) => identity,
+	None => gethostname().to_string_lossy().to_string(),
+    };
 
     let kube_client = KubeClient::try_default().await.unwrap();
     let mgr = KonfigManager::new(kube_client.clone());
+    let health = NodeHealth::new(kube_client.clone(), &args.lease_namespace, args.node_timeout, args.node_retention);
+    let registrar = Registrar::new(kube_client.clone(), &args.node_namespace, &args.lease_namespace, args.node_token_ttl, args.auto_approve);
+    let elector = LeaderElector::new(kube_client.clone(), &args.lease_namespace, &args.leader_lease, &identity,
+				     args.leader_lease_duration, args.leader_renew_deadline, args.leader_retry_period);
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("unable to handle SIGTERM");
+    let (leading_tx, leading_rx) = watch::channel(false);
     tokio::select! {
+	// followers keep their reflectors warm
 	_ = mgr.watcher() => {},
-	_ = mgr.controller() => {},
+	_ = elector.run(leading_tx) => {},
+	_ = lead(&mgr, &health, &registrar, leading_rx) => {},
+
+	// every replica answers the admission requests
+	_ = webhook(&args) => {},
 
-	// handle CTRL^C as gracefully as we can.
+	// handle CTRL^C and SIGTERM as gracefully as we can.
 	_ = tokio::signal::ctrl_c() => {},
+	_ = sigterm.recv() => {},
     }
+
+    /* the controllers are stopped by now, hand over to another replica */
+    elector.release().await;
+
     Ok(())
 }
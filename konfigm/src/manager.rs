@@ -1,3 +1,4 @@
+use crate::selector;
 use konfig_api as api;
 
 use futures::StreamExt;
@@ -5,27 +6,44 @@ use kube::Api as KubeApi;
 use kube::Client as KubeClient;
 use kube::Error as KubeError;
 use kube::api::ListParams as KubeListParams;
-use kube::api::ObjectMeta;
 use kube::api::Patch as KubePatch;
 use kube::api::PatchParams as KubePatchParams;
 use kube::runtime::WatchStreamExt;
 use kube::runtime::controller::Action as KubeAction;
 use kube::runtime::controller::Controller as KubeController;
 use kube::runtime::reflector as kube_reflector;
+use kube::runtime::reflector::ObjectRef as KubeObjectRef;
 use kube::runtime::watcher as kube_watcher;
 use kube::runtime::watcher::Config as KubeWatcherConfig;
 use log;
+use std::collections::BTreeMap;
 use std::future::Future;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
+use tokio::sync::broadcast;
+
+/* KonfigSet updates the leader may fall behind on, before it resyncs from the store */
+const UPDATES: usize = 1024;
 
 /*
  * KonfigManager implementation
+ *
+ * Every replica watches the KonfigSets (see watcher()) into the konfigsets
+ * store, the leader's controller is fed by that store and its updates, so
+ * becoming the leader doesn't need to list everything again.
  */
 #[derive(Clone)]
 pub struct KonfigManager {
+    client: KubeClient,
     konfig_api: KubeApi<api::KonfigSet>,
     knode_api: KubeApi<api::KonfigNode>,
+    group_api: KubeApi<api::KonfigNodeGroup>,
+    policy_api: KubeApi<api::KonfigPolicy>,
+
+    konfigsets: kube_reflector::Store<api::KonfigSet>,
+    writer: Arc<Mutex<Option<kube_reflector::store::Writer<api::KonfigSet>>>>,
+    updates: broadcast::Sender<Arc<api::KonfigSet>>,
 }
 
 #[derive(Clone)]
@@ -34,53 +52,79 @@ pub struct KonfigManagerCtx {
 }
 
 async fn reconcile(konfigset: Arc<api::KonfigSet>, ctx: Arc<KonfigManagerCtx>) -> Result<KubeAction, KubeError> {
+    let kfg_name = konfigset.metadata.name.clone().unwrap();
+    let kfg_namespace = konfigset.metadata.namespace.clone().unwrap();
 
-    if let Some(selectors) = &konfigset.spec.selectors {
-	let label_selectors = selectors.join(",");
-	let params = KubeListParams::default()
-	    .match_any()
-	    .timeout(60)
-	    .labels(&label_selectors);
-
-	for knode in ctx.manager.knode_api.list(&params).await? {
-	    let mut configsets_list: Vec<api::ConfigsetRef> = vec![];
-	    let knode_name = knode.metadata.name.clone().unwrap();
-	    let kfg_name = konfigset.metadata.name.clone().unwrap();
-	    let kfg_namespace = konfigset.metadata.namespace.clone().unwrap();
-	    log::debug!("Config {}/{} should be applied to KonfigNode {:?}", kfg_namespace, kfg_name, knode_name);
-
-
-	    /* Skip if it's kfg is already assigned to knode */
-	    if let Some(mut knode_configs) = knode.spec.configsets {
-		if knode_configs.clone().into_iter().any(|kfg| kfg.references(&kfg_name, &kfg_namespace)) {
-		    log::debug!("Skipping KonfigSet '{}/{}' as it already assigned to knode '{}'", kfg_name, kfg_namespace, knode_name);
-		    return Ok(KubeAction::requeue(Duration::from_secs(30)));
-		}
+    let groups = match &konfigset.spec.node_groups {
+	Some(_) => ctx.manager.group_api.list(&KubeListParams::default()).await?.items,
+	None => vec![],
+    };
 
-		// add all current configs reference for the update
-		configsets_list.append(&mut knode_configs);
-	    }
-	    configsets_list.push(api::ConfigsetRef::new(&kfg_name, &kfg_namespace));
+    /* a KonfigSet managing anything its namespace isn't allowed to is not assigned at all */
+    let all_policies = ctx.manager.policy_api.list(&KubeListParams::default()).await?.items;
+    let policies = api::konfigpolicy::applicable(&all_policies, &kfg_namespace);
+    let mut violations = match &konfigset.spec.configurations {
+	Some(configs) => api::konfigpolicy::violations(&policies, &kfg_namespace, configs),
+	None => vec![],
+    };
 
-	    log::debug!("New list of ConfigsetsRef is about to be: {:?}", configsets_list);
+    for knode in ctx.manager.knode_api.list(&KubeListParams::default().timeout(60)).await? {
+	let knode_name = knode.metadata.name.clone().unwrap();
+	let labels = knode.metadata.labels.clone().unwrap_or_default();
 
-	    let mut metadata = ObjectMeta::default();
-	    metadata.name = Some(knode_name.clone());
-	    let with_new_konfigset = api::KonfigNode{
-		metadata: metadata,
-		spec: api::konfignode::KonfigNodeSpec{
-		    configsets: Some(configsets_list),
-		},
-		status: None,
-	    };
-	    let params = KubePatchParams::apply(&knode_name);
-	    let patch = KubePatch::Merge(&with_new_konfigset);
-	    if let Err(err) = ctx.manager.knode_api.patch(&knode_name, &params, &patch).await {
-		log::error!("Unable to assigned konfigset {}/{} to konfig node '{}', got error: {:?}",
-			    kfg_namespace, kfg_name, knode_name, err);
-		return Err(err);
+	let selected = match selector::selects(&konfigset.spec, &knode_name, &labels, &groups) {
+	    Ok(selected) => selected,
+	    Err(err) => {
+		/* nothing is assigned nor removed until the selector is fixed */
+		log::error!("KonfigSet {}/{} has an invalid selector: {}", kfg_namespace, kfg_name, err);
+		return Ok(KubeAction::requeue(Duration::from_secs(60)));
 	    }
+	};
+
+	let allowed = match api::konfigpolicy::allows_node(&policies, &labels) {
+	    Ok(allowed) => allowed,
+	    Err(err) => {
+		log::error!("A KonfigPolicy of namespace {} has an invalid node selector: {}", kfg_namespace, err);
+		false
+	    }
+	};
+	if selected && !allowed {
+	    violations.push(format!("KonfigNode {} is not allowed by any KonfigPolicy of namespace {}", knode_name, kfg_namespace));
+	}
+	let selected = selected && allowed && violations.is_empty();
+
+	let mut configsets_list = knode.konfigsets();
+	let assigned = configsets_list.iter().any(|kfg| kfg.references(&kfg_name, &kfg_namespace));
+
+	match (selected, assigned) {
+	    (true, false) => {
+		log::debug!("Config {}/{} should be applied to KonfigNode {:?}", kfg_namespace, kfg_name, knode_name);
+		configsets_list.push(api::ConfigsetRef::new(&kfg_name, &kfg_namespace));
+	    },
+	    (false, true) => {
+		log::debug!("Config {}/{} is no longer selecting KonfigNode {:?}", kfg_namespace, kfg_name, knode_name);
+		configsets_list.retain(|kfg| !kfg.references(&kfg_name, &kfg_namespace));
+	    },
+	    _ => continue,
 	}
+
+	log::debug!("New list of ConfigsetsRef is about to be: {:?}", configsets_list);
+	if let Err(err) = ctx.manager.patch_configsets(&knode_name, configsets_list).await {
+	    log::error!("Unable to update konfigset {}/{} on konfig node '{}', got error: {:?}",
+			kfg_namespace, kfg_name, knode_name, err);
+	    return Err(err);
+	}
+    }
+
+    let policy = if violations.is_empty() {
+	api::Condition::new(api::konfigpolicy::POLICY_VIOLATION, false, "Allowed", "allowed by the KonfigPolicies of its namespace")
+    } else {
+	log::warn!("KonfigSet {}/{} refused: {}", kfg_namespace, kfg_name, violations.join("; "));
+	api::Condition::new(api::konfigpolicy::POLICY_VIOLATION, true, "NotAllowed", &violations.join("; "))
+    };
+
+    if let Err(err) = ctx.manager.patch_konfigset_status(&konfigset, policy).await {
+	log::error!("Unable to update the status of KonfigSet {:?}: {:?}", konfigset.metadata.name, err);
     }
 
     Ok(KubeAction::requeue(Duration::from_secs(15)))
@@ -90,28 +134,114 @@ fn error_policy(_knode: Arc<api::KonfigSet>, _error: &KubeError, _ctx: Arc<Konfi
     KubeAction::requeue(Duration::from_secs(60))
 }
 
+/*
+ * Keeps the members of a KonfigNodeGroup in its status, KonfigSets
+ * targeting the group are reconciled whenever it changes.
+ */
+async fn group_reconcile(group: Arc<api::KonfigNodeGroup>, ctx: Arc<KonfigManagerCtx>) -> Result<KubeAction, KubeError> {
+    let group_name = group.metadata.name.clone().unwrap();
+
+    let mut members: Vec<String> = vec![];
+    for knode in ctx.manager.knode_api.list(&KubeListParams::default().timeout(60)).await? {
+	let knode_name = knode.metadata.name.clone().unwrap();
+	let labels = knode.metadata.labels.clone().unwrap_or_default();
+
+	match selector::group_selects(&group.spec, &knode_name, &labels) {
+	    Ok(true) => members.push(knode_name),
+	    Ok(false) => {},
+	    Err(err) => {
+		log::error!("KonfigNodeGroup {} has an invalid selector: {}", group_name, err);
+		return Ok(KubeAction::requeue(Duration::from_secs(60)));
+	    }
+	}
+    }
+    members.sort();
+    members.dedup();
+
+    if group.members() != members {
+	log::info!("KonfigNodeGroup {} members: {}", group_name, members.join(", "));
+
+	let mut new_group = (*group).clone();
+	new_group.status = Some(api::konfignodegroup::KonfigNodeGroupStatus{ members: Some(members) });
+	ctx.manager.group_api.patch_status(&group_name, &KubePatchParams::default(), &KubePatch::Merge(new_group)).await?;
+    }
+
+    Ok(KubeAction::requeue(Duration::from_secs(30)))
+}
+
+fn group_error_policy(_group: Arc<api::KonfigNodeGroup>, _error: &KubeError, _ctx: Arc<KonfigManagerCtx>) -> KubeAction {
+    KubeAction::requeue(Duration::from_secs(60))
+}
+
 impl KonfigManager {
 
+    /* keeps the konfigsets store up to date, on every replica */
     pub fn watcher(&self) -> impl Future<Output = ()> {
-	let (_reader, writer) = kube_reflector::store();
+	let writer = self.writer.lock().unwrap().take().expect("the KonfigSets are only watched once");
+	let updates = self.updates.clone();
 
 	let watcher = kube_watcher(self.konfig_api.clone(), KubeWatcherConfig::default());
 	kube_reflector::reflector(writer, watcher)
 	    .default_backoff()
 	    .applied_objects()
-	    .for_each(|obj| {
-		log::debug!("Received an update for {:?}", obj);
+	    .for_each(move |obj| {
+		if let Ok(konfigset) = obj {
+		    log::debug!("Received an update for KonfigSet {:?}", konfigset.metadata.name);
 
+		    /* nobody listens unless we are the leader */
+		    let _ = updates.send(Arc::new(konfigset));
+		}
 		futures::future::ready(())
 	    })
     }
 
+    /*
+     * Every KonfigSet of the store, then the ones updated since, or all of
+     * them again when the controller fell behind.
+     */
+    fn konfigset_updates(&self) -> impl futures::Stream<Item = Arc<api::KonfigSet>> + Send + 'static {
+	let receiver = self.updates.subscribe();
+	let store = self.konfigsets.clone();
+
+	let current = futures::stream::once(async move {
+	    if store.wait_until_ready().await.is_err() {
+		log::error!("The KonfigSets store was dropped");
+	    }
+	    store.state()
+	});
+
+	let store = self.konfigsets.clone();
+	let updates = futures::stream::unfold((receiver, store), |(mut receiver, store)| async move {
+	    match receiver.recv().await {
+		Ok(konfigset) => Some((vec![konfigset], (receiver, store))),
+		Err(broadcast::error::RecvError::Lagged(missed)) => {
+		    log::warn!("Missed {} KonfigSet updates, reconciling all of them", missed);
+		    Some((store.state(), (receiver, store)))
+		},
+		Err(broadcast::error::RecvError::Closed) => None,
+	    }
+	});
+
+	current.chain(updates).flat_map(futures::stream::iter)
+    }
+
     pub fn controller(&self) -> impl Future<Output = ()> {
 	let ctx = Arc::new(KonfigManagerCtx{
 	    manager: self.clone()
 	});
 
-	KubeController::new(self.konfig_api.clone(), KubeWatcherConfig::default())
+	let controller = KubeController::for_shared_stream(self.konfigset_updates(), self.konfigsets.clone());
+
+	/* re-evaluates the KonfigSets targeting a KonfigNodeGroup when it changes */
+	let konfigsets = controller.store();
+	controller
+	    .watches(self.group_api.clone(), KubeWatcherConfig::default(), move |group| {
+		let group_name = group.metadata.name.clone().unwrap_or_default();
+		konfigsets.state().into_iter()
+		    .filter(|konfigset| konfigset.spec.node_groups.iter().flatten().any(|name| *name == group_name))
+		    .map(|konfigset| KubeObjectRef::from_obj(&*konfigset))
+		    .collect::<Vec<_>>()
+	    })
 	    .run(reconcile, error_policy, ctx)
 	    .for_each(|reconcile| async move {
 		log::debug!("Reconciled finished");
@@ -122,10 +252,123 @@ impl KonfigManager {
 	    })
     }
 
+    pub fn group_controller(&self) -> impl Future<Output = ()> {
+	let ctx = Arc::new(KonfigManagerCtx{
+	    manager: self.clone()
+	});
+
+	KubeController::new(self.group_api.clone(), KubeWatcherConfig::default())
+	    .run(group_reconcile, group_error_policy, ctx)
+	    .for_each(|reconcile| async move {
+		if let Err(err) = reconcile {
+		    log::error!("Failed to reconcile KonfigNodeGroup with error {:?}", err);
+		}
+	    })
+    }
+
+    async fn patch_configsets(&self, knode_name: &str, configsets: Vec<api::ConfigsetRef>) -> Result<(), KubeError> {
+	/* only the configsets, so the rest of the spec (e.g: paused) is kept */
+	let with_konfigsets = serde_json::json!({ "spec": { "configsets": configsets } });
+	let params = KubePatchParams::apply(knode_name);
+	let patch = KubePatch::Merge(&with_konfigsets);
+	self.knode_api.patch(knode_name, &params, &patch).await?;
+
+	Ok(())
+    }
+
+    /*
+     * Counts the KonfigNodes the KonfigSet is assigned to and flags whether,
+     * on any of them, it manages the same resource as another KonfigSet with
+     * the same priority (konfigd refuses to apply both there), along with
+     * whether its KonfigPolicies allow it.
+     */
+    async fn patch_konfigset_status(&self, konfigset: &api::KonfigSet, policy: api::Condition) -> Result<(), KubeError> {
+	let kfg_name = konfigset.metadata.name.clone().unwrap();
+	let kfg_namespace = konfigset.metadata.namespace.clone().unwrap();
+	let me = api::ConfigsetRef::new(&kfg_name, &kfg_namespace);
+
+	let mut references = 0;
+	let mut conflicts: Vec<String> = vec![];
+	let mut known: BTreeMap<api::ConfigsetRef, api::KonfigSet> = BTreeMap::new();
+	known.insert(me.clone(), konfigset.clone());
+
+	for knode in self.knode_api.list(&KubeListParams::default()).await? {
+	    let assigned = knode.konfigsets();
+	    if !assigned.contains(&me) {
+		continue;
+	    }
+
+	    /* nodes which stopped sending heartbeats don't count as applying it */
+	    let state = knode.status.clone().and_then(|status| status.state);
+	    if state != Some(api::KonfigNodeState::Unknown) {
+		references += 1;
+	    }
+
+	    for kref in &assigned {
+		if !known.contains_key(kref) {
+		    let (name, namespace) = kref.names();
+		    let konfigset_api: KubeApi<api::KonfigSet> = KubeApi::namespaced(self.client.clone(), &namespace);
+		    if let Some(other) = konfigset_api.get_opt(&name).await? {
+			known.insert(kref.clone(), other);
+		    }
+		}
+	    }
+
+	    let konfigsets: Vec<(api::ConfigsetRef, &api::KonfigSet)> = assigned.iter()
+		.filter_map(|kref| known.get(kref).map(|other| (kref.clone(), other)))
+		.collect();
+	    for conflict in api::conflict::resolve(&konfigsets).conflicts_of(&me) {
+		let conflict = format!("{} on {}", conflict, knode.metadata.name.clone().unwrap_or_default());
+		if !conflicts.contains(&conflict) {
+		    conflicts.push(conflict);
+		}
+	    }
+	}
+
+	let condition = if conflicts.is_empty() {
+	    api::Condition::new(api::condition::CONFLICT, false, "NoConflict", "no resource is managed by another KonfigSet")
+	} else {
+	    log::warn!("KonfigSet {} conflicts: {}", me, conflicts.join("; "));
+	    api::Condition::new(api::condition::CONFLICT, true, "SamePriority", &conflicts.join("; "))
+	};
+
+	let paused = if konfigset.is_paused() {
+	    api::Condition::new(api::condition::PAUSED, true, "Paused", "the rollout is halted, the nodes only report the drift")
+	} else {
+	    api::Condition::new(api::condition::PAUSED, false, "Active", "the nodes apply this KonfigSet")
+	};
+
+	let mut status = konfigset.status.clone().unwrap_or_default();
+	let mut conditions = status.conditions.clone().unwrap_or_default();
+	let changed = api::condition::set(&mut conditions, condition)
+	    | api::condition::set(&mut conditions, policy)
+	    | api::condition::set(&mut conditions, paused);
+	if !changed && status.references == Some(references) {
+	    return Ok(());
+	}
+	status.references = Some(references);
+	status.conditions = Some(conditions);
+
+	let mut new_konfigset = konfigset.clone();
+	new_konfigset.status = Some(status);
+	let konfigset_api: KubeApi<api::KonfigSet> = KubeApi::namespaced(self.client.clone(), &kfg_namespace);
+	konfigset_api.patch_status(&kfg_name, &KubePatchParams::default(), &KubePatch::Merge(new_konfigset)).await?;
+
+	Ok(())
+    }
+
     pub fn new(kube_client: KubeClient) -> Self {
+	let (konfigsets, writer) = kube_reflector::store();
+
 	Self{
+	    client: kube_client.clone(),
 	    konfig_api: KubeApi::all(kube_client.clone()),
 	    knode_api: KubeApi::all(kube_client.clone()),
+	    group_api: KubeApi::all(kube_client.clone()),
+	    policy_api: KubeApi::all(kube_client.clone()),
+	    konfigsets,
+	    writer: Arc::new(Mutex::new(Some(writer))),
+	    updates: broadcast::channel(UPDATES).0,
 	}
     }
 }
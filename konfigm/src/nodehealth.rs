@@ -0,0 +1,128 @@
+/*
+ * nodehealth - marks the KonfigNodes whose konfigd stopped renewing its
+ * heartbeat Lease as unknown, and optionally deletes the ones which stayed
+ * unknown for too long (e.g: decommissioned hosts).  It also lets the
+ * deletion of the KonfigNodes whose konfigd is leaving go ahead.
+ */
+use konfig_api as api;
+
+use chrono::DateTime;
+use chrono::Utc;
+use k8s_openapi::api::coordination::v1::Lease;
+use kube::Api as KubeApi;
+use kube::Client as KubeClient;
+use kube::Error as KubeError;
+use kube::api::DeleteParams as KubeDeleteParams;
+use kube::api::ListParams as KubeListParams;
+use kube::api::Patch as KubePatch;
+use kube::api::PatchParams as KubePatchParams;
+use std::time::Duration;
+
+pub struct NodeHealth {
+    knode_api: KubeApi<api::KonfigNode>,
+    lease_api: KubeApi<Lease>,
+
+    /* seconds without heartbeat before a node is unknown */
+    timeout: u64,
+
+    /* seconds a node stays unknown before being deleted, if ever */
+    retention: Option<u64>,
+}
+
+impl NodeHealth {
+
+    pub fn new(kube_client: KubeClient, lease_namespace: &str, timeout: u64, retention: Option<u64>) -> Self {
+	Self{
+	    knode_api: KubeApi::all(kube_client.clone()),
+	    lease_api: KubeApi::namespaced(kube_client.clone(), lease_namespace),
+	    timeout,
+	    retention,
+	}
+    }
+
+    pub async fn run(&self) {
+	let interval = Duration::from_secs((self.timeout / 4).max(1));
+
+	loop {
+	    if let Err(err) = self.check().await {
+		log::error!("Unable to check the KonfigNodes heartbeats: {:?}", err);
+	    }
+	    tokio::time::sleep(interval).await;
+	}
+    }
+
+    /*
+     * The last time the node was heard of, nodes which never renewed their
+     * Lease count from their creation.
+     */
+    async fn last_heartbeat(&self, knode: &api::KonfigNode) -> Result<Option<DateTime<Utc>>, KubeError> {
+	let name = knode.metadata.name.clone().unwrap_or_default();
+
+	let renewed = self.lease_api.get_opt(&api::konfignode::lease_name(&name)).await?
+	    .and_then(|lease| lease.spec)
+	    .and_then(|spec| spec.renew_time)
+	    .map(|renew_time| renew_time.0);
+	Ok(renewed.or(knode.metadata.creation_timestamp.clone().map(|created| created.0)))
+    }
+
+    /* removes the finalizer of konfigd, if any */
+    async fn release(&self, knode: &api::KonfigNode) -> Result<(), KubeError> {
+	let name = knode.metadata.name.clone().unwrap_or_default();
+	let finalizers = knode.metadata.finalizers.clone().unwrap_or_default();
+
+	if finalizers.iter().any(|finalizer| finalizer == api::konfignode::FINALIZER) {
+	    log::info!("Releasing KonfigNode {}", name);
+	    let finalizers: Vec<String> = finalizers.into_iter().filter(|finalizer| finalizer != api::konfignode::FINALIZER).collect();
+	    let patch = serde_json::json!({ "metadata": { "finalizers": finalizers } });
+	    self.knode_api.patch(&name, &KubePatchParams::default(), &KubePatch::Merge(&patch)).await?;
+	}
+	Ok(())
+    }
+
+    async fn check(&self) -> Result<(), KubeError> {
+	for knode in self.knode_api.list(&KubeListParams::default()).await? {
+	    let name = knode.metadata.name.clone().unwrap_or_default();
+	    let state = knode.status.clone().and_then(|status| status.state).unwrap_or(api::KonfigNodeState::Starting);
+
+	    /* bootstrapped nodes can't remove their finalizer, they only say they are leaving */
+	    if knode.metadata.deletion_timestamp.is_some() && state == api::KonfigNodeState::Leaving {
+		self.release(&knode).await?;
+		continue;
+	    }
+
+	    let silence = match self.last_heartbeat(&knode).await? {
+		Some(heard) => (Utc::now() - heard).num_seconds().max(0) as u64,
+		None => continue,
+	    };
+	    if silence <= self.timeout {
+		continue;
+	    }
+
+	    /* a stopped konfigd said goodbye, it's not unknown but may still be gone for good */
+	    if state != api::KonfigNodeState::Unknown && state != api::KonfigNodeState::Stopped {
+		log::warn!("KonfigNode {} didn't renew its Lease for {}s, marking it as unknown", name, silence);
+
+		let patch = serde_json::json!({
+		    "status": {
+			"state": api::KonfigNodeState::Unknown,
+			"synced": false,
+			"lastUpdated": api::konfignode::timestamp(),
+		    }
+		});
+		self.knode_api.patch_status(&name, &KubePatchParams::default(), &KubePatch::Merge(&patch)).await?;
+		continue;
+	    }
+
+	    if let Some(retention) = self.retention {
+		if silence > self.timeout + retention {
+		    log::warn!("KonfigNode {} is {} for longer than {}s, deleting it", name, state, retention);
+
+		    /* its konfigd isn't around to clean up */
+		    self.release(&knode).await?;
+		    self.knode_api.delete(&name, &KubeDeleteParams::default()).await?;
+		}
+	    }
+	}
+	Ok(())
+    }
+}
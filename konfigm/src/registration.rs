@@ -0,0 +1,447 @@
+/*
+ * registration - approves the KonfigNodeRegistrations and gives every
+ * approved node its own ServiceAccount, only allowed to read its KonfigNode
+ * and update its status, its heartbeat Lease and the KonfigSets (and the ConfigMaps and
+ * Secrets they read from) assigned to it.  Its token is sealed for the node
+ * and renewed before it expires.
+ *
+ * Every object created for a node is owned by its registration: deleting
+ * the registration revokes the node.  What a node reads only follows from
+ * the selection of konfigm, never from what the node may write.
+ */
+use crate::selector;
+
+use konfig_api as api;
+use konfig_api::KonfigNodeRegistration;
+
+use chrono::DateTime;
+use chrono::Utc;
+use futures::StreamExt;
+use k8s_openapi::api::authentication::v1::TokenRequest;
+use k8s_openapi::api::authentication::v1::TokenRequestSpec;
+use k8s_openapi::api::core::v1::ServiceAccount;
+use k8s_openapi::api::rbac::v1::ClusterRole;
+use k8s_openapi::api::rbac::v1::ClusterRoleBinding;
+use k8s_openapi::api::rbac::v1::PolicyRule;
+use k8s_openapi::api::rbac::v1::Role;
+use k8s_openapi::api::rbac::v1::RoleBinding;
+use k8s_openapi::api::rbac::v1::RoleRef;
+use k8s_openapi::api::rbac::v1::Subject;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use kube::Api as KubeApi;
+use kube::Client as KubeClient;
+use kube::Error as KubeError;
+use kube::Resource;
+use kube::api::DeleteParams as KubeDeleteParams;
+use kube::api::ListParams as KubeListParams;
+use kube::api::ObjectMeta;
+use kube::api::Patch as KubePatch;
+use kube::api::PatchParams as KubePatchParams;
+use kube::api::PostParams as KubePostParams;
+use kube::runtime::controller::Action as KubeAction;
+use kube::runtime::controller::Controller as KubeController;
+use kube::runtime::reflector::ObjectRef as KubeObjectRef;
+use kube::runtime::watcher::Config as KubeWatcherConfig;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MANAGER: &str = "konfigm";
+
+#[derive(Clone)]
+pub struct Registrar {
+    client: KubeClient,
+    registration_api: KubeApi<KonfigNodeRegistration>,
+    knode_api: KubeApi<api::KonfigNode>,
+    konfigset_api: KubeApi<api::KonfigSet>,
+    group_api: KubeApi<api::KonfigNodeGroup>,
+    policy_api: KubeApi<api::KonfigPolicy>,
+
+    /* where the ServiceAccounts of the nodes are created */
+    namespace: String,
+
+    /* where the nodes renew their heartbeat Lease */
+    lease_namespace: String,
+
+    /* seconds the node tokens are valid for, they are renewed after two thirds */
+    token_ttl: u64,
+
+    auto_approve: bool,
+}
+
+#[derive(Clone)]
+struct RegistrarCtx {
+    registrar: Registrar,
+}
+
+/* the objects a node reads, per namespace */
+#[derive(Default)]
+struct Grants {
+    konfigsets: BTreeSet<String>,
+    configmaps: BTreeSet<String>,
+    secrets: BTreeSet<String>,
+}
+
+fn rule(api_group: &str, resource: &str, verbs: &[&str], names: Option<&BTreeSet<String>>) -> PolicyRule {
+    PolicyRule{
+	api_groups: Some(vec![api_group.to_string()]),
+	resources: Some(vec![resource.to_string()]),
+	verbs: verbs.iter().map(|verb| verb.to_string()).collect(),
+	resource_names: names.map(|names| names.iter().cloned().collect()),
+	..PolicyRule::default()
+    }
+}
+
+/*
+ * Rules restricted to some names must not be created without any: an empty
+ * resourceNames grants every object.
+ */
+fn named_rule(api_group: &str, resource: &str, verbs: &[&str], names: &BTreeSet<String>) -> Option<PolicyRule> {
+    match names.is_empty() {
+	true => None,
+	false => Some(rule(api_group, resource, verbs, Some(names))),
+    }
+}
+
+fn names(name: &str) -> BTreeSet<String> {
+    BTreeSet::from([name.to_string()])
+}
+
+fn metadata(node: &str, name: &str, namespace: Option<&str>, owner: &OwnerReference) -> ObjectMeta {
+    ObjectMeta{
+	name: Some(name.to_string()),
+	namespace: namespace.map(String::from),
+	labels: Some(BTreeMap::from([(api::konfignoderegistration::NODE_LABEL.to_string(), node.to_string())])),
+	owner_references: Some(vec![owner.clone()]),
+	..ObjectMeta::default()
+    }
+}
+
+async fn reconcile(registration: Arc<KonfigNodeRegistration>, ctx: Arc<RegistrarCtx>) -> Result<KubeAction, KubeError> {
+    let registrar = &ctx.registrar;
+    let node = registration.metadata.name.clone().unwrap();
+
+    if !registration.is_approved() {
+	if !registrar.auto_approve {
+	    log::info!("KonfigNodeRegistration {} is waiting for approval (konfigm approve {})", node, node);
+	    return Ok(KubeAction::requeue(Duration::from_secs(300)));
+	}
+	/* a new registration must not take over an existing node */
+	if let Some(knode) = registrar.knode_api.get_opt(&node).await? {
+	    if !registrar.registered(&registration, &knode) {
+		log::error!("Not approving KonfigNodeRegistration {}, KonfigNode {} already exists (konfigm approve {} to take it over)", node, node, node);
+		return Ok(KubeAction::requeue(Duration::from_secs(300)));
+	    }
+	}
+	log::info!("Approving KonfigNodeRegistration {}", node);
+	approve(&registrar.registration_api, &node, "AutoApproved", "approved by konfigm --auto-approve").await?;
+	return Ok(KubeAction::await_change());
+    }
+
+    let owner = registration.controller_owner_ref(&()).unwrap();
+    registrar.ensure_knode(&registration).await?;
+    registrar.ensure_account(&node, &owner).await?;
+    registrar.ensure_roles(&node, &owner).await?;
+
+    let renew_in = registrar.ensure_token(&registration).await?;
+    Ok(KubeAction::requeue(renew_in.min(Duration::from_secs(300))))
+}
+
+fn error_policy(_registration: Arc<KonfigNodeRegistration>, _error: &KubeError, _ctx: Arc<RegistrarCtx>) -> KubeAction {
+    KubeAction::requeue(Duration::from_secs(60))
+}
+
+/* `konfigm approve` and --auto-approve */
+pub async fn approve(registration_api: &KubeApi<KonfigNodeRegistration>, name: &str, reason: &str, message: &str) -> Result<(), KubeError> {
+    let registration = registration_api.get(name).await?;
+
+    let mut status = registration.status.clone().unwrap_or_default();
+    let mut conditions = status.conditions.clone().unwrap_or_default();
+    if api::condition::set(&mut conditions, api::Condition::new(api::konfignoderegistration::APPROVED, true, reason, message)) {
+	status.conditions = Some(conditions);
+
+	let mut new_registration = registration.clone();
+	new_registration.status = Some(status);
+	registration_api.patch_status(name, &KubePatchParams::default(), &KubePatch::Merge(new_registration)).await?;
+    }
+    Ok(())
+}
+
+impl Registrar {
+
+    pub fn new(kube_client: KubeClient, namespace: &str, lease_namespace: &str, token_ttl: u64, auto_approve: bool) -> Self {
+	Self{
+	    client: kube_client.clone(),
+	    registration_api: KubeApi::all(kube_client.clone()),
+	    knode_api: KubeApi::all(kube_client.clone()),
+	    konfigset_api: KubeApi::all(kube_client.clone()),
+	    group_api: KubeApi::all(kube_client.clone()),
+	    policy_api: KubeApi::all(kube_client.clone()),
+	    namespace: namespace.to_string(),
+	    lease_namespace: lease_namespace.to_string(),
+	    token_ttl,
+	    auto_approve,
+	}
+    }
+
+    pub fn controller(&self) -> impl Future<Output = ()> {
+	let ctx = Arc::new(RegistrarCtx{
+	    registrar: self.clone(),
+	});
+
+	/* the grants follow the KonfigSets assigned to the node (and its labels) */
+	KubeController::new(self.registration_api.clone(), KubeWatcherConfig::default())
+	    .watches(self.knode_api.clone(), KubeWatcherConfig::default(), |knode| {
+		knode.metadata.name.clone().map(|name| KubeObjectRef::<KonfigNodeRegistration>::new(&name))
+	    })
+	    .run(reconcile, error_policy, ctx)
+	    .for_each(|reconcile| async move {
+		if let Err(err) = reconcile {
+		    log::error!("Failed to reconcile KonfigNodeRegistration with error {:?}", err);
+		}
+	    })
+    }
+
+    /* whether the KonfigNode was created for this registration */
+    fn registered(&self, registration: &KonfigNodeRegistration, knode: &api::KonfigNode) -> bool {
+	let owners = registration.metadata.owner_references.clone().unwrap_or_default();
+	owners.iter().any(|owner| Some(&owner.uid) == knode.metadata.uid.as_ref())
+    }
+
+    /*
+     * KonfigNodes can't be created by name, so the node's is created here,
+     * along with the finalizer the node can't add itself.  The registration
+     * then belongs to it: deleting the KonfigNode revokes the node.
+     */
+    async fn ensure_knode(&self, registration: &KonfigNodeRegistration) -> Result<(), KubeError> {
+	let node = registration.metadata.name.clone().unwrap();
+
+	let knode = match self.knode_api.get_opt(&node).await? {
+	    Some(knode) => knode,
+	    None => {
+		let mut knode = api::konfignode::new(&node, api::konfignode::default_labels(&node));
+		knode.metadata.finalizers = Some(vec![api::konfignode::FINALIZER.to_string()]);
+		self.knode_api.create(&KubePostParams::default(), &knode).await?
+	    }
+	};
+
+	let mut finalizers = knode.metadata.finalizers.clone().unwrap_or_default();
+	if knode.metadata.deletion_timestamp.is_none() && !finalizers.iter().any(|finalizer| finalizer == api::konfignode::FINALIZER) {
+	    finalizers.push(api::konfignode::FINALIZER.to_string());
+	    let patch = serde_json::json!({ "metadata": { "finalizers": finalizers } });
+	    self.knode_api.patch(&node, &KubePatchParams::default(), &KubePatch::Merge(&patch)).await?;
+	}
+
+	if !self.registered(registration, &knode) {
+	    let owner = knode.owner_ref(&()).unwrap();
+	    let patch = serde_json::json!({ "metadata": { "ownerReferences": [owner] } });
+	    self.registration_api.patch(&node, &KubePatchParams::default(), &KubePatch::Merge(&patch)).await?;
+	}
+	Ok(())
+    }
+
+    async fn apply<K>(&self, api: &KubeApi<K>, object: &K) -> Result<(), KubeError>
+    where K: Resource + Clone + serde::Serialize + serde::de::DeserializeOwned + std::fmt::Debug {
+	let name = object.meta().name.clone().unwrap_or_default();
+	api.patch(&name, &KubePatchParams::apply(MANAGER).force(), &KubePatch::Apply(object)).await?;
+	Ok(())
+    }
+
+    async fn ensure_account(&self, node: &str, owner: &OwnerReference) -> Result<(), KubeError> {
+	let name = api::konfignoderegistration::account_name(node);
+
+	let account = ServiceAccount{
+	    metadata: metadata(node, &name, Some(&self.namespace), owner),
+	    ..ServiceAccount::default()
+	};
+	self.apply(&KubeApi::namespaced(self.client.clone(), &self.namespace), &account).await
+    }
+
+    /*
+     * What the node reads in each namespace: the KonfigSets assigned to it
+     * and the ConfigMaps and Secrets these take content from.  The
+     * selection is evaluated here again, spec.configsets of the KonfigNode
+     * isn't trusted.
+     */
+    async fn grants(&self, node: &str) -> Result<BTreeMap<String, Grants>, KubeError> {
+	let mut grants: BTreeMap<String, Grants> = BTreeMap::new();
+
+	let knode = match self.knode_api.get_opt(node).await? {
+	    Some(knode) => knode,
+	    None => return Ok(grants),
+	};
+	let labels = knode.metadata.labels.clone().unwrap_or_default();
+	let groups = self.group_api.list(&KubeListParams::default()).await?.items;
+	let policies = self.policy_api.list(&KubeListParams::default()).await?.items;
+
+	for konfigset in self.konfigset_api.list(&KubeListParams::default()).await? {
+	    let name = konfigset.metadata.name.clone().unwrap_or_default();
+	    let namespace = konfigset.metadata.namespace.clone().unwrap_or_default();
+
+	    match selector::assigns(&konfigset, node, &labels, &groups, &policies) {
+		Ok(true) => {},
+		Ok(false) => continue,
+		Err(err) => {
+		    log::error!("Not granting KonfigSet {}/{} to KonfigNode {}: {}", namespace, name, node, err);
+		    continue;
+		}
+	    }
+	    grants.entry(namespace.clone()).or_default().konfigsets.insert(name.clone());
+
+	    let configs = match konfigset.spec.configurations {
+		Some(configs) => configs,
+		None => continue,
+	    };
+
+	    for file in configs.files.iter().flatten() {
+		if let Ok(api::FileSource::ConfigMap{ namespace: in_source, name: configmap, .. }) = file.content_source() {
+		    let namespace = in_source.unwrap_or(namespace.clone());
+		    grants.entry(namespace).or_default().configmaps.insert(configmap);
+		}
+	    }
+	    for keys in configs.users.iter().flatten().filter_map(|user| user.authorized_keys.as_ref()) {
+		match keys.content_source() {
+		    Ok(Some(api::KeysSource::ConfigMap{ namespace: in_source, name: configmap, .. })) => {
+			let namespace = in_source.unwrap_or(namespace.clone());
+			grants.entry(namespace).or_default().configmaps.insert(configmap);
+		    },
+		    Ok(Some(api::KeysSource::Secret{ namespace: in_source, name: secret, .. })) => {
+			let namespace = in_source.unwrap_or(namespace.clone());
+			grants.entry(namespace).or_default().secrets.insert(secret);
+		    },
+		    _ => {},
+		}
+	    }
+	}
+	Ok(grants)
+    }
+
+    async fn ensure_roles(&self, node: &str, owner: &OwnerReference) -> Result<(), KubeError> {
+	let name = api::konfignoderegistration::account_name(node);
+	let subject = Subject{
+	    kind: String::from("ServiceAccount"),
+	    name: name.clone(),
+	    namespace: Some(self.namespace.clone()),
+	    ..Subject::default()
+	};
+
+	/* its own cluster scoped objects */
+	let cluster_role = ClusterRole{
+	    metadata: metadata(node, &name, None, owner),
+	    rules: Some(vec![
+		rule("runfc.br", "konfignodes", &["get", "list", "watch", "delete"], Some(&names(node))),
+		rule("runfc.br", "konfignodes/status", &["get", "update", "patch"], Some(&names(node))),
+		rule("runfc.br", "konfignoderegistrations", &["get", "list", "watch"], Some(&names(node))),
+		rule("runfc.br", "konfigpolicies", &["get", "list", "watch"], None),
+	    ]),
+	    ..ClusterRole::default()
+	};
+	self.apply(&KubeApi::all(self.client.clone()), &cluster_role).await?;
+
+	let cluster_binding = ClusterRoleBinding{
+	    metadata: metadata(node, &name, None, owner),
+	    role_ref: RoleRef{ api_group: String::from("rbac.authorization.k8s.io"), kind: String::from("ClusterRole"), name: name.clone() },
+	    subjects: Some(vec![subject.clone()]),
+	};
+	self.apply(&KubeApi::all(self.client.clone()), &cluster_binding).await?;
+
+	/* the namespaced ones, a single Role per namespace */
+	let mut rules: BTreeMap<String, Vec<PolicyRule>> = BTreeMap::new();
+	rules.entry(self.lease_namespace.clone()).or_default().extend([
+	    /* creation can't be restricted by name */
+	    rule("coordination.k8s.io", "leases", &["create"], None),
+	    rule("coordination.k8s.io", "leases", &["get", "update", "patch"], Some(&names(&api::konfignode::lease_name(node)))),
+	]);
+	for (namespace, grants) in self.grants(node).await? {
+	    let namespaced = rules.entry(namespace).or_default();
+	    namespaced.extend(named_rule("runfc.br", "konfigsets", &["get"], &grants.konfigsets));
+	    namespaced.extend(named_rule("", "configmaps", &["get"], &grants.configmaps));
+	    namespaced.extend(named_rule("", "secrets", &["get"], &grants.secrets));
+	}
+
+	for (namespace, rules) in &rules {
+	    let role = Role{
+		metadata: metadata(node, &name, Some(namespace), owner),
+		rules: Some(rules.clone()),
+	    };
+	    self.apply(&KubeApi::namespaced(self.client.clone(), namespace), &role).await?;
+
+	    let binding = RoleBinding{
+		metadata: metadata(node, &name, Some(namespace), owner),
+		role_ref: RoleRef{ api_group: String::from("rbac.authorization.k8s.io"), kind: String::from("Role"), name: name.clone() },
+		subjects: Some(vec![subject.clone()]),
+	    };
+	    self.apply(&KubeApi::namespaced(self.client.clone(), namespace), &binding).await?;
+	}
+
+	/* and revokes the namespaces it doesn't read from anymore */
+	let selector = KubeListParams::default().labels(&format!("{}={}", api::konfignoderegistration::NODE_LABEL, node));
+	let role_api: KubeApi<Role> = KubeApi::all(self.client.clone());
+	for role in role_api.list(&selector).await? {
+	    let namespace = role.metadata.namespace.clone().unwrap_or_default();
+	    if !rules.contains_key(&namespace) {
+		log::info!("Revoking the access of KonfigNode {} to namespace {}", node, namespace);
+		let binding_api: KubeApi<RoleBinding> = KubeApi::namespaced(self.client.clone(), &namespace);
+		binding_api.delete(&name, &KubeDeleteParams::default()).await?;
+		KubeApi::<Role>::namespaced(self.client.clone(), &namespace).delete(&name, &KubeDeleteParams::default()).await?;
+	    }
+	}
+	Ok(())
+    }
+
+    /*
+     * Issues a new token when there is none or once two thirds of its
+     * lifetime passed, returns when it should be renewed.
+     */
+    async fn ensure_token(&self, registration: &KonfigNodeRegistration) -> Result<Duration, KubeError> {
+	let node = registration.metadata.name.clone().unwrap();
+	let status = registration.status.clone().unwrap_or_default();
+	let renew_before = Duration::from_secs(self.token_ttl / 3);
+
+	let expiration = status.expiration.as_deref().and_then(|expiration| DateTime::parse_from_rfc3339(expiration).ok());
+	if let (Some(_), Some(expiration)) = (&status.token, expiration) {
+	    let remaining = (expiration.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or_default();
+	    if remaining > renew_before {
+		return Ok(remaining - renew_before);
+	    }
+	}
+
+	let account_api: KubeApi<ServiceAccount> = KubeApi::namespaced(self.client.clone(), &self.namespace);
+	let request = TokenRequest{
+	    spec: TokenRequestSpec{
+		expiration_seconds: Some(self.token_ttl as i64),
+		..TokenRequestSpec::default()
+	    },
+	    ..TokenRequest::default()
+	};
+	let issued = account_api.create_token_request(&api::konfignoderegistration::account_name(&node), &KubePostParams::default(), &request).await?;
+	let issued = match issued.status {
+	    Some(issued) => issued,
+	    None => {
+		log::error!("No token was issued for KonfigNode {}", node);
+		return Ok(Duration::from_secs(60));
+	    }
+	};
+
+	let token = match api::sealed::seal(issued.token.as_bytes(), std::slice::from_ref(&registration.spec.public_key)) {
+	    Ok(token) => token,
+	    Err(err) => {
+		log::error!("Unable to seal the token of KonfigNode {}: {}", node, err);
+		return Ok(Duration::from_secs(60));
+	    }
+	};
+	log::info!("Issued a token for KonfigNode {}, expiring at {}", node, issued.expiration_timestamp.0.to_rfc3339());
+
+	let mut new_registration = registration.clone();
+	new_registration.status = Some(api::konfignoderegistration::KonfigNodeRegistrationStatus{
+	    token: Some(token),
+	    expiration: Some(issued.expiration_timestamp.0.to_rfc3339()),
+	    ..status
+	});
+	self.registration_api.patch_status(&node, &KubePatchParams::default(), &KubePatch::Merge(new_registration)).await?;
+
+	Ok(Duration::from_secs(self.token_ttl) - renew_before)
+    }
+}
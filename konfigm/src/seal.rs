@@ -0,0 +1,46 @@
+/*
+ * seal - `konfigm seal`, encrypts some content for a set of age recipients,
+ * to be pasted as the content of a KonfigFile (see api::sealed).
+ */
+use konfig_api as api;
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+/* recipients files: one age1... per line, like age(1) -R */
+fn read_recipients(path: &Path) -> Result<Vec<String>, String> {
+    let content = fs::read_to_string(path).map_err(|err| format!("Unable to read the recipients {}: {}", path.display(), err))?;
+
+    Ok(content.lines()
+       .map(|line| line.trim())
+       .filter(|line| !line.is_empty() && !line.starts_with('#'))
+       .map(String::from)
+       .collect())
+}
+
+/*
+ * Seals the content of `file`, or of the standard input when not given, for
+ * every recipient.
+ */
+pub fn content(file: &Option<PathBuf>, recipients: &[String], recipients_file: &Option<PathBuf>) -> Result<String, String> {
+    let mut recipients = recipients.to_vec();
+    if let Some(path) = recipients_file {
+	recipients.extend(read_recipients(path)?);
+    }
+    if recipients.is_empty() {
+	return Err(String::from("at least one recipient is required"));
+    }
+
+    let plaintext = match file {
+	Some(path) => fs::read(path).map_err(|err| format!("Unable to read {}: {}", path.display(), err))?,
+	None => {
+	    let mut plaintext = vec![];
+	    std::io::stdin().read_to_end(&mut plaintext).map_err(|err| format!("Unable to read the standard input: {}", err))?;
+	    plaintext
+	}
+    };
+
+    api::sealed::seal(&plaintext, &recipients)
+}
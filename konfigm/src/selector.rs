@@ -0,0 +1,237 @@
+/*
+ * selector - decides which KonfigNodes a KonfigSet is assigned to.
+ *
+ * A KonfigNode is selected when it is named in spec.nodes, or is a member of
+ * one of spec.nodeGroups, or matches spec.nodeSelector, or matches every one
+ * of the (deprecated) spec.selectors; and it is not named in
+ * spec.excludeNodes.  A KonfigSet without any of these selects nothing.
+ */
+use konfig_api as api;
+use konfig_api::labels::label_selector_matches;
+
+use std::collections::BTreeMap;
+
+pub type Labels = BTreeMap<String, String>;
+
+/*
+ * The deprecated spec.selectors, where each entry is a comma separated list
+ * of "key=value", "key==value", "key!=value", "key" or "!key" and all of
+ * them must match (as they used to be passed to the API server).
+ */
+pub fn selectors_match(selectors: &[String], labels: &Labels) -> Result<bool, String> {
+    for term in selectors.iter().flat_map(|selector| selector.split(',')) {
+	let term = term.trim();
+	if term.is_empty() {
+	    continue;
+	}
+
+	let matched = if let Some((key, value)) = term.split_once("!=") {
+	    labels.get(key.trim()).map(|current| current.as_str()) != Some(value.trim())
+	} else if let Some((key, value)) = term.split_once("==").or_else(|| term.split_once('=')) {
+	    labels.get(key.trim()).map(|current| current.as_str()) == Some(value.trim())
+	} else if let Some(key) = term.strip_prefix('!') {
+	    !labels.contains_key(key.trim())
+	} else if term.contains(' ') {
+	    return Err(format!("unsupported selector: {}", term));
+	} else {
+	    labels.contains_key(term)
+	};
+
+	if !matched {
+	    return Ok(false);
+	}
+    }
+    Ok(true)
+}
+
+fn named(list: &Option<Vec<String>>, name: &str) -> bool {
+    list.iter().flatten().any(|node| node == name)
+}
+
+/*
+ * Whether the KonfigNode `name` is a member of the KonfigNodeGroup, either
+ * statically or by its labels.
+ */
+pub fn group_selects(spec: &api::konfignodegroup::KonfigNodeGroupSpec, name: &str, labels: &Labels) -> Result<bool, String> {
+    if named(&spec.nodes, name) {
+	return Ok(true);
+    }
+
+    match &spec.node_selector {
+	Some(selector) => label_selector_matches(selector, labels),
+	None => Ok(false),
+    }
+}
+
+/*
+ * Whether the KonfigSet must be assigned to the KonfigNode `name`, `groups`
+ * being every KonfigNodeGroup (unknown group names select nothing).
+ */
+pub fn selects(spec: &api::konfigset::KonfigSetSpec, name: &str, labels: &Labels, groups: &[api::KonfigNodeGroup]) -> Result<bool, String> {
+    if named(&spec.exclude_nodes, name) {
+	return Ok(false);
+    }
+    if named(&spec.nodes, name) {
+	return Ok(true);
+    }
+
+    for group in groups.iter().filter(|group| named(&spec.node_groups, &group.metadata.name.clone().unwrap_or_default())) {
+	if group_selects(&group.spec, name, labels)? {
+	    return Ok(true);
+	}
+    }
+
+    if let Some(selector) = &spec.node_selector {
+	if label_selector_matches(selector, labels)? {
+	    return Ok(true);
+	}
+    }
+
+    match &spec.selectors {
+	Some(selectors) if !selectors.is_empty() => selectors_match(selectors, labels),
+	_ => Ok(false),
+    }
+}
+
+/*
+ * Whether the KonfigSet is assigned to the KonfigNode `name`: it selects the
+ * node and `policies` (every KonfigPolicy) allow both what it manages and
+ * the node, as decided by manager.rs.
+ */
+pub fn assigns(konfigset: &api::KonfigSet, name: &str, labels: &Labels, groups: &[api::KonfigNodeGroup], policies: &[api::KonfigPolicy]) -> Result<bool, String> {
+    let namespace = konfigset.metadata.namespace.clone().unwrap_or_default();
+    let policies = api::konfigpolicy::applicable(policies, &namespace);
+
+    if let Some(configs) = &konfigset.spec.configurations {
+	if !api::konfigpolicy::violations(&policies, &namespace, configs).is_empty() {
+	    return Ok(false);
+	}
+    }
+    Ok(selects(&konfigset.spec, name, labels, groups)? && api::konfigpolicy::allows_node(&policies, labels)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(spec: serde_json::Value) -> api::konfigset::KonfigSetSpec {
+	serde_json::from_value(spec).unwrap()
+    }
+
+    fn group(name: &str, spec: serde_json::Value) -> api::KonfigNodeGroup {
+	serde_json::from_value(serde_json::json!({
+	    "apiVersion": "runfc.br/v1alpha",
+	    "kind": "KonfigNodeGroup",
+	    "metadata": { "name": name },
+	    "spec": spec,
+	})).unwrap()
+    }
+
+    fn labels(labels: &[(&str, &str)]) -> Labels {
+	labels.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn selects_nothing_by_default() {
+	assert_eq!(selects(&spec(serde_json::json!({})), "pi", &labels(&[("zone", "a")]), &[]), Ok(false));
+	assert_eq!(selects(&spec(serde_json::json!({ "selectors": [] })), "pi", &labels(&[]), &[]), Ok(false));
+    }
+
+    #[test]
+    fn nodes_and_exclusions() {
+	let named = spec(serde_json::json!({ "nodes": ["pi"] }));
+	assert_eq!(selects(&named, "pi", &labels(&[]), &[]), Ok(true));
+	assert_eq!(selects(&named, "rock", &labels(&[]), &[]), Ok(false));
+
+	/* excludeNodes wins over every other way of selecting a node */
+	let excluded = spec(serde_json::json!({
+	    "nodes": ["pi"],
+	    "nodeGroups": ["edge"],
+	    "nodeSelector": { "matchLabels": { "zone": "a" } },
+	    "selectors": ["zone=a"],
+	    "excludeNodes": ["pi"],
+	}));
+	let groups = [group("edge", serde_json::json!({ "nodes": ["pi"] }))];
+	assert_eq!(selects(&excluded, "pi", &labels(&[("zone", "a")]), &groups), Ok(false));
+	assert_eq!(selects(&excluded, "rock", &labels(&[("zone", "a")]), &groups), Ok(true));
+    }
+
+    #[test]
+    fn node_groups() {
+	let groups = [
+	    group("edge", serde_json::json!({ "nodes": ["pi"] })),
+	    group("gpu", serde_json::json!({ "nodeSelector": { "matchLabels": { "gpu": "true" } } })),
+	];
+
+	let edge = spec(serde_json::json!({ "nodeGroups": ["edge"] }));
+	assert_eq!(selects(&edge, "pi", &labels(&[]), &groups), Ok(true));
+	assert_eq!(selects(&edge, "rock", &labels(&[("gpu", "true")]), &groups), Ok(false));
+
+	let gpu = spec(serde_json::json!({ "nodeGroups": ["gpu", "unknown"] }));
+	assert_eq!(selects(&gpu, "rock", &labels(&[("gpu", "true")]), &groups), Ok(true));
+	assert_eq!(selects(&gpu, "pi", &labels(&[]), &groups), Ok(false));
+
+	/* unknown groups select nothing */
+	let unknown = spec(serde_json::json!({ "nodeGroups": ["unknown"] }));
+	assert_eq!(selects(&unknown, "pi", &labels(&[]), &groups), Ok(false));
+    }
+
+    #[test]
+    fn node_selector() {
+	let zone = spec(serde_json::json!({
+	    "nodeSelector": { "matchExpressions": [{ "key": "zone", "operator": "In", "values": ["a", "b"] }] },
+	}));
+	assert_eq!(selects(&zone, "pi", &labels(&[("zone", "b")]), &[]), Ok(true));
+	assert_eq!(selects(&zone, "pi", &labels(&[("zone", "c")]), &[]), Ok(false));
+
+	let invalid = spec(serde_json::json!({
+	    "nodeSelector": { "matchExpressions": [{ "key": "zone", "operator": "Near" }] },
+	}));
+	assert!(selects(&invalid, "pi", &labels(&[("zone", "a")]), &[]).is_err());
+    }
+
+    #[test]
+    fn deprecated_selectors_must_all_match() {
+	let both = spec(serde_json::json!({ "selectors": ["zone=a", "gpu"] }));
+	assert_eq!(selects(&both, "pi", &labels(&[("zone", "a"), ("gpu", "true")]), &[]), Ok(true));
+	assert_eq!(selects(&both, "pi", &labels(&[("zone", "a")]), &[]), Ok(false));
+	assert_eq!(selects(&both, "pi", &labels(&[("gpu", "true")]), &[]), Ok(false));
+
+	let terms = spec(serde_json::json!({ "selectors": ["zone==a, rack!=r1, !legacy"] }));
+	assert_eq!(selects(&terms, "pi", &labels(&[("zone", "a"), ("rack", "r2")]), &[]), Ok(true));
+	assert_eq!(selects(&terms, "pi", &labels(&[("zone", "a"), ("rack", "r1")]), &[]), Ok(false));
+	assert_eq!(selects(&terms, "pi", &labels(&[("zone", "a"), ("legacy", "")]), &[]), Ok(false));
+
+	assert!(selects(&spec(serde_json::json!({ "selectors": ["zone in (a)"] })), "pi", &labels(&[]), &[]).is_err());
+    }
+
+    #[test]
+    fn any_way_of_selecting_is_enough() {
+	/* a node failing the deprecated selectors is still selected by name */
+	let mixed = spec(serde_json::json!({ "nodes": ["pi"], "selectors": ["zone=b"] }));
+	assert_eq!(selects(&mixed, "pi", &labels(&[("zone", "a")]), &[]), Ok(true));
+	assert_eq!(selects(&mixed, "rock", &labels(&[("zone", "a")]), &[]), Ok(false));
+	assert_eq!(selects(&mixed, "rock", &labels(&[("zone", "b")]), &[]), Ok(true));
+    }
+
+    #[test]
+    fn assigned_only_when_allowed() {
+	let konfigset: api::KonfigSet = serde_json::from_value(serde_json::json!({
+	    "apiVersion": "runfc.br/v1alpha",
+	    "kind": "KonfigSet",
+	    "metadata": { "name": "motd", "namespace": "team-a" },
+	    "spec": { "nodes": ["pi"], "configurations": { "files": [{ "source": "static://", "destination": "/etc/motd" }] } },
+	})).unwrap();
+	let policy = |spec: serde_json::Value| api::KonfigPolicy::new("team-a", serde_json::from_value(spec).unwrap());
+
+	assert_eq!(assigns(&konfigset, "pi", &labels(&[]), &[], &[]), Ok(true));
+	assert_eq!(assigns(&konfigset, "rock", &labels(&[]), &[], &[]), Ok(false));
+
+	let paths = policy(serde_json::json!({ "namespaces": ["team-a"], "allowedPaths": ["/etc/app"] }));
+	assert_eq!(assigns(&konfigset, "pi", &labels(&[]), &[], &[paths]), Ok(false));
+
+	let nodes = policy(serde_json::json!({ "namespaces": ["team-a"], "nodeSelector": { "matchLabels": { "team": "a" } } }));
+	assert_eq!(assigns(&konfigset, "pi", &labels(&[("team", "a")]), &[], std::slice::from_ref(&nodes)), Ok(true));
+	assert_eq!(assigns(&konfigset, "pi", &labels(&[("team", "b")]), &[], &[nodes]), Ok(false));
+    }
+}
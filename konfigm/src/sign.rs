@@ -0,0 +1,45 @@
+/*
+ * sign - `konfigm sign`, adds the signature annotation to the KonfigSets of
+ * a YAML file so konfigd nodes configured with the matching public key
+ * accept them.
+ */
+use crate::validate;
+use konfig_api as api;
+
+use std::path::Path;
+
+/*
+ * Returns the objects of the file, as YAML, with every KonfigSet signed
+ * (other objects are left as they are).
+ */
+pub fn file(path: &Path, key: &Path) -> Result<String, String> {
+    let key = api::signature::read_signing_key(key)?;
+
+    let mut signed: Vec<String> = vec![];
+    for mut value in validate::documents(path)? {
+	if value.get("kind").and_then(|kind| kind.as_str()) == Some("KonfigSet") {
+	    let name = validate::describe(&value);
+	    let errors = validate::object(&value);
+	    if !errors.is_empty() {
+		let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+		return Err(format!("{} is invalid: {}", name, errors.join("; ")));
+	    }
+
+	    let konfigset: api::KonfigSet = serde_json::from_value(value.clone()).map_err(|err| err.to_string())?;
+	    if konfigset.metadata.namespace.is_none() {
+		return Err(format!("{} must define its namespace, which is part of the signature", name));
+	    }
+	    let signature = api::signature::sign(&konfigset, &key)?;
+
+	    let metadata = value.as_object_mut()
+		.and_then(|fields| fields.get_mut("metadata"))
+		.and_then(|metadata| metadata.as_object_mut())
+		.ok_or(format!("{} has no metadata", name))?;
+	    let annotations = metadata.entry("annotations").or_insert(serde_json::json!({}));
+	    annotations[api::signature::ANNOTATION] = serde_json::Value::from(signature);
+	}
+
+	signed.push(serde_yaml::to_string(&value).map_err(|err| err.to_string())?);
+    }
+    Ok(signed.join("---\n"))
+}
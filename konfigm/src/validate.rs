@@ -0,0 +1,113 @@
+/*
+ * validate - checks KonfigSets and KonfigNodes before they reach the nodes,
+ * used by the admission webhook and by `konfigm validate -f <file>`.
+ */
+use konfig_api as api;
+use konfig_api::validation::FieldError;
+
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::Path;
+
+/* deserialization errors also get the path of the offending field */
+fn parse<T: DeserializeOwned>(value: &serde_json::Value) -> Result<T, FieldError> {
+    serde_path_to_error::deserialize(value.clone()).map_err(|err| FieldError{
+	field: err.path().to_string(),
+	message: err.inner().to_string(),
+    })
+}
+
+/*
+ * Validates a single object, other kinds than ours are accepted as is.
+ */
+pub fn object(value: &serde_json::Value) -> Vec<FieldError> {
+    let kind = value.get("kind").and_then(|kind| kind.as_str()).unwrap_or_default();
+
+    let parsed = match kind {
+	"KonfigSet" => parse::<api::KonfigSet>(value).map(|konfigset| api::validation::konfigset(&konfigset.spec)),
+	"KonfigNode" => parse::<api::KonfigNode>(value).map(|knode| api::validation::konfignode(&knode.spec)),
+	_ => Ok(vec![]),
+    };
+
+    match parsed {
+	Ok(errors) => errors,
+	Err(err) => vec![err],
+    }
+}
+
+/*
+ * kubectl reads YAML 1.1, where `mode: 0644` is the octal 420, while
+ * serde_yaml sticks to YAML 1.2 and reads it as the string "0644".
+ */
+fn octal_modes(value: &mut serde_json::Value) {
+    match value {
+	serde_json::Value::Object(fields) => {
+	    for (key, field) in fields.iter_mut() {
+		let octal = match field.as_str() {
+		    Some(mode) if key == "mode" && mode.len() > 1 && mode.starts_with('0') => u32::from_str_radix(mode, 8).ok(),
+		    _ => None,
+		};
+		match octal {
+		    Some(mode) => *field = serde_json::Value::from(mode),
+		    None => octal_modes(field),
+		}
+	    }
+	},
+	serde_json::Value::Array(items) => items.iter_mut().for_each(octal_modes),
+	_ => {},
+    }
+}
+
+/* kind namespace/name, as used in messages */
+pub fn describe(value: &serde_json::Value) -> String {
+    let field = |path: &str| value.pointer(path).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+
+    match field("/metadata/namespace").as_str() {
+	"" => format!("{} {}", field("/kind"), field("/metadata/name")),
+	namespace => format!("{} {}/{}", field("/kind"), namespace, field("/metadata/name")),
+    }
+}
+
+/*
+ * The objects of a (multi document) YAML file, read the way kubectl would.
+ */
+pub fn documents(path: &Path) -> Result<Vec<serde_json::Value>, String> {
+    let content = fs::read_to_string(path).map_err(|err| format!("Unable to read {}: {}", path.display(), err))?;
+
+    let mut documents = vec![];
+    for document in serde_yaml::Deserializer::from_str(&content) {
+	let mut value = match serde_json::Value::deserialize(document) {
+	    Ok(serde_json::Value::Null) => continue,
+	    Ok(value) => value,
+	    Err(err) => return Err(format!("{}: {}", path.display(), err)),
+	};
+
+	octal_modes(&mut value);
+	documents.push(value);
+    }
+    Ok(documents)
+}
+
+/*
+ * Validates every object of a YAML file, returns whether all of them are
+ * valid.
+ */
+pub fn file(path: &Path) -> Result<bool, String> {
+    let mut valid = true;
+
+    for value in documents(path)? {
+	let errors = object(&value);
+	if errors.is_empty() {
+	    println!("{}: {} is valid", path.display(), describe(&value));
+	    continue;
+	}
+
+	valid = false;
+	println!("{}: {} is invalid:", path.display(), describe(&value));
+	for error in errors {
+	    println!("  {}", error);
+	}
+    }
+    Ok(valid)
+}
@@ -0,0 +1,60 @@
+/*
+ * webhook - ValidatingAdmissionWebhook rejecting malformed KonfigSets and
+ * KonfigNodes with the same checks konfigd runs before applying them.
+ */
+use crate::validate;
+
+use axum::Json;
+use axum::Router;
+use axum::routing::post;
+use axum_server::tls_openssl::OpenSSLConfig;
+use kube::core::DynamicObject;
+use kube::core::admission::AdmissionRequest;
+use kube::core::admission::AdmissionResponse;
+use kube::core::admission::AdmissionReview;
+use std::net::SocketAddr;
+use std::path::Path;
+
+async fn validate_review(Json(review): Json<AdmissionReview<DynamicObject>>) -> Json<AdmissionReview<DynamicObject>> {
+    let request: AdmissionRequest<DynamicObject> = match review.try_into() {
+	Ok(request) => request,
+	Err(err) => {
+	    log::error!("Invalid admission review: {}", err);
+	    return Json(AdmissionResponse::invalid(err.to_string()).into_review());
+	}
+    };
+
+    let response = AdmissionResponse::from(&request);
+    let object = match &request.object {
+	Some(object) => object,
+	None => return Json(response.into_review()),
+    };
+
+    let value = match serde_json::to_value(object) {
+	Ok(value) => value,
+	Err(err) => return Json(response.deny(err.to_string()).into_review()),
+    };
+    let errors = validate::object(&value);
+    if errors.is_empty() {
+	return Json(response.into_review());
+    }
+
+    let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+    log::info!("Denied {}: {}", validate::describe(&value), errors.join("; "));
+    Json(response.deny(errors.join("; ")).into_review())
+}
+
+/*
+ * Serves POST /validate over TLS, the certificate must be trusted by the
+ * caBundle of the ValidatingWebhookConfiguration.
+ */
+pub async fn serve(addr: SocketAddr, cert: &Path, key: &Path) -> Result<(), std::io::Error> {
+    let config = OpenSSLConfig::from_pem_chain_file(cert, key)
+	.map_err(|err| std::io::Error::other(format!("Unable to load the webhook certificate: {}", err)))?;
+    let app = Router::new().route("/validate", post(validate_review));
+
+    log::info!("Serving the admission webhook on {}", addr);
+    axum_server::bind_openssl(addr, config)
+	.serve(app.into_make_service())
+	.await
+}